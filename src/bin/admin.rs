@@ -0,0 +1,43 @@
+use poncu::client::core::{PoncuTcpClient, TcpClient};
+use poncu::utils::{cli, config};
+
+fn main() {
+    let options = cli::Options::from_env();
+    log4rs::init_file(&options.log_config, Default::default()).unwrap();
+
+    let config = config::get_config(&options).unwrap_or_else(|err| {
+        log::error!("invalid configuration: {}", err);
+        std::process::exit(1);
+    });
+    let mut client = PoncuTcpClient::with_config(&config);
+    client.connect().expect("client connection error");
+
+    let status = client.status().expect("status request error");
+    let node = &status.node;
+    println!("node:        {}", node.id);
+    println!("name:        {}", node.name);
+    println!("description: {}", node.description);
+    println!("address:     {}", node.addr);
+    println!("peers:");
+    for (addr, peer) in &status.peers {
+        match peer {
+            Some(peer) => println!("  {} {} ({})", addr, peer.id, peer.name),
+            None => println!("  {} (not contacted yet)", addr),
+        }
+    }
+
+    let reload = &status.reload;
+    println!("config reloads: {}", reload.reloads);
+    if let Some(last_attempt) = reload.last_attempt {
+        println!("  last attempt: {} (seconds since the UNIX epoch)", last_attempt);
+    }
+    if let Some(error) = &reload.last_error {
+        println!("  rejected:     {}", error);
+    }
+
+    if let Some(hints) = &status.hints {
+        println!("hints pending: {} ({} bytes)", hints.pending, hints.pending_bytes);
+        println!("  replayed:     {}", hints.replayed);
+        println!("  dropped:      {}", hints.dropped);
+    }
+}
@@ -1,8 +1,10 @@
 use poncu::client::core::{PoncuTcpClient, TcpClient};
-use poncu::utils::config;
+use poncu::protocol::message::ConsistencyLevel;
+use poncu::utils::{cli, config};
 
 fn main() {
-    log4rs::init_file("log.yaml", Default::default()).unwrap();
+    let options = cli::Options::from_env();
+    log4rs::init_file(&options.log_config, Default::default()).unwrap();
 
     log::info!(
         "{} client v{} ",
@@ -10,10 +12,16 @@ fn main() {
         env!("CARGO_PKG_VERSION")
     );
 
-    let config = config::get_config();
+    let config = config::get_config(&options).unwrap_or_else(|err| {
+        log::error!("invalid configuration: {}", err);
+        std::process::exit(1);
+    });
     let mut client = PoncuTcpClient::with_config(&config);
     client.connect().expect("client connection error");
 
+    let key = String::from("greeting");
     let msg = String::from("Hi there!");
-    client.set_item(msg).expect("set item error");
+    client
+        .set_item(key, msg.into_bytes(), ConsistencyLevel::Quorum)
+        .expect("set item error");
 }
@@ -2,19 +2,27 @@ use core::time;
 use log::{log_enabled, Level};
 use poncu::client::core::{PoncuTcpClient, TcpClient};
 use poncu::client::file_client;
+use poncu::protocol::message::ConsistencyLevel;
+use poncu::server::access::Access;
 use poncu::server::core::{PoncuMutex, PoncuTcpServer, TcpServer};
-use poncu::utils::config;
+use poncu::utils::{cli, config};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
 fn main() {
-    log4rs::init_file("log.yaml", Default::default()).unwrap();
+    let options = cli::Options::from_env();
+    let log_config =
+        log4rs::config::load_config_file(&options.log_config, Default::default()).unwrap();
+    let log_handle = log4rs::init_config(log_config).unwrap();
 
     log::info!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
 
-    let config = config::get_config();
+    let config = config::get_config(&options).unwrap_or_else(|err| {
+        log::error!("invalid configuration: {}", err);
+        std::process::exit(1);
+    });
 
     let flag_tcp_server_ready = Arc::new(AtomicBool::new(false));
     let flag_tcp_server_shutdown = Arc::new(AtomicBool::new(false));
@@ -22,14 +30,28 @@ fn main() {
     let flag_tcp_server_ready_worker = flag_tcp_server_ready.clone();
     let flag_tcp_server_shutdown_worker = flag_tcp_server_shutdown.clone();
 
+    // the rules and the limits are shared by both servers and follow the reloads
+    let access = Arc::new(Access::with_config(&config).unwrap_or_else(|err| {
+        log::error!("invalid configuration: {}", err);
+        std::process::exit(1);
+    }));
+
     let server_config = config.clone();
+    let server_access = access.clone();
     let handle_tcp_server = thread::spawn(move || {
-        let server = PoncuTcpServer::with_config(&server_config);
+        let server = PoncuTcpServer::with_access(&server_config, server_access).unwrap_or_else(|err| {
+            log::error!("server could not be set up: {}", err);
+            std::process::exit(1);
+        });
         let _poncu_mutex: PoncuMutex = Arc::new(Mutex::new(&server));
-        server.start(
+        server.watch_config(options, Some(log_handle), &flag_tcp_server_shutdown_worker);
+        if let Err(err) = server.start(
             &flag_tcp_server_shutdown_worker,
             &flag_tcp_server_ready_worker,
-        );
+        ) {
+            log::error!("server failed to start: {}", err);
+            std::process::exit(1);
+        }
     });
 
     while !flag_tcp_server_ready.load(Ordering::SeqCst) {
@@ -44,19 +66,26 @@ fn main() {
     client1.connect().expect("client connection error");
 
     let msg1 = String::from("Hi there1!");
-    client1.set_item(msg1).expect("set item error");
+    client1
+        .set_item("key1".to_string(), msg1.into_bytes(), ConsistencyLevel::One)
+        .expect("set item error");
     thread::sleep(Duration::from_millis(20));
     let msg2 = String::from("Hi there2!");
-    client1.set_item(msg2).expect("set item error");
+    client1
+        .set_item("key2".to_string(), msg2.into_bytes(), ConsistencyLevel::Quorum)
+        .expect("set item error");
 
     let mut client2 = PoncuTcpClient::with_config(&client_config);
     client2.connect().expect("client connection error");
 
-    let msg1 = String::from("Hi there1!");
-    client2.set_item(msg1).expect("set item error");
+    let item = client2
+        .get_item("key1".to_string(), ConsistencyLevel::Quorum)
+        .expect("get item error");
+    log::info!("key1: {:?}", item.map(String::from_utf8));
     thread::sleep(Duration::from_millis(20));
-    let msg2 = String::from("Hi there2!");
-    client2.set_item(msg2).expect("set item error");
+    client2
+        .remove_item("key2".to_string(), ConsistencyLevel::All)
+        .expect("remove item error");
 
     // shutdown the server
     // server_shutdown.store(false, Ordering::SeqCst);
@@ -68,9 +97,14 @@ fn main() {
 
     let handle_file_server = poncu::server::file_server::start_file_server(
         &file_server_config,
+        access,
         flag_file_server_ready.clone(),
         flag_file_server_shutdown.clone(),
-    );
+    )
+    .unwrap_or_else(|err| {
+        log::error!("file server failed to start: {}", err);
+        std::process::exit(1);
+    });
 
     while !flag_file_server_ready.load(Ordering::SeqCst) {
         if log_enabled!(Level::Trace) {
@@ -79,14 +113,14 @@ fn main() {
         thread::sleep(time::Duration::from_millis(20));
     }
 
-    file_client::get_file_info("http://127.0.0.1:8181/LICENSE");
-    file_client::get_file("http://127.0.0.1:8181/LICENSE");
+    file_client::get_file_info(&config, "http://127.0.0.1:8181/LICENSE");
+    file_client::get_file(&config, "http://127.0.0.1:8181/LICENSE");
 
     let range = 48..482;
-    file_client::get_file_in_range("http://127.0.0.1:8181/LICENSE", Some(range));
+    file_client::get_file_in_range(&config, "http://127.0.0.1:8181/LICENSE", Some(range));
 
     let range = 2000..2100;
-    file_client::get_file_in_range("http://127.0.0.1:8181/LICENSE", Some(range));
+    file_client::get_file_in_range(&config, "http://127.0.0.1:8181/LICENSE", Some(range));
 
     let _ = handle_file_server.join();
     let _ = handle_tcp_server.join();
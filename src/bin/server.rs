@@ -1,13 +1,16 @@
 use core::time;
 use log::{log_enabled, Level};
 use poncu::server::core::{PoncuTcpServer, TcpServer};
-use poncu::utils::config;
+use poncu::utils::{cli, config};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 
 fn main() {
-    log4rs::init_file("log.yaml", Default::default()).unwrap();
+    let options = cli::Options::from_env();
+    let log_config =
+        log4rs::config::load_config_file(&options.log_config, Default::default()).unwrap();
+    let log_handle = log4rs::init_config(log_config).unwrap();
 
     log::info!(
         "{} server v{}",
@@ -15,12 +18,22 @@ fn main() {
         env!("CARGO_PKG_VERSION")
     );
 
-    let config = config::get_config();
-    let server = PoncuTcpServer::with_config(&config);
+    let config = config::get_config(&options).unwrap_or_else(|err| {
+        log::error!("invalid configuration: {}", err);
+        std::process::exit(1);
+    });
+    let server = PoncuTcpServer::with_config(&config).unwrap_or_else(|err| {
+        log::error!("server could not be set up: {}", err);
+        std::process::exit(1);
+    });
 
     let flag_server_ready = Arc::new(AtomicBool::new(false));
     let flag_server_shutdown = Arc::new(AtomicBool::new(false));
-    server.start(&flag_server_shutdown, &flag_server_ready);
+    server.watch_config(options, Some(log_handle), &flag_server_shutdown);
+    if let Err(err) = server.start(&flag_server_shutdown, &flag_server_ready) {
+        log::error!("server failed to start: {}", err);
+        std::process::exit(1);
+    }
 
     while !flag_server_ready.load(Ordering::SeqCst) {
         if log_enabled!(Level::Trace) {
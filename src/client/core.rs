@@ -1,69 +1,314 @@
+use crate::client::nodes::{backoff, NodePool};
+use crate::protocol::frame::{read_message, write_message, Connection};
+use crate::protocol::message::{
+    ConsistencyLevel, Credentials, NodeInfo, NodeStatus, Request, Response,
+};
 use crate::utils::config::Config;
+use crate::utils::tls;
+use rustls::{ClientConfig, ClientConnection, StreamOwned};
 use std::io::prelude::*;
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Rounds over all nodes before giving up connecting
+const CONNECT_ROUNDS_MAX: u32 = 5;
+/// Attempts for idempotent requests, each one on the best available node
+const REQUEST_ATTEMPTS_MAX: u32 = 3;
 
 pub trait TcpClient<'a> {
     fn with_config(config: &'a Config) -> Self;
     fn connect(&mut self) -> std::io::Result<()>;
     fn disconnect(&mut self) -> std::io::Result<()>;
-    fn set_item(&mut self, key: String) -> std::io::Result<()>;
-    fn get_item(&mut self, key: String) -> std::io::Result<()>;
-    fn remove_item(&self, key: String) -> bool;
+    fn set_item(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        consistency: ConsistencyLevel,
+    ) -> std::io::Result<()>;
+    fn get_item(
+        &mut self,
+        key: String,
+        consistency: ConsistencyLevel,
+    ) -> std::io::Result<Option<Vec<u8>>>;
+    fn remove_item(&mut self, key: String, consistency: ConsistencyLevel) -> std::io::Result<()>;
 }
 pub struct PoncuTcpClient<'a> {
-    stream: Option<TcpStream>,
-    config: &'a Config,
+    stream: Option<Box<dyn Connection>>,
+    /// node of the current connection
+    node: Option<SocketAddr>,
+    /// identity of the connected node, received in the handshake
+    server: Option<NodeInfo>,
+    nodes: NodePool,
+    /// presented in the handshake if an access token is configured
+    credentials: Option<Credentials>,
+    /// TLS settings if configured, the error is reported on connecting
+    tls: Result<Option<Arc<ClientConfig>>, String>,
+    /// verified in the server certificates instead of the node address
+    server_name: Option<String>,
+    _config: &'a Config,
 }
 
 impl<'a> TcpClient<'a> for PoncuTcpClient<'a> {
     fn with_config(config: &'a Config) -> Self {
+        let nodes = config
+            .remote
+            .as_ref()
+            .map(|remote| NodePool::new(&remote.nodes))
+            .unwrap_or_else(|| NodePool::new(&[]));
+        let credentials = config.client.as_ref().and_then(|client| {
+            match (&client.bearer_token, &client.access_token) {
+                (Some(token), _) => Some(Credentials::Bearer {
+                    token: token.clone(),
+                }),
+                (None, Some(token)) => Some(Credentials::AccessToken {
+                    id: client.id.clone(),
+                    token: token.clone(),
+                }),
+                (None, None) => None,
+            }
+        });
+        let tls_settings = config.client.as_ref().and_then(|client| client.tls.as_ref());
+        let tls = tls_settings
+            .map(tls::client_config)
+            .transpose()
+            .map_err(|err| err.to_string());
         PoncuTcpClient {
             stream: None,
-            config,
+            node: None,
+            server: None,
+            nodes,
+            credentials,
+            tls,
+            server_name: tls_settings.and_then(|tls| tls.server_name.clone()),
+            _config: config,
         }
     }
 
+    /// Connects to the best available node, retrying with exponential backoff if none is reachable
     fn connect(&mut self) -> std::io::Result<()> {
-        assert!(self.config.remote.is_some());
-        let config_remote = self.config.remote.as_ref().unwrap();
-        assert!(!config_remote.nodes.is_empty());
-        let remote_address = config_remote.nodes[0];
+        if self.nodes.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no remote nodes configured",
+            ));
+        }
+        if let Err(msg) = &self.tls {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid TLS settings: {}", msg),
+            ));
+        }
 
-        let stream = TcpStream::connect(remote_address)?;
+        for round in 1..=CONNECT_ROUNDS_MAX {
+            for node in self.nodes.candidates() {
+                match self.connect_to(node) {
+                    Ok(()) => return Ok(()),
+                    // the other nodes share the credentials
+                    Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                        log::error!("authentication with {} failed: {}", node, err);
+                        return Err(err);
+                    }
+                    Err(err) => {
+                        log::warn!("could not connect to {}: {:?}", node, err);
+                        self.nodes.record_failure(node);
+                    }
+                }
+            }
 
-        let local_addr = stream.local_addr().unwrap();
-        log::info!("connected to {} as {}", remote_address, local_addr);
-        
-        stream.set_nodelay(true).expect("set_nodelay call failed");
+            if round < CONNECT_ROUNDS_MAX {
+                let delay = backoff(round);
+                log::warn!("no reachable nodes, next attempt in {:?}", delay);
+                thread::sleep(delay);
+            }
+        }
 
-        self.stream = Some(stream);
-        Ok(())
+        Err(std::io::Error::new(
+            std::io::ErrorKind::NotConnected,
+            "no reachable nodes",
+        ))
     }
 
     fn disconnect(&mut self) -> std::io::Result<()> {
-        let stream = self.stream.as_mut().unwrap();
-        stream.flush()?;
-        self.stream = None;
+        self.node = None;
+        self.server = None;
+        if let Some(mut stream) = self.stream.take() {
+            stream.flush()?;
+        }
         Ok(())
     }
 
-    fn set_item(&mut self, key: String) -> std::io::Result<()> {
-        let stream = self.stream.as_mut().unwrap();
-        stream.write_all(key.as_bytes())?;
-        // stream.shutdown(std::net::Shutdown::Write).unwrap();
-        stream.flush().unwrap();
+    fn set_item(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        consistency: ConsistencyLevel,
+    ) -> std::io::Result<()> {
+        let request = Request::SetItem {
+            key,
+            value,
+            consistency,
+        };
+        match self.request(&request)? {
+            Response::Done => Ok(()),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    fn get_item(
+        &mut self,
+        key: String,
+        consistency: ConsistencyLevel,
+    ) -> std::io::Result<Option<Vec<u8>>> {
+        let request = Request::GetItem { key, consistency };
+        match self.request(&request)? {
+            Response::Item(item) => Ok(item.and_then(|item| item.value)),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    fn remove_item(&mut self, key: String, consistency: ConsistencyLevel) -> std::io::Result<()> {
+        let request = Request::RemoveItem { key, consistency };
+        match self.request(&request)? {
+            Response::Done => Ok(()),
+            response => Err(unexpected_response(response)),
+        }
+    }
+}
+
+impl<'a> PoncuTcpClient<'a> {
+    /// Remote nodes with their health and latencies
+    pub fn nodes(&self) -> &NodePool {
+        &self.nodes
+    }
+
+    /// Identity of the connected node
+    pub fn server(&self) -> Option<&NodeInfo> {
+        self.server.as_ref()
+    }
+
+    /// Identity and claster view of the connected node
+    pub fn status(&mut self) -> std::io::Result<NodeStatus> {
+        match self.request(&Request::Status)? {
+            Response::Status(status) => Ok(status),
+            response => Err(unexpected_response(response)),
+        }
+    }
+
+    fn connect_to(&mut self, node: SocketAddr) -> std::io::Result<()> {
+        let started = Instant::now();
+        let stream = TcpStream::connect_timeout(&node, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        let local_addr = stream.local_addr()?;
+        self.stream = Some(match self.tls.as_ref().ok().and_then(Option::as_ref) {
+            Some(config) => {
+                let server_name = tls::server_name(self.server_name.as_deref(), node.ip())?;
+                let connection = ClientConnection::new(config.clone(), server_name)
+                    .map_err(std::io::Error::other)?;
+                Box::new(StreamOwned::new(connection, stream))
+            }
+            None => Box::new(stream),
+        });
+        self.node = Some(node);
+
+        let hello = Request::Hello {
+            node: None,
+            credentials: self.credentials.clone(),
+        };
+        let server = match self.exchange(&hello) {
+            Ok(Response::Hello { node: server, .. }) => server,
+            Ok(response) => {
+                self.stream = None;
+                self.node = None;
+                return Err(unexpected_response(response));
+            }
+            Err(err) => {
+                self.stream = None;
+                self.node = None;
+                return Err(err);
+            }
+        };
+        self.nodes.record_success(node, started.elapsed());
+
+        log::info!(
+            "connected to {} ({}) at {} as {}",
+            server.id,
+            server.name,
+            node,
+            local_addr
+        );
+        self.server = Some(server);
         Ok(())
     }
 
-    fn get_item(&mut self, _key: String) -> std::io::Result<()> {
+    /// Sends the request, reconnecting if needed.
+    /// Idempotent requests are retried on another node if the current one fails.
+    fn request(&mut self, request: &Request) -> std::io::Result<Response> {
+        let attempts = if request.is_idempotent() {
+            REQUEST_ATTEMPTS_MAX
+        } else {
+            1
+        };
+
+        let mut last_err = None;
+        for _ in 0..attempts {
+            if self.stream.is_none() {
+                self.connect()?;
+            }
+
+            let node = self.node.unwrap();
+            let started = Instant::now();
+            match self.exchange(request) {
+                Ok(response) => {
+                    self.nodes.record_success(node, started.elapsed());
+                    return Ok(response);
+                }
+                Err(err) => {
+                    log::warn!("request to {} failed: {:?}", node, err);
+                    self.nodes.record_failure(node);
+                    self.stream = None;
+                    self.node = None;
+                    self.server = None;
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    fn exchange(&mut self, request: &Request) -> std::io::Result<Response> {
         let stream = self.stream.as_mut().unwrap();
-        let mut buf = [0; 128];
-        stream.write_all(&buf)?;
-        stream.read_exact(&mut buf)?;
-        Ok(())
+        write_message(stream, request)?;
+        read_message(stream)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "connection closed by server",
+            )
+        })
     }
+}
 
-    fn remove_item(&self, _key: String) -> bool {
-        true
+fn unexpected_response(response: Response) -> std::io::Error {
+    if let Response::Unauthorized(msg) | Response::Forbidden(msg) = response {
+        return std::io::Error::new(std::io::ErrorKind::PermissionDenied, msg);
     }
+    let msg = match response {
+        Response::Unavailable { required, replied } => format!(
+            "consistency level not reached: {} of {} replicas replied",
+            replied, required
+        ),
+        Response::LimitExceeded {
+            reason,
+            retry_after_ms: Some(retry_after_ms),
+        } => format!("{}, retry after {} ms", reason, retry_after_ms),
+        Response::LimitExceeded { reason, .. } => reason,
+        Response::Error(msg) => msg,
+        other => format!("unexpected response: {:?}", other),
+    };
+    std::io::Error::other(msg)
 }
@@ -2,62 +2,83 @@ use std::ops::Range;
 
 use bytes::Bytes;
 use http_body_util::{BodyExt, Empty};
+use hyper::client::conn::http1::SendRequest;
 use hyper::Request;
-use tokio::io::{self, AsyncWriteExt as _};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt as _};
 use tokio::net::TcpStream;
 use tokio::runtime::Runtime;
+use rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
 
-use http_common::http_range::{HttpRange, CompleteLength};
+use http_common::http_range::RANGE_UNIT;
 use hyper_util::rt::TokioIo;
 
+use crate::utils::config::{ClientTls, Config};
+use crate::utils::tls;
+
 // A simple type alias so as to DRY.
 type FileClientResult<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
-pub fn get_file(url: &str) {
-    get_file_in_range(url, None)
+/// `https://` URLs are verified with the TLS settings of the client section
+pub fn get_file(config: &Config, url: &str) {
+    get_file_in_range(config, url, None)
 }
 
-pub fn get_file_in_range(url: &str, range: Option<Range<u64>>) {
+pub fn get_file_in_range(config: &Config, url: &str, range: Option<Range<u64>>) {
     let url = url.parse::<hyper::Uri>().unwrap();
 
     let async_runtime = Runtime::new().unwrap();
     async_runtime.block_on(async {
-        let result = request_url("GET", url, range).await;
+        let result = request_url("GET", url, range, client_tls(config)).await;
         if let Err(err) = result {
             log::error!("Connection failed: {:?}", err)
         }
     });
 }
 
-pub fn get_file_info(url: &str) {
+pub fn get_file_info(config: &Config, url: &str) {
     let url = url.parse::<hyper::Uri>().unwrap();
 
     let async_runtime = Runtime::new().unwrap();
     async_runtime.block_on(async {
-        let result = request_url("HEAD", url, None).await;
+        let result = request_url("HEAD", url, None, client_tls(config)).await;
         if let Err(err) = result {
             log::error!("Connection failed: {:?}", err)
         }
     });
 }
 
+fn client_tls(config: &Config) -> Option<&ClientTls> {
+    config.client.as_ref().and_then(|client| client.tls.as_ref())
+}
+
 async fn request_url(
     method: &str,
     url: hyper::Uri,
     range: Option<Range<u64>>,
+    tls_settings: Option<&ClientTls>,
 ) -> FileClientResult<()> {
+    let https = url.scheme_str() == Some("https");
     let host = url.host().expect("uri has no host");
-    let port = url.port_u16().unwrap_or(80);
+    let port = url.port_u16().unwrap_or(if https { 443 } else { 80 });
     let addr = format!("{}:{}", host, port);
     let stream = TcpStream::connect(addr).await?;
-    let io = TokioIo::new(stream);
 
-    let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
-    tokio::task::spawn(async move {
-        if let Err(err) = conn.await {
-            log::error!("Connection failed: {:?}", err);
-        }
-    });
+    let mut sender = if https {
+        let config = match tls_settings {
+            Some(settings) => tls::client_config(settings)?,
+            None => tls::client_config(&ClientTls::default())?,
+        };
+        // IPv6 hosts come in brackets
+        let name = tls_settings
+            .and_then(|settings| settings.server_name.as_deref())
+            .unwrap_or(host.trim_start_matches('[').trim_end_matches(']'));
+        let server_name = ServerName::try_from(name.to_string())?;
+        let stream = TlsConnector::from(config).connect(server_name, stream).await?;
+        handshake(stream).await?
+    } else {
+        handshake(stream).await?
+    };
 
     if log::log_enabled!(log::Level::Trace) {
         log::trace!(
@@ -76,14 +97,11 @@ async fn request_url(
         .header(hyper::header::HOST, authority.as_str())
         .body(Empty::<Bytes>::new())?;
 
+    // the range ends inclusively
     if let Some(range_v) = range {
-        let http_range = HttpRange {
-            ranges: vec![range_v],
-            complete_length: Some(CompleteLength::Unknown),
-        };
         req.headers_mut().append(
-            hyper::header::CONTENT_RANGE,
-            http_range.to_header().parse().unwrap(),
+            hyper::header::RANGE,
+            format!("{}={}-{}", RANGE_UNIT, range_v.start, range_v.end).parse().unwrap(),
         );
     }
 
@@ -109,3 +127,16 @@ async fn request_url(
 
     Ok(())
 }
+
+async fn handshake<S>(stream: S) -> FileClientResult<SendRequest<Empty<Bytes>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::task::spawn(async move {
+        if let Err(err) = conn.await {
+            log::error!("Connection failed: {:?}", err);
+        }
+    });
+    Ok(sender)
+}
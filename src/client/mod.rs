@@ -1,2 +1,3 @@
 pub mod core;
-pub mod file_client;
\ No newline at end of file
+pub mod file_client;
+pub mod nodes;
\ No newline at end of file
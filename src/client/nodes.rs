@@ -0,0 +1,132 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Pause before the first retry of a failed node, doubled with each further failure
+pub const BACKOFF_MIN: Duration = Duration::from_millis(100);
+pub const BACKOFF_MAX: Duration = Duration::from_secs(10);
+
+/// Weight of the latest sample in the moving average of latencies
+const LATENCY_WEIGHT: f64 = 0.2;
+
+#[derive(Debug, Clone)]
+pub struct NodeState {
+    pub addr: SocketAddr,
+    /// exponentially weighted moving average of the request latencies
+    pub latency: Option<Duration>,
+    /// failures in a row
+    pub failures: u32,
+    /// the node is not selected before this moment, unless all nodes failed
+    pub retry_at: Option<Instant>,
+}
+
+/// Remote nodes known by the client, with their health and latencies
+#[derive(Debug, Clone)]
+pub struct NodePool {
+    nodes: Vec<NodeState>,
+}
+
+impl NodePool {
+    pub fn new(nodes: &[SocketAddr]) -> Self {
+        let nodes = nodes
+            .iter()
+            .map(|addr| NodeState {
+                addr: *addr,
+                latency: None,
+                failures: 0,
+                retry_at: None,
+            })
+            .collect();
+        NodePool { nodes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn nodes(&self) -> &[NodeState] {
+        &self.nodes
+    }
+
+    /// Returns the nodes in the order they should be tried:
+    /// healthy nodes by latency first (unmeasured ones before measured),
+    /// then the failed ones by the end of their backoff.
+    pub fn candidates(&self) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let (mut healthy, mut failed): (Vec<_>, Vec<_>) = self
+            .nodes
+            .iter()
+            .partition(|node| node.retry_at.is_none_or(|retry_at| retry_at <= now));
+
+        healthy.sort_by_key(|node| node.latency.unwrap_or_default());
+        failed.sort_by_key(|node| node.retry_at);
+
+        healthy
+            .into_iter()
+            .chain(failed)
+            .map(|node| node.addr)
+            .collect()
+    }
+
+    pub fn record_success(&mut self, addr: SocketAddr, latency: Duration) {
+        if let Some(node) = self.node_mut(addr) {
+            node.latency = Some(match node.latency {
+                Some(average) => average.mul_f64(1.0 - LATENCY_WEIGHT) + latency.mul_f64(LATENCY_WEIGHT),
+                None => latency,
+            });
+            node.failures = 0;
+            node.retry_at = None;
+        }
+    }
+
+    pub fn record_failure(&mut self, addr: SocketAddr) {
+        if let Some(node) = self.node_mut(addr) {
+            node.failures = node.failures.saturating_add(1);
+            let backoff = backoff(node.failures);
+            node.retry_at = Some(Instant::now() + backoff);
+            log::debug!(
+                "node {} failed {} time(s), next retry in {:?}",
+                addr,
+                node.failures,
+                backoff
+            );
+        }
+    }
+
+    fn node_mut(&mut self, addr: SocketAddr) -> Option<&mut NodeState> {
+        self.nodes.iter_mut().find(|node| node.addr == addr)
+    }
+}
+
+/// Exponential backoff after the given number of failures in a row
+pub fn backoff(failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(1).min(16);
+    (BACKOFF_MIN * 2u32.pow(exponent)).min(BACKOFF_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates_prefer_healthy_and_fast_nodes() {
+        let a: SocketAddr = "127.0.0.1:9191".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:9192".parse().unwrap();
+        let c: SocketAddr = "127.0.0.1:9193".parse().unwrap();
+        let mut pool = NodePool::new(&[a, b, c]);
+
+        pool.record_success(a, Duration::from_millis(30));
+        pool.record_success(b, Duration::from_millis(10));
+        pool.record_success(c, Duration::from_millis(20));
+        assert_eq!(pool.candidates(), vec![b, c, a]);
+
+        pool.record_failure(b);
+        assert_eq!(pool.candidates(), vec![c, a, b]);
+    }
+
+    #[test]
+    fn backoff_is_exponential_and_bounded() {
+        assert_eq!(backoff(1), BACKOFF_MIN);
+        assert_eq!(backoff(3), BACKOFF_MIN * 4);
+        assert_eq!(backoff(100), BACKOFF_MAX);
+    }
+}
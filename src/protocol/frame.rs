@@ -0,0 +1,53 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+
+/// Upper limit for a single message, protects against corrupted length prefixes
+pub const FRAME_SIZE_MAX: usize = 64 * 1024 * 1024;
+
+/// A byte stream carrying messages: TCP, TLS or a Unix domain socket
+pub trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+/// Writes a message prefixed with its length as big-endian u32
+pub fn write_message<T: Serialize>(stream: &mut impl Write, message: &T) -> io::Result<()> {
+    let payload = bincode::serialize(message).map_err(to_io_error)?;
+    if payload.len() > FRAME_SIZE_MAX {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("message too large: {} bytes", payload.len()),
+        ));
+    }
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(&payload)?;
+    stream.flush()
+}
+
+/// Reads a length prefixed message.
+/// Returns `Ok(None)` if the stream was closed before a new message started.
+pub fn read_message<T: DeserializeOwned>(stream: &mut impl Read) -> io::Result<Option<T>> {
+    let mut prefix = [0; 4];
+    match stream.read_exact(&mut prefix) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > FRAME_SIZE_MAX {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message too large: {} bytes", len),
+        ));
+    }
+
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    let message = bincode::deserialize(&payload).map_err(to_io_error)?;
+    Ok(Some(message))
+}
+
+fn to_io_error(err: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
@@ -0,0 +1,228 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of replicas that must respond before a request is considered successful
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConsistencyLevel {
+    /// a single replica
+    #[default]
+    One,
+    /// a majority of replicas
+    Quorum,
+    /// every replica
+    All,
+}
+
+impl ConsistencyLevel {
+    /// Returns the number of replies required from `replicas` replicas
+    pub fn required(&self, replicas: usize) -> usize {
+        match self {
+            ConsistencyLevel::One => 1.min(replicas),
+            ConsistencyLevel::Quorum => replicas / 2 + 1,
+            ConsistencyLevel::All => replicas,
+        }
+    }
+}
+
+/// Version of an item, ordered by timestamp with the writing node as a tie-breaker
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ItemVersion {
+    /// microseconds since the UNIX epoch
+    pub timestamp: u64,
+    pub node: u64,
+}
+
+impl ItemVersion {
+    pub fn now(node: u64) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+        ItemVersion { timestamp, node }
+    }
+}
+
+/// A value stored on a replica. A missing value marks a removed item (tombstone).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionedValue {
+    pub version: ItemVersion,
+    pub value: Option<Vec<u8>>,
+}
+
+/// Identity of a server node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeInfo {
+    /// unique in the claster
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    /// address the node accepts connections on
+    pub addr: SocketAddr,
+}
+
+/// Node identity with the peers known to the node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeStatus {
+    pub node: NodeInfo,
+    /// configured peers with their identity, if already known
+    pub peers: Vec<(SocketAddr, Option<NodeInfo>)>,
+    pub reload: ReloadStatus,
+    /// none if hinted handoff is disabled
+    pub hints: Option<HintMetrics>,
+}
+
+/// Outcome of the configuration reloads since the node started
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReloadStatus {
+    /// successfully applied reloads
+    pub reloads: u64,
+    /// seconds since the UNIX epoch of the last attempt
+    pub last_attempt: Option<u64>,
+    /// reason of the last rejected reload, cleared by a successful one
+    pub last_error: Option<String>,
+}
+
+/// Hints kept for unreachable replicas since the node started, the pending ones include hints left from a previous run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HintMetrics {
+    /// hints waiting for their nodes
+    pub pending: u64,
+    pub pending_bytes: u64,
+    /// hints delivered to their nodes
+    pub replayed: u64,
+    /// hints dropped because of the size or age limits
+    pub dropped: u64,
+}
+
+/// Identity and secret presented by a client or a peer node in the handshake
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Credentials {
+    /// client id with its access token
+    AccessToken { id: String, token: String },
+    /// JWT issued to the client
+    Bearer { token: String },
+    /// HMAC of the node identity, the timestamp and the nonce keyed with the node token
+    Node {
+        timestamp: u64,
+        nonce: u64,
+        mac: Vec<u8>,
+    },
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::AccessToken { id, .. } => f
+                .debug_struct("AccessToken")
+                .field("id", id)
+                .finish_non_exhaustive(),
+            Credentials::Bearer { .. } => f.debug_struct("Bearer").finish_non_exhaustive(),
+            Credentials::Node { timestamp, .. } => f
+                .debug_struct("Node")
+                .field("timestamp", timestamp)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// One of `count` ranges of the key hash space, used by anti-entropy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRange {
+    pub index: u32,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+    /// the first message of a connection, peer nodes present their identity,
+    /// both peer nodes and clients their credentials
+    Hello {
+        node: Option<NodeInfo>,
+        credentials: Option<Credentials>,
+    },
+    /// identity and claster view of the node
+    Status,
+    SetItem {
+        key: String,
+        value: Vec<u8>,
+        consistency: ConsistencyLevel,
+    },
+    GetItem {
+        key: String,
+        consistency: ConsistencyLevel,
+    },
+    RemoveItem {
+        key: String,
+        consistency: ConsistencyLevel,
+    },
+    /// node to node: store the value if it is newer than the local one
+    ReplicaSet { key: String, item: VersionedValue },
+    /// node to node: read the local value
+    ReplicaGet { key: String },
+    /// node to node: hash tree of the items in the range replicated on both nodes
+    SyncTree { range: KeyRange, peer: SocketAddr },
+    /// node to node: item versions in the given leaves of the range hash tree
+    SyncKeys {
+        range: KeyRange,
+        leaves: Vec<u32>,
+        peer: SocketAddr,
+    },
+}
+
+impl Request {
+    /// Returns `true` if the request can be safely repeated on another replica
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Request::Hello { .. }
+                | Request::Status
+                | Request::GetItem { .. }
+                | Request::ReplicaGet { .. }
+                | Request::SyncTree { .. }
+                | Request::SyncKeys { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    /// identity of the node, signed in replies to peer nodes if a node token is configured
+    Hello {
+        node: NodeInfo,
+        credentials: Option<Credentials>,
+    },
+    Status(NodeStatus),
+    Done,
+    Item(Option<VersionedValue>),
+    SyncTree(Vec<u64>),
+    SyncKeys(Vec<(String, ItemVersion)>),
+    /// not enough replicas replied to satisfy the requested consistency level
+    Unavailable { required: usize, replied: usize },
+    /// the connection is not authenticated or not allowed to send the request
+    Unauthorized(String),
+    /// the access control rules deny the request to the client
+    Forbidden(String),
+    /// a rate limit or a storage quota of the client is exceeded,
+    /// rate limits suggest when to try again
+    LimitExceeded {
+        reason: String,
+        retry_after_ms: Option<u64>,
+    },
+    Error(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consistency_level_required() {
+        assert_eq!(ConsistencyLevel::One.required(3), 1);
+        assert_eq!(ConsistencyLevel::Quorum.required(3), 2);
+        assert_eq!(ConsistencyLevel::Quorum.required(4), 3);
+        assert_eq!(ConsistencyLevel::All.required(3), 3);
+        assert_eq!(ConsistencyLevel::One.required(0), 0);
+    }
+}
@@ -1,3 +1,24 @@
 pub mod items;
 pub mod core;
-pub mod file_server;
\ No newline at end of file
+pub mod file_server;
+pub mod file_paths;
+pub mod file_body;
+pub mod dir_listing;
+pub mod file_upload;
+pub mod upload_sessions;
+pub mod mime_types;
+pub mod compression;
+pub mod conditional;
+pub mod byte_ranges;
+pub mod access;
+pub mod acl;
+pub mod anti_entropy;
+pub mod auth;
+pub mod hints;
+pub mod limits;
+pub mod listeners;
+pub mod membership;
+pub mod merkle;
+pub mod reload;
+pub mod replication;
+pub mod store;
\ No newline at end of file
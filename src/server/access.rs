@@ -0,0 +1,59 @@
+use std::sync::{Arc, RwLock};
+
+use crate::server::acl::Acl;
+use crate::server::auth::JwtVerifier;
+use crate::server::limits::Limits;
+use crate::utils::config::Config;
+
+/// Access settings shared by the TCP server and the file server of a node, replaced by reloads.
+/// Both servers draw from the same rate and bandwidth buckets.
+pub struct Access {
+    /// verifies the bearer tokens of the file server, the TCP server has its own in the authenticator
+    jwt: RwLock<Option<Arc<JwtVerifier>>>,
+    acl: RwLock<Arc<Acl>>,
+    limits: Limits,
+}
+
+impl Access {
+    /// Fails if the JWT key can not be loaded
+    pub fn with_config(config: &Config) -> Result<Self, String> {
+        Ok(Access {
+            jwt: RwLock::new(jwt_verifier(config)?.map(Arc::new)),
+            acl: RwLock::new(Arc::new(Acl::with_config(config))),
+            limits: Limits::with_config(config)?,
+        })
+    }
+
+    pub fn jwt(&self) -> Option<Arc<JwtVerifier>> {
+        self.jwt.read().unwrap().clone()
+    }
+
+    pub fn acl(&self) -> Arc<Acl> {
+        self.acl.read().unwrap().clone()
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Applies the rules and the limits of a reloaded configuration
+    pub fn reconfigure(&self, config: &Config) {
+        *self.acl.write().unwrap() = Arc::new(Acl::with_config(config));
+        self.limits.set_limits(&config.limits.clone().unwrap_or_default());
+    }
+
+    /// Applies a verifier loaded by `jwt_verifier` from a reloaded configuration
+    pub fn set_jwt(&self, jwt: Option<JwtVerifier>) {
+        *self.jwt.write().unwrap() = jwt.map(Arc::new);
+    }
+}
+
+/// Loads the JWT key of the configuration, none if JWTs are not configured
+pub fn jwt_verifier(config: &Config) -> Result<Option<JwtVerifier>, String> {
+    config
+        .auth
+        .as_ref()
+        .and_then(|auth| auth.jwt.as_ref())
+        .map(JwtVerifier::with_config)
+        .transpose()
+}
@@ -0,0 +1,134 @@
+use crate::protocol::message::Request;
+use crate::server::auth::Caller;
+use crate::utils::config::{AclRule, Config, Permission};
+
+/// Resource of a request checked against the rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource<'a> {
+    Item(&'a str),
+    Path(&'a str),
+    Node,
+}
+
+/// Grants of permissions to clients and roles
+#[derive(Debug, Default)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+}
+
+impl Acl {
+    pub fn with_config(config: &Config) -> Self {
+        Acl {
+            rules: config
+                .acl
+                .as_ref()
+                .map(|acl| acl.rules.clone())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Peer nodes are not restricted, neither is anybody without rules
+    pub fn allows(&self, caller: &Caller, permission: Permission, resource: Resource) -> bool {
+        if self.rules.is_empty() || matches!(caller, Caller::Node(_)) {
+            return true;
+        }
+        self.rules.iter().any(|rule| {
+            rule.permissions.contains(&permission)
+                && applies_to(rule, caller)
+                && covers(rule, resource)
+        })
+    }
+
+    /// Checks the client requests, returns the denied permission.
+    /// The other requests are limited by `Caller::permits`.
+    pub fn check_request<'a>(
+        &self,
+        caller: &Caller,
+        request: &'a Request,
+    ) -> Result<(), (Permission, Resource<'a>)> {
+        let (permission, resource) = match request {
+            Request::Status => (Permission::Admin, Resource::Node),
+            Request::GetItem { key, .. } => (Permission::Read, Resource::Item(key)),
+            Request::SetItem { key, .. } => (Permission::Write, Resource::Item(key)),
+            Request::RemoveItem { key, .. } => (Permission::Delete, Resource::Item(key)),
+            _ => return Ok(()),
+        };
+        if self.allows(caller, permission, resource) {
+            Ok(())
+        } else {
+            Err((permission, resource))
+        }
+    }
+}
+
+fn applies_to(rule: &AclRule, caller: &Caller) -> bool {
+    if rule.clients.iter().any(|client| client == "*") {
+        return true;
+    }
+    match caller {
+        Caller::Client { id, roles } => {
+            rule.clients.contains(id) || rule.roles.iter().any(|role| roles.contains(role))
+        }
+        _ => false,
+    }
+}
+
+fn covers(rule: &AclRule, resource: Resource) -> bool {
+    match resource {
+        Resource::Node => true,
+        Resource::Item(key) => rule.items.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => key.starts_with(prefix),
+            None => pattern == key,
+        }),
+        Resource::Path(path) => rule.paths.iter().any(|folder| {
+            let folder = folder.trim_end_matches('/');
+            path == folder
+                || path
+                    .strip_prefix(folder)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::config::parse_config;
+    use std::path::Path;
+
+    fn client(id: &str, roles: &[&str]) -> Caller {
+        Caller::Client {
+            id: id.to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn grants() {
+        let source = "\
+acl:
+  rules:
+    - clients: '*'
+      paths: /public
+      permissions: read
+    - roles: editor
+      items: users/*, settings
+      paths: /
+      permissions: read, write
+    - clients: ops
+      permissions: admin
+";
+        let acl = Acl::with_config(&parse_config(source, Path::new("x"), &[]).unwrap());
+        let editor = client("app1", &["editor"]);
+
+        assert!(acl.allows(&Caller::Anonymous, Permission::Read, Resource::Path("/public/a.txt")));
+        assert!(!acl.allows(&Caller::Anonymous, Permission::Read, Resource::Path("/publicity")));
+        assert!(!acl.allows(&Caller::Anonymous, Permission::Read, Resource::Item("settings")));
+        assert!(acl.allows(&editor, Permission::Write, Resource::Item("users/1")));
+        assert!(acl.allows(&editor, Permission::Read, Resource::Path("/docs/a.txt")));
+        assert!(!acl.allows(&editor, Permission::Write, Resource::Item("settings/1")));
+        assert!(!acl.allows(&editor, Permission::Delete, Resource::Item("users/1")));
+        assert!(!acl.allows(&editor, Permission::Admin, Resource::Node));
+        assert!(acl.allows(&client("ops", &[]), Permission::Admin, Resource::Node));
+    }
+}
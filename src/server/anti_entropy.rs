@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::protocol::message::{ItemVersion, KeyRange, Request, Response};
+use crate::server::merkle::{self, MerkleTree};
+use crate::server::reload::LiveConfig;
+use crate::server::replication::{peer_request, Coordinator};
+
+/// Starts the background synchronisation of the local replicas with the peers.
+/// The settings are read again for every round, so that reloads take effect.
+pub fn start_anti_entropy(
+    coordinator: Arc<Coordinator>,
+    live: Arc<LiveConfig>,
+    flag_shutdown: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        while !flag_shutdown.load(Ordering::SeqCst) {
+            let settings = live.get().anti_entropy.clone().unwrap_or_default();
+            let round_start = Instant::now();
+            while round_start.elapsed() < settings.interval {
+                if flag_shutdown.load(Ordering::SeqCst) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(200));
+            }
+
+            if !settings.enabled {
+                continue;
+            }
+
+            let budget = Budget {
+                bandwidth_max: settings.bandwidth_max.bytes(),
+                cpu_max: settings.cpu_max,
+            };
+            for peer in coordinator.peers() {
+                for index in 0..settings.ranges {
+                    let range = KeyRange {
+                        index,
+                        count: settings.ranges,
+                    };
+                    if let Err(err) = sync_range(&coordinator, peer, range, &budget) {
+                        log::warn!("anti-entropy with {} failed: {:?}", peer, err);
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Hash tree of the items in the range replicated on both the local node and the peer
+pub fn range_tree(coordinator: &Coordinator, range: KeyRange, peer: SocketAddr) -> MerkleTree {
+    let items = shared_items(coordinator, range, peer);
+    MerkleTree::build(
+        items
+            .iter()
+            .map(|(leaf, key, version)| (*leaf, key.as_str(), *version)),
+    )
+}
+
+/// Item versions in the given leaves of the range hash tree
+pub fn range_keys(
+    coordinator: &Coordinator,
+    range: KeyRange,
+    leaves: &[u32],
+    peer: SocketAddr,
+) -> Vec<(String, ItemVersion)> {
+    let leaves = leaves.iter().collect::<HashSet<_>>();
+    shared_items(coordinator, range, peer)
+        .into_iter()
+        .filter(|(leaf, _, _)| leaves.contains(leaf))
+        .map(|(_, key, version)| (key, version))
+        .collect()
+}
+
+fn shared_items(
+    coordinator: &Coordinator,
+    range: KeyRange,
+    peer: SocketAddr,
+) -> Vec<(u32, String, ItemVersion)> {
+    let local = coordinator.local();
+    coordinator
+        .store()
+        .versions(|key| merkle::locate(key, range.count).0 == range.index)
+        .into_iter()
+        .filter(|(key, _)| {
+            let replicas = coordinator.replicas(key);
+            replicas.contains(&local) && replicas.contains(&peer)
+        })
+        .map(|(key, version)| (merkle::locate(&key, range.count).1, key, version))
+        .collect()
+}
+
+/// Compares the range with the peer and transfers only the items that differ
+fn sync_range(
+    coordinator: &Coordinator,
+    peer: SocketAddr,
+    range: KeyRange,
+    budget: &Budget,
+) -> io::Result<()> {
+    let local = coordinator.local();
+    let store = coordinator.store();
+
+    let started = Instant::now();
+    let local_tree = range_tree(coordinator, range, peer);
+    budget.spend_cpu(started.elapsed());
+
+    let request = Request::SyncTree { range, peer: local };
+    let remote_tree = match exchange(coordinator, peer, &request, budget)? {
+        Response::SyncTree(nodes) => MerkleTree::from_nodes(nodes)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "hash tree mismatch"))?,
+        other => return Err(unexpected_response(other)),
+    };
+
+    let leaves = local_tree.diff(&remote_tree);
+    if leaves.is_empty() {
+        return Ok(());
+    }
+
+    if log::log_enabled!(log::Level::Debug) {
+        log::debug!(
+            "anti-entropy: {} leaves of range {:?} differ with {}",
+            leaves.len(),
+            range,
+            peer
+        );
+    }
+
+    let request = Request::SyncKeys {
+        range,
+        leaves: leaves.clone(),
+        peer: local,
+    };
+    let remote_versions = match exchange(coordinator, peer, &request, budget)? {
+        Response::SyncKeys(keys) => keys.into_iter().collect::<HashMap<_, _>>(),
+        other => return Err(unexpected_response(other)),
+    };
+    let local_versions = range_keys(coordinator, range, &leaves, peer)
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+
+    // pull the items which are newer on the peer
+    for (key, remote_version) in &remote_versions {
+        if local_versions
+            .get(key)
+            .is_none_or(|version| version < remote_version)
+        {
+            let request = Request::ReplicaGet { key: key.clone() };
+            if let Response::Item(Some(item)) = exchange(coordinator, peer, &request, budget)? {
+                store.apply(key, item);
+            }
+        }
+    }
+
+    // push the items which are newer on the local node
+    for (key, local_version) in &local_versions {
+        if remote_versions
+            .get(key)
+            .is_none_or(|version| version < local_version)
+        {
+            if let Some(item) = store.get(key) {
+                let request = Request::ReplicaSet {
+                    key: key.clone(),
+                    item,
+                };
+                exchange(coordinator, peer, &request, budget)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends a request to the peer and charges the transferred bytes to the budget
+fn exchange(
+    coordinator: &Coordinator,
+    peer: SocketAddr,
+    request: &Request,
+    budget: &Budget,
+) -> io::Result<Response> {
+    let response = peer_request(coordinator.membership(), peer, request)?;
+    let transferred = bincode::serialized_size(request).unwrap_or_default()
+        + bincode::serialized_size(&response).unwrap_or_default();
+    budget.spend_bandwidth(transferred);
+    Ok(response)
+}
+
+fn unexpected_response(response: Response) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected response: {:?}", response),
+    )
+}
+
+/// Keeps anti-entropy within the configured bandwidth and CPU limits by pausing after the work done
+struct Budget {
+    bandwidth_max: u64,
+    cpu_max: u8,
+}
+
+impl Budget {
+    fn spend_cpu(&self, busy: Duration) {
+        if self.cpu_max < 100 {
+            let idle = busy * (100 - self.cpu_max) as u32 / self.cpu_max as u32;
+            thread::sleep(idle);
+        }
+    }
+
+    fn spend_bandwidth(&self, bytes: u64) {
+        if self.bandwidth_max > 0 {
+            thread::sleep(Duration::from_secs_f64(
+                bytes as f64 / self.bandwidth_max as f64,
+            ));
+        }
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rustls::pki_types::CertificateDer;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::protocol::message::{Credentials, NodeInfo, Request};
+use crate::utils::config::{self, Config};
+use crate::utils::tls;
+
+/// Seconds the clocks of the nodes may differ, older node credentials are rejected
+const NODE_CLOCK_SKEW: u64 = 300;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Identity of a connection, established by the handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Caller {
+    /// authentication is not configured
+    Anonymous,
+    /// roles come from the `auth` section or from the claims of a JWT
+    Client { id: String, roles: Vec<String> },
+    Node(NodeInfo),
+}
+
+impl Caller {
+    /// Clients are limited to the item requests, replication is reserved to the authenticated nodes
+    pub fn permits(&self, request: &Request) -> bool {
+        match request {
+            Request::Hello { .. } => false,
+            Request::Status
+            | Request::SetItem { .. }
+            | Request::GetItem { .. }
+            | Request::RemoveItem { .. } => true,
+            Request::ReplicaSet { .. }
+            | Request::ReplicaGet { .. }
+            | Request::SyncTree { .. }
+            | Request::SyncKeys { .. } => matches!(self, Caller::Node(_)),
+        }
+    }
+}
+
+/// Claims of a JWT identifying the caller
+#[derive(Debug, Clone, Deserialize)]
+pub struct Claims {
+    /// client id
+    pub sub: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+impl From<Claims> for Caller {
+    fn from(claims: Claims) -> Self {
+        Caller::Client {
+            id: claims.sub,
+            roles: claims.roles,
+        }
+    }
+}
+
+/// Verifies the signature, expiry, issuer and audience of JWTs
+pub struct JwtVerifier {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtVerifier {
+    pub fn with_config(settings: &config::Jwt) -> Result<Self, String> {
+        let key_file = settings.key_file.display();
+        let key = fs::read(&settings.key_file)
+            .map_err(|err| format!("could not read {}: {}", key_file, err))?;
+        let key = match settings.algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                Ok(DecodingKey::from_secret(key.trim_ascii_end()))
+            }
+            Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512 => DecodingKey::from_rsa_pem(&key),
+            Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&key),
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(&key),
+        }
+        .map_err(|err| format!("invalid key in {}: {}", key_file, err))?;
+
+        let mut validation = Validation::new(settings.algorithm);
+        validation.set_issuer(&[&settings.issuer]);
+        validation.set_audience(&[&settings.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.leeway = settings.leeway.as_secs();
+
+        Ok(JwtVerifier { key, validation })
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, String> {
+        jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|err| format!("invalid bearer token: {}", err))
+    }
+}
+
+/// Signs the identity of the local node for the handshake with a peer
+pub fn node_credentials(token: &str, node: &NodeInfo) -> Credentials {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let timestamp = now.as_secs();
+    // unique across connections and restarts, the receiving node rejects repeated ones
+    let nonce = (now.as_nanos() as u64).wrapping_add(COUNTER.fetch_add(1, Ordering::Relaxed));
+    let mac = node_mac(HELLO, token, node, timestamp, nonce).finalize().into_bytes().to_vec();
+    Credentials::Node {
+        timestamp,
+        nonce,
+        mac,
+    }
+}
+
+/// Signs the identity of the local node in the reply to the handshake of a peer, over the nonce of the peer
+pub fn node_reply_credentials(token: &str, node: &NodeInfo, nonce: u64) -> Credentials {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mac = node_mac(REPLY, token, node, timestamp, nonce).finalize().into_bytes().to_vec();
+    Credentials::Node {
+        timestamp,
+        nonce,
+        mac,
+    }
+}
+
+/// Verifies the reply of a peer to the handshake signed with `sent`, the credentials of the local node
+pub fn verify_node_reply(
+    token: &str,
+    node: &NodeInfo,
+    sent: Option<&Credentials>,
+    reply: Option<&Credentials>,
+) -> Result<(), String> {
+    let invalid = || format!("invalid handshake reply of node {} at {}", node.id, node.addr);
+    let (Some(Credentials::Node { nonce: sent, .. }), Some(Credentials::Node { timestamp, nonce, mac })) = (sent, reply)
+    else {
+        return Err(invalid());
+    };
+    // the nonce of the local node makes the reply fresh
+    if nonce != sent {
+        return Err(invalid());
+    }
+    node_mac(REPLY, token, node, *timestamp, *nonce)
+        .verify_slice(mac)
+        .map_err(|_| invalid())?;
+    check_clock_skew(node, *timestamp)
+}
+
+/// Purposes of the node MACs, a reply can not pass for a handshake
+const HELLO: &str = "poncu-node";
+const REPLY: &str = "poncu-node-reply";
+
+fn node_mac(purpose: &str, token: &str, node: &NodeInfo, timestamp: u64, nonce: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any size");
+    let message = format!("{}|{}|{}|{}|{}", purpose, node.id, node.addr, timestamp, nonce);
+    mac.update(message.as_bytes());
+    mac
+}
+
+fn check_clock_skew(node: &NodeInfo, timestamp: u64) -> Result<(), String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let skew = now.abs_diff(timestamp);
+    if skew > NODE_CLOCK_SKEW {
+        return Err(format!(
+            "node credentials of node {} at {} are {} seconds off, check the clocks",
+            node.id, node.addr, skew
+        ));
+    }
+    Ok(())
+}
+
+/// Verifies the handshake against the token hashes and the JWT keys of the configuration
+pub struct Authenticator {
+    /// accepted from any client id
+    shared: Option<[u8; 32]>,
+    /// token hashes and roles of the clients
+    clients: HashMap<String, ([u8; 32], Vec<String>)>,
+    jwt: Option<JwtVerifier>,
+    node_token: Option<String>,
+    used_macs: Arc<UsedMacs>,
+}
+
+/// Node credentials accepted within the clock skew, with their timestamps.
+/// Kept by the coordinator, so that a reload does not forget them.
+#[derive(Debug, Default)]
+pub struct UsedMacs(Mutex<HashMap<Vec<u8>, u64>>);
+
+impl UsedMacs {
+    /// False if the signature was accepted before
+    fn insert(&self, mac: &[u8], timestamp: u64) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut used = self.0.lock().unwrap();
+        used.retain(|_, used_at| now.abs_diff(*used_at) <= NODE_CLOCK_SKEW);
+        used.insert(mac.to_vec(), timestamp).is_none()
+    }
+}
+
+impl Authenticator {
+    /// Fails if the JWT key can not be loaded
+    pub fn with_config(config: &Config, used_macs: Arc<UsedMacs>) -> Result<Self, String> {
+        let auth = config.auth.clone().unwrap_or_default();
+        Ok(Authenticator {
+            shared: config
+                .server
+                .as_ref()
+                .and_then(|server| server.access_token)
+                .map(|hash| hash.0),
+            clients: auth
+                .clients
+                .iter()
+                .map(|client| (client.id.clone(), (client.token_sha256.0, client.roles.clone())))
+                .collect(),
+            jwt: auth.jwt.as_ref().map(JwtVerifier::with_config).transpose()?,
+            node_token: auth.node_token.map(|token| token.0),
+            used_macs,
+        })
+    }
+
+    /// Clients are accepted without credentials if no token or key is configured
+    pub fn required(&self) -> bool {
+        self.shared.is_some() || !self.clients.is_empty() || self.jwt.is_some()
+    }
+
+    /// Identifies the connection from the handshake.
+    /// Peer nodes must sign their identity with the node token, if one is configured.
+    /// Otherwise a configured peer is recognised by the client certificate verified by the TLS handshake,
+    /// which has to name the address of the peer; without either no connection is trusted as a node.
+    pub fn authenticate(
+        &self,
+        node: Option<&NodeInfo>,
+        credentials: Option<&Credentials>,
+        peer_cert: Option<&CertificateDer>,
+        peers: &[SocketAddr],
+    ) -> Result<Caller, String> {
+        if let Some(node) = node {
+            return match (self.node_token.as_ref(), credentials) {
+                (Some(token), Some(Credentials::Node { timestamp, nonce, mac })) => self
+                    .verify_node(token, node, *timestamp, *nonce, mac)
+                    .map(|()| Caller::Node(node.clone())),
+                (Some(_), _) => Err(format!("node {} at {}: node credentials required", node.id, node.addr)),
+                (None, _) => {
+                    let certified = peer_cert.is_some_and(|cert| tls::certifies(cert, node.addr.ip()));
+                    if certified && peers.contains(&node.addr) {
+                        Ok(Caller::Node(node.clone()))
+                    } else {
+                        Err(format!(
+                            "node {} at {}: a certificate for the address of a configured peer is required",
+                            node.id, node.addr
+                        ))
+                    }
+                }
+            };
+        }
+
+        if !self.required() {
+            return Ok(Caller::Anonymous);
+        }
+
+        match credentials {
+            Some(Credentials::AccessToken { id, token }) => {
+                if self.verify(id, token) {
+                    let roles = self.clients.get(id).map(|(_, roles)| roles.clone());
+                    Ok(Caller::Client {
+                        id: id.clone(),
+                        roles: roles.unwrap_or_default(),
+                    })
+                } else {
+                    Err(format!("invalid credentials of client {}", id))
+                }
+            }
+            Some(Credentials::Bearer { token }) => self.bearer(token),
+            Some(Credentials::Node { .. }) => Err("node credentials without node identity".to_string()),
+            None => Err("authentication required".to_string()),
+        }
+    }
+
+    /// Identifies the caller by a JWT
+    pub fn bearer(&self, token: &str) -> Result<Caller, String> {
+        let jwt = self
+            .jwt
+            .as_ref()
+            .ok_or_else(|| "bearer tokens are not accepted".to_string())?;
+        jwt.verify(token).map(Caller::from)
+    }
+
+    fn verify(&self, id: &str, token: &str) -> bool {
+        let hash: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        let expected = self.clients.get(id).map(|(hash, _)| hash).or(self.shared.as_ref());
+        // unknown clients take as long as known ones
+        let matches: bool = hash.ct_eq(expected.unwrap_or(&[0u8; 32])).into();
+        matches && expected.is_some()
+    }
+
+    /// Accepts each signature once, within the clock skew
+    fn verify_node(
+        &self,
+        token: &str,
+        node: &NodeInfo,
+        timestamp: u64,
+        nonce: u64,
+        mac: &[u8],
+    ) -> Result<(), String> {
+        let invalid = || format!("invalid node credentials of node {} at {}", node.id, node.addr);
+        node_mac(HELLO, token, node, timestamp, nonce)
+            .verify_slice(mac)
+            .map_err(|_| invalid())?;
+        check_clock_skew(node, timestamp)?;
+        if !self.used_macs.insert(mac, timestamp) {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn client_tokens() {
+        // SHA-256 of "secret1" and "secret2"
+        let source = "\
+server:
+  access_token: 5b11618c2e44027877d0cd0921ed166b9f176f50587fc91e7534dd2946db77d6
+auth:
+  clients:
+    - id: app1
+      token_sha256: 35224d0d3465d74e855f8d69a136e79c744ea35a675d3393360a327cbf6359a2
+";
+        let config = config::parse_config(source, Path::new("x"), &[]).unwrap();
+        let authenticator = Authenticator::with_config(&config, Arc::default()).unwrap();
+        assert!(authenticator.verify("any", "secret1"));
+        assert!(authenticator.verify("app1", "secret2"));
+        assert!(!authenticator.verify("app1", "secret1"));
+        assert!(!authenticator.verify("any", "secret2"));
+        assert!(authenticator.authenticate(None, None, None, &[]).is_err());
+        assert!(authenticator.bearer("x.y.z").is_err());
+    }
+
+    #[test]
+    fn bearer_tokens() {
+        use jsonwebtoken::{encode, EncodingKey, Header};
+        use serde::Serialize;
+
+        #[derive(Serialize)]
+        struct TestClaims<'a> {
+            sub: &'a str,
+            roles: Vec<&'a str>,
+            iss: &'a str,
+            aud: &'a str,
+            exp: u64,
+        }
+
+        let key_file = std::env::temp_dir().join(format!("poncu-jwt-{}.key", std::process::id()));
+        fs::write(&key_file, "jwt-secret\n").unwrap();
+        let source = format!(
+            "auth:\n  jwt:\n    algorithm: HS256\n    key_file: {}\n    issuer: idp\n    audience: poncu\n",
+            key_file.display()
+        );
+        let config = config::parse_config(&source, Path::new("x"), &[]).unwrap();
+        let authenticator = Authenticator::with_config(&config, Arc::default()).unwrap();
+        fs::remove_file(&key_file).unwrap();
+
+        let now = jsonwebtoken::get_current_timestamp();
+        let token = |aud: &str, exp: u64| {
+            let claims = TestClaims {
+                sub: "app1",
+                roles: vec!["reader"],
+                iss: "idp",
+                aud,
+                exp,
+            };
+            let key = EncodingKey::from_secret(b"jwt-secret");
+            encode(&Header::default(), &claims, &key).unwrap()
+        };
+
+        assert_eq!(
+            authenticator.bearer(&token("poncu", now + 600)),
+            Ok(Caller::Client {
+                id: "app1".to_string(),
+                roles: vec!["reader".to_string()],
+            })
+        );
+        assert!(authenticator.bearer(&token("other", now + 600)).is_err());
+        assert!(authenticator.bearer(&token("poncu", now - 600)).is_err());
+    }
+
+    #[test]
+    fn node_tokens() {
+        let source = "auth:\n  node_token: 0123456789abcdef\n";
+        let config = config::parse_config(source, Path::new("x"), &[]).unwrap();
+        let used_macs = Arc::new(UsedMacs::default());
+        let authenticator = Authenticator::with_config(&config, used_macs.clone()).unwrap();
+        let node = NodeInfo {
+            id: "n2".to_string(),
+            name: "n2".to_string(),
+            description: String::new(),
+            addr: "127.0.0.1:9192".parse().unwrap(),
+        };
+        let authenticate = |credentials: &Credentials| authenticator.authenticate(Some(&node), Some(credentials), None, &[]);
+
+        let credentials = node_credentials("0123456789abcdef", &node);
+        assert_eq!(authenticate(&credentials), Ok(Caller::Node(node.clone())));
+        // replayed, also to an authenticator replacing this one
+        assert!(authenticate(&credentials).is_err());
+        let replacement = Authenticator::with_config(&config, used_macs).unwrap();
+        assert!(replacement.authenticate(Some(&node), Some(&credentials), None, &[]).is_err());
+        assert!(authenticate(&node_credentials("fedcba9876543210", &node)).is_err());
+        assert!(authenticator.authenticate(Some(&node), None, None, &[]).is_err());
+        // clients are not affected by the node token
+        assert_eq!(authenticator.authenticate(None, None, None, &[]), Ok(Caller::Anonymous));
+
+        // replies are bound to the nonce of the handshake
+        let sent = node_credentials("0123456789abcdef", &node);
+        let Credentials::Node { nonce, .. } = sent else { unreachable!() };
+        let reply = node_reply_credentials("0123456789abcdef", &node, nonce);
+        assert!(verify_node_reply("0123456789abcdef", &node, Some(&sent), Some(&reply)).is_ok());
+        assert!(verify_node_reply("fedcba9876543210", &node, Some(&sent), Some(&reply)).is_err());
+        let stale = node_reply_credentials("0123456789abcdef", &node, nonce.wrapping_add(1));
+        assert!(verify_node_reply("0123456789abcdef", &node, Some(&sent), Some(&stale)).is_err());
+        assert!(verify_node_reply("0123456789abcdef", &node, Some(&sent), None).is_err());
+        // a reply does not pass for a handshake
+        assert!(authenticate(&reply).is_err());
+    }
+
+    #[test]
+    fn nodes_need_a_node_token() {
+        let config = config::parse_config("", Path::new("x"), &[]).unwrap();
+        let authenticator = Authenticator::with_config(&config, Arc::default()).unwrap();
+        let node = NodeInfo {
+            id: "n2".to_string(),
+            name: "n2".to_string(),
+            description: String::new(),
+            addr: "127.0.0.1:9192".parse().unwrap(),
+        };
+        assert!(authenticator.authenticate(Some(&node), None, None, &[]).is_err());
+        let credentials = node_credentials("0123456789abcdef", &node);
+        assert!(authenticator.authenticate(Some(&node), Some(&credentials), None, &[node.addr]).is_err());
+
+        // a verified certificate naming the address of a configured peer will do instead
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = |name: &str| {
+            let params = rcgen::CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.self_signed(&key).unwrap().der().clone()
+        };
+        let peer_cert = cert("127.0.0.1");
+        let caller = authenticator.authenticate(Some(&node), None, Some(&peer_cert), &[node.addr]);
+        assert!(matches!(caller, Ok(Caller::Node(caller)) if caller == node));
+        assert!(authenticator.authenticate(Some(&node), None, Some(&peer_cert), &[]).is_err());
+        let other_cert = cert("127.0.0.2");
+        assert!(authenticator.authenticate(Some(&node), None, Some(&other_cert), &[node.addr]).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http_common::http_range::{CompleteLength, HttpRange, RANGE_UNIT};
+
+/// Ranges of a request served, headers with more are ignored and the whole file is sent
+pub const RANGES_MAX: usize = 100;
+
+/// Parses a `Range` header as in RFC 9110, section 14.1.2.
+/// Returns none if the header is to be ignored: other units, invalid syntax or more than `RANGES_MAX` ranges.
+/// The ranges end inclusively and are clamped to the file, unsatisfiable ones are dropped,
+/// so an empty list means 416 Range Not Satisfiable.
+pub fn parse(header: &str, complete_length: u64) -> Option<HttpRange> {
+    let (unit, range_set) = header.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case(RANGE_UNIT) {
+        return None;
+    }
+
+    let specs: Vec<&str> = range_set.split(',').map(str::trim).filter(|spec| !spec.is_empty()).collect();
+    if specs.is_empty() || specs.len() > RANGES_MAX {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        if first.is_empty() {
+            let suffix_length: u64 = parse_number(last)?;
+            if suffix_length > 0 && complete_length > 0 {
+                ranges.push(complete_length.saturating_sub(suffix_length)..complete_length - 1);
+            }
+            continue;
+        }
+        let first: u64 = parse_number(first)?;
+        let last = match last {
+            "" => None,
+            last => Some(parse_number(last)?),
+        };
+        if last.is_some_and(|last| last < first) {
+            return None;
+        }
+        if first < complete_length {
+            let last = last.map_or(complete_length - 1, |last| last.min(complete_length - 1));
+            ranges.push(first..last);
+        }
+    }
+
+    Some(HttpRange {
+        ranges,
+        complete_length: Some(CompleteLength::Representation(complete_length)),
+    })
+}
+
+/// Merges overlapping and adjacent ranges, in the order of their start
+pub fn coalesce(ranges: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(sorted.len());
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Whether any of the ranges overlap, such ranges are always merged
+pub fn overlap(ranges: &[Range<u64>]) -> bool {
+    let mut sorted = ranges.to_vec();
+    sorted.sort_by_key(|range| range.start);
+    sorted.windows(2).any(|pair| pair[1].start <= pair[0].end)
+}
+
+/// `Content-Range` of an inclusive range
+pub fn content_range(range: &Range<u64>, complete_length: u64) -> String {
+    format!("{} {}-{}/{}", RANGE_UNIT, range.start, range.end, complete_length)
+}
+
+/// Boundary of a multipart/byteranges body, unlikely to occur in the file
+pub fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    format!("poncu-{:016x}{:04x}", nanos, COUNTER.fetch_add(1, Ordering::Relaxed) & 0xffff)
+}
+
+/// Delimiter and headers of a part, followed by the bytes of the range
+pub fn part_header(boundary: &str, content_type: &str, range: &Range<u64>, complete_length: u64) -> String {
+    format!(
+        "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+        boundary,
+        content_type,
+        content_range(range, complete_length)
+    )
+}
+
+pub fn closing_boundary(boundary: &str) -> String {
+    format!("\r\n--{}--\r\n", boundary)
+}
+
+/// Digits only, `u64::from_str` would accept a sign
+fn parse_number(digits: &str) -> Option<u64> {
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(header: &str, complete_length: u64) -> Option<Vec<(u64, u64)>> {
+        parse(header, complete_length)
+            .map(|range| range.ranges.iter().map(|range| (range.start, range.end)).collect())
+    }
+
+    #[test]
+    fn range_headers() {
+        assert_eq!(ranges("bytes=0-499", 1000), Some(vec![(0, 499)]));
+        assert_eq!(ranges("bytes=500-", 1000), Some(vec![(500, 999)]));
+        assert_eq!(ranges("bytes=-200", 1000), Some(vec![(800, 999)]));
+        assert_eq!(ranges("bytes=-2000", 1000), Some(vec![(0, 999)]));
+        assert_eq!(ranges("Bytes=900-1500, 0-0", 1000), Some(vec![(900, 999), (0, 0)]));
+        // unsatisfiable ranges are dropped
+        assert_eq!(ranges("bytes=1000-1200, 5-9", 1000), Some(vec![(5, 9)]));
+        assert_eq!(ranges("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(ranges("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(ranges("bytes=0-", 0), Some(vec![]));
+        // invalid headers are ignored
+        assert_eq!(ranges("bytes=5-1", 1000), None);
+        assert_eq!(ranges("bytes=a-b", 1000), None);
+        assert_eq!(ranges("bytes=+1-2", 1000), None);
+        assert_eq!(ranges("items=0-1", 1000), None);
+        assert_eq!(ranges("bytes 0-1/1000", 1000), None);
+        assert_eq!(ranges("bytes=", 1000), None);
+        // so are too many ranges
+        let many = format!("bytes={}", vec!["0-"; RANGES_MAX + 1].join(","));
+        assert_eq!(ranges(&many, 1000), None);
+        let most = format!("bytes={}", vec!["0-"; RANGES_MAX].join(","));
+        assert_eq!(ranges(&most, 1000).map(|ranges| ranges.len()), Some(RANGES_MAX));
+    }
+
+    #[test]
+    fn coalesced_ranges() {
+        assert_eq!(coalesce(&[5..9, 0..2, 3..4, 20..30, 25..26]), vec![0..9, 20..30]);
+        assert_eq!(coalesce(&[0..2, 4..5]), vec![0..2, 4..5]);
+        assert_eq!(coalesce(&[0..u64::MAX, 1..2]), vec![0..u64::MAX]);
+        assert!(overlap(&[5..9, 0..5]));
+        assert!(!overlap(&[5..9, 0..4]));
+    }
+}
@@ -0,0 +1,234 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+
+/// Content codings of the file server, in the order of preference
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    /// `Content-Encoding` value
+    pub fn token(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Extension of the precompressed sidecar files
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => ".br",
+            Encoding::Zstd => ".zst",
+            Encoding::Gzip => ".gz",
+        }
+    }
+}
+
+/// Chooses the acceptable encoding with the highest weight, the preferred one among equal weights.
+/// Encodings that are not listed are acceptable only through `*`.
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let weights: Vec<(&str, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next()?.trim();
+            let weight = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|weight| weight.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!coding.is_empty()).then_some((coding, weight))
+        })
+        .collect();
+    let weight_of = |encoding: Encoding| {
+        let listed = weights.iter().find(|(coding, _)| {
+            coding.eq_ignore_ascii_case(encoding.token())
+                || (encoding == Encoding::Gzip && coding.eq_ignore_ascii_case("x-gzip"))
+        });
+        listed
+            .or_else(|| weights.iter().find(|(coding, _)| *coding == "*"))
+            .map_or(0.0, |(_, weight)| *weight)
+    };
+
+    let mut chosen: Option<(Encoding, f32)> = None;
+    for encoding in Encoding::ALL.into_iter().filter(|encoding| available.contains(encoding)) {
+        let weight = weight_of(encoding);
+        if weight > 0.0 && chosen.is_none_or(|(_, best)| weight > best) {
+            chosen = Some((encoding, weight));
+        }
+    }
+    chosen.map(|(encoding, _)| encoding)
+}
+
+/// Text formats gain from compression, media and archives are compressed already
+pub fn compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || matches!(
+            essence.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/yaml"
+                | "application/toml"
+                | "application/wasm"
+                | "application/rtf"
+                | "font/otf"
+                | "font/ttf"
+                | "image/bmp"
+                | "image/vnd.microsoft.icon"
+        )
+}
+
+/// Precompressed sidecar of a file: a regular file below the root, not older than the file
+pub async fn find_sidecar(
+    root: &Path,
+    file_path: &Path,
+    encoding: Encoding,
+    modified: SystemTime,
+) -> Option<(PathBuf, std::fs::Metadata)> {
+    let mut sidecar = file_path.as_os_str().to_owned();
+    sidecar.push(encoding.extension());
+    let sidecar = tokio::fs::canonicalize(PathBuf::from(sidecar)).await.ok()?;
+    if !sidecar.starts_with(root) {
+        return None;
+    }
+    let metadata = tokio::fs::metadata(&sidecar).await.ok()?;
+    let current = metadata.modified().is_ok_and(|sidecar_modified| sidecar_modified >= modified);
+    (metadata.is_file() && current).then_some((sidecar, metadata))
+}
+
+/// Compressed bytes written by an encoder, taken after each chunk
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Output {
+    fn take(&self) -> Bytes {
+        std::mem::take(&mut *self.0.borrow_mut()).into()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Output>>),
+    Zstd(zstd::stream::write::Encoder<'static, Output>),
+    Gzip(flate2::write::GzEncoder<Output>),
+}
+
+/// Compresses a body chunk by chunk, not `Send`: it is used by one blocking task
+pub struct Compressor {
+    encoder: Encoder,
+    output: Output,
+}
+
+impl Compressor {
+    /// Levels favour speed, as files are compressed for every response
+    pub fn new(encoding: Encoding) -> io::Result<Self> {
+        let output = Output::default();
+        let encoder = match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(output.clone(), 64 * 1024, 5, 22))),
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(output.clone(), 3)?),
+            Encoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(output.clone(), flate2::Compression::new(6))),
+        };
+        Ok(Compressor { encoder, output })
+    }
+
+    /// Compressed bytes available so far, may be empty
+    pub fn compress(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        match &mut self.encoder {
+            Encoder::Brotli(encoder) => encoder.write_all(chunk)?,
+            Encoder::Zstd(encoder) => encoder.write_all(chunk)?,
+            Encoder::Gzip(encoder) => encoder.write_all(chunk)?,
+        }
+        Ok(self.output.take())
+    }
+
+    /// The remaining bytes and the trailer of the stream
+    pub fn finish(self) -> io::Result<Bytes> {
+        let Compressor { encoder, output } = self;
+        match encoder {
+            Encoder::Brotli(encoder) => {
+                encoder.into_inner();
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.finish()?;
+            }
+            Encoder::Gzip(encoder) => {
+                encoder.finish()?;
+            }
+        }
+        Ok(output.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        let all = Encoding::ALL;
+        assert_eq!(negotiate("gzip, deflate, br, zstd", &all), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, br;q=0.5", &all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, *", &all), Some(Encoding::Zstd));
+        assert_eq!(negotiate("x-gzip", &all), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity", &all), None);
+        assert_eq!(negotiate("*;q=0", &all), None);
+        assert_eq!(negotiate("", &all), None);
+        assert_eq!(negotiate("br, gzip", &[Encoding::Gzip]), Some(Encoding::Gzip));
+
+        assert!(compressible("text/html; charset=utf-8"));
+        assert!(compressible("image/svg+xml"));
+        assert!(compressible("application/json"));
+        assert!(!compressible("image/png"));
+        assert!(!compressible("application/zip"));
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        use std::io::Read;
+
+        let contents: Vec<u8> = (0..300_000u32).flat_map(|i| format!("line {}\n", i % 977).into_bytes()).collect();
+        for encoding in Encoding::ALL {
+            let mut compressor = Compressor::new(encoding).unwrap();
+            let mut compressed = Vec::new();
+            for chunk in contents.chunks(64 * 1024) {
+                compressed.extend_from_slice(&compressor.compress(chunk).unwrap());
+            }
+            compressed.extend_from_slice(&compressor.finish().unwrap());
+            assert!(compressed.len() < contents.len() / 10, "{:?}", encoding);
+
+            let mut decompressed = Vec::new();
+            match encoding {
+                Encoding::Brotli => brotli::Decompressor::new(compressed.as_slice(), 4096).read_to_end(&mut decompressed),
+                Encoding::Zstd => zstd::stream::read::Decoder::new(compressed.as_slice()).unwrap().read_to_end(&mut decompressed),
+                Encoding::Gzip => flate2::read::GzDecoder::new(compressed.as_slice()).read_to_end(&mut decompressed),
+            }
+            .unwrap();
+            assert!(decompressed == contents, "{:?}", encoding);
+        }
+    }
+}
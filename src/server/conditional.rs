@@ -0,0 +1,218 @@
+use std::fs::Metadata;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{self, HeaderMap};
+use hyper::Method;
+
+/// Validators of a file, sent as `ETag` and `Last-Modified`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validators {
+    /// entity tag with its quotes, weak ones start with `W/`
+    pub etag: String,
+    pub last_modified: SystemTime,
+}
+
+/// Outcome of the preconditions of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    Passed,
+    /// 304 Not Modified
+    NotModified,
+    /// 412 Precondition Failed
+    Failed,
+}
+
+impl Validators {
+    /// The entity tag is weak for files modified within the last second,
+    /// another write in the same timestamp tick would not change it
+    pub fn new(metadata: &Metadata) -> Self {
+        let last_modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let nanos = last_modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let recent = SystemTime::now()
+            .duration_since(last_modified)
+            .map_or(true, |age| age < Duration::from_secs(1));
+        let weak = if recent { "W/" } else { "" };
+        Validators {
+            etag: format!("{}\"{:x}-{:x}\"", weak, metadata.len(), nanos),
+            last_modified,
+        }
+    }
+
+    /// Validators of an encoded representation, its entity tag differs from the one of the file
+    pub fn encoded(mut self, token: &str) -> Self {
+        self.etag.insert_str(self.etag.len() - 1, &format!("-{}", token));
+        self
+    }
+
+    /// Preconditions of a request for a file that does not exist: `If-Match` cannot hold
+    pub fn evaluate_absent(headers: &HeaderMap) -> Precondition {
+        match headers.contains_key(header::IF_MATCH) {
+            true => Precondition::Failed,
+            false => Precondition::Passed,
+        }
+    }
+
+    /// `Last-Modified` value
+    pub fn http_date(&self) -> String {
+        httpdate::fmt_http_date(self.last_modified)
+    }
+
+    /// Evaluates the conditional headers in the order of RFC 9110, section 13.2.2.
+    /// `If-Match` of a write compares weakly, so that a file stored a moment ago may be replaced by its entity tag;
+    /// the writes of the file server are serialised and the tag changes with the modification time in nanoseconds.
+    pub fn evaluate(&self, method: &Method, headers: &HeaderMap) -> Precondition {
+        let safe = *method == Method::GET || *method == Method::HEAD;
+        if let Some(if_match) = header_str(headers, header::IF_MATCH) {
+            let matches = |etag: &str| if safe { strong_eq(etag, &self.etag) } else { weak_eq(etag, &self.etag) };
+            if !matches_any(if_match, matches) {
+                return Precondition::Failed;
+            }
+        } else if let Some(since) = header_date(headers, header::IF_UNMODIFIED_SINCE) {
+            if self.modified_after(since) {
+                return Precondition::Failed;
+            }
+        }
+
+        if let Some(if_none_match) = header_str(headers, header::IF_NONE_MATCH) {
+            if matches_any(if_none_match, |etag| weak_eq(etag, &self.etag)) {
+                return if safe {
+                    Precondition::NotModified
+                } else {
+                    Precondition::Failed
+                };
+            }
+        } else if let Some(since) = header_date(headers, header::IF_MODIFIED_SINCE).filter(|_| safe) {
+            if !self.modified_after(since) {
+                return Precondition::NotModified;
+            }
+        }
+        Precondition::Passed
+    }
+
+    /// Whether the ranges of a request are served: `If-Range` must hold the current entity tag
+    /// or modification date, both compared as strong validators
+    pub fn if_range(&self, headers: &HeaderMap) -> bool {
+        let Some(value) = header_str(headers, header::IF_RANGE).map(str::trim) else {
+            return true;
+        };
+        if value.starts_with('"') || value.starts_with("W/") {
+            return strong_eq(value, &self.etag);
+        }
+        // the date is weak as well while the entity tag is
+        match httpdate::parse_http_date(value) {
+            Ok(date) => !self.etag.starts_with("W/") && unix_seconds(date) == unix_seconds(self.last_modified),
+            Err(_) => false,
+        }
+    }
+
+    /// HTTP dates have a resolution of one second
+    fn modified_after(&self, date: SystemTime) -> bool {
+        unix_seconds(self.last_modified) > unix_seconds(date)
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Invalid dates are ignored, as required by the RFC
+fn header_date(headers: &HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+    header_str(headers, name).and_then(|value| httpdate::parse_http_date(value).ok())
+}
+
+/// `*` or a comma separated list of entity tags
+fn matches_any(list: &str, matches: impl FnMut(&str) -> bool) -> bool {
+    list.trim() == "*" || list.split(',').map(str::trim).any(matches)
+}
+
+fn strong_eq(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn preconditions() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let validators = Validators {
+            etag: "\"5-1\"".to_string(),
+            last_modified: modified,
+        };
+        let before = httpdate::fmt_http_date(modified - Duration::from_secs(10));
+        let at = httpdate::fmt_http_date(modified);
+        let evaluate = |method: Method, pairs: &[(header::HeaderName, &str)]| {
+            validators.evaluate(&method, &headers(pairs))
+        };
+
+        assert_eq!(evaluate(Method::GET, &[]), Precondition::Passed);
+        assert_eq!(evaluate(Method::GET, &[(header::IF_NONE_MATCH, "W/\"5-1\"")]), Precondition::NotModified);
+        assert_eq!(evaluate(Method::GET, &[(header::IF_NONE_MATCH, "\"x\", *")]), Precondition::Passed);
+        assert_eq!(evaluate(Method::GET, &[(header::IF_NONE_MATCH, "*")]), Precondition::NotModified);
+        assert_eq!(evaluate(Method::PUT, &[(header::IF_NONE_MATCH, "*")]), Precondition::Failed);
+        assert_eq!(evaluate(Method::GET, &[(header::IF_MODIFIED_SINCE, &at)]), Precondition::NotModified);
+        assert_eq!(evaluate(Method::GET, &[(header::IF_MODIFIED_SINCE, &before)]), Precondition::Passed);
+        assert_eq!(evaluate(Method::GET, &[(header::IF_MODIFIED_SINCE, "yesterday")]), Precondition::Passed);
+        // If-None-Match takes precedence over If-Modified-Since
+        let pairs = [(header::IF_NONE_MATCH, "\"other\""), (header::IF_MODIFIED_SINCE, at.as_str())];
+        assert_eq!(evaluate(Method::GET, &pairs), Precondition::Passed);
+
+        assert_eq!(evaluate(Method::GET, &[(header::IF_MATCH, "\"x\", \"5-1\"")]), Precondition::Passed);
+        assert_eq!(evaluate(Method::GET, &[(header::IF_MATCH, "W/\"5-1\"")]), Precondition::Failed);
+        assert_eq!(evaluate(Method::PUT, &[(header::IF_MATCH, "W/\"5-1\"")]), Precondition::Passed);
+        assert_eq!(evaluate(Method::PUT, &[(header::IF_MATCH, "W/\"5-2\"")]), Precondition::Failed);
+        assert_eq!(evaluate(Method::GET, &[(header::IF_UNMODIFIED_SINCE, &before)]), Precondition::Failed);
+        // If-Match takes precedence over If-Unmodified-Since, and over If-None-Match
+        let pairs = [(header::IF_MATCH, "*"), (header::IF_UNMODIFIED_SINCE, before.as_str())];
+        assert_eq!(evaluate(Method::GET, &pairs), Precondition::Passed);
+        let pairs = [(header::IF_MATCH, "\"x\""), (header::IF_NONE_MATCH, "\"5-1\"")];
+        assert_eq!(evaluate(Method::GET, &pairs), Precondition::Failed);
+
+        assert_eq!(Validators::evaluate_absent(&headers(&[(header::IF_MATCH, "*")])), Precondition::Failed);
+        assert_eq!(Validators::evaluate_absent(&headers(&[(header::IF_NONE_MATCH, "*")])), Precondition::Passed);
+    }
+
+    #[test]
+    fn if_range() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let validators = Validators {
+            etag: "\"5-1\"".to_string(),
+            last_modified: modified,
+        };
+        let at = httpdate::fmt_http_date(modified);
+        let before = httpdate::fmt_http_date(modified - Duration::from_secs(10));
+
+        assert!(validators.if_range(&headers(&[])));
+        assert!(validators.if_range(&headers(&[(header::IF_RANGE, "\"5-1\"")])));
+        assert!(validators.if_range(&headers(&[(header::IF_RANGE, &at)])));
+        assert!(!validators.if_range(&headers(&[(header::IF_RANGE, "W/\"5-1\"")])));
+        assert!(!validators.if_range(&headers(&[(header::IF_RANGE, "\"5-2\"")])));
+        assert!(!validators.if_range(&headers(&[(header::IF_RANGE, &before)])));
+        let weak = Validators {
+            etag: "W/\"5-1\"".to_string(),
+            ..validators
+        };
+        assert!(!weak.if_range(&headers(&[(header::IF_RANGE, &at)])));
+    }
+}
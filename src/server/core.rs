@@ -1,21 +1,40 @@
 use log;
+use rustls::pki_types::CertificateDer;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 use std::collections::HashMap;
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::io::prelude::*;
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
+use crate::protocol::frame::{read_message, write_message, Connection};
+use crate::protocol::message::{Request, Response};
+use crate::utils::cli::Options;
 use crate::utils::config::Config;
+use crate::utils::tls;
+use crate::server::access::Access;
+use crate::server::anti_entropy::start_anti_entropy;
+use crate::server::auth::{Authenticator, Caller};
+use crate::server::hints::start_hints_replay;
 use crate::server::items::storage::StorageItem;
+use crate::server::listeners::{bind_all, Listener, Stream};
+use crate::server::reload::{start_config_watcher, LiveConfig};
+use crate::server::replication::Coordinator;
+use crate::server::store::ItemStore;
+
+/// Time a new connection has to complete the TLS handshake and the `Hello`
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub trait TcpServer<'a> {
-    fn with_config(config: &'a Config) -> Self;
+    fn with_config(config: &'a Config) -> std::io::Result<Self>
+    where
+        Self: Sized;
     fn start(
         &'a self,
         server_shutdown: &Arc<AtomicBool>,
         server_ready: &Arc<AtomicBool>,
-    );
+    ) -> std::io::Result<()>;
     fn stop();
     fn set_item(key: String, item: StorageItem) -> bool;
     fn get_item(key: String) -> Option<StorageItem>;
@@ -24,30 +43,103 @@ pub trait TcpServer<'a> {
 
 pub struct PoncuTcpServer<'a> {
     _storage: HashMap<String, StorageItem>,
+    coordinator: Arc<Coordinator>,
     config: &'a Config,
+    /// reloadable settings are read from here
+    live: Arc<LiveConfig>,
 }
 
 pub type PoncuMutex <'a> = Arc<Mutex<&'a PoncuTcpServer <'a>> >;
 
 impl<'a> TcpServer<'a> for PoncuTcpServer<'a> {
-    fn with_config(config: &'a Config) -> Self {
-        PoncuTcpServer {
-            _storage: HashMap::new(),
-            config,
+    fn with_config(config: &'a Config) -> std::io::Result<Self> {
+        let access = Access::with_config(config)
+            .map_err(|msg| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg))?;
+        PoncuTcpServer::with_access(config, Arc::new(access))
+    }
+
+    fn start(&self, flag_shutdown: &Arc<AtomicBool>, flag_ready: &Arc<AtomicBool>) -> std::io::Result<()> {
+
+        let config_server = self.config.server.as_ref().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "the server section is missing")
+        })?;
+        let authenticator = Authenticator::with_config(self.config, self.coordinator.used_macs())
+            .map_err(|msg| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg))?;
+        self.coordinator.set_authenticator(authenticator);
+        let tls = config_server.tls.as_ref().map(tls::server_config).transpose()?;
+        let listeners = bind_all(&config_server.listen_on(), config_server.unix_socket.as_deref())?;
+        flag_ready.store(true, Ordering::SeqCst);
+
+        let node = self.coordinator.membership().local();
+        let names = listeners.iter().map(Listener::name).collect::<Vec<_>>();
+        log::info!("node {} ({}) started listening on {} ...", node.id, node.name, names.join(", "));
+        if tls.is_some() {
+            log::info!("TLS enabled on the TCP listeners");
         }
+        if !node.description.is_empty() {
+            log::info!("{}", node.description);
+        }
+
+        // both can be enabled or get peers by a reload
+        start_anti_entropy(self.coordinator.clone(), self.live.clone(), flag_shutdown.clone());
+
+        if let Some(hints) = self.coordinator.hints() {
+            start_hints_replay(
+                self.coordinator.clone(),
+                hints.clone(),
+                self.live.clone(),
+                flag_shutdown.clone(),
+            );
+        }
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        thread::scope(|scope| {
+            for listener in &listeners {
+                let connections = &connections;
+                let tls = tls.as_ref();
+                scope.spawn(move || self.accept_connections(listener, tls, connections, flag_shutdown));
+            }
+        });
+        Ok(())
     }
 
-    fn start(&self, flag_shutdown: &Arc<AtomicBool>, flag_ready: &Arc<AtomicBool>) {
+    fn stop() {}
 
-        assert!(self.config.server.is_some());
-        let config_server = self.config.server.as_ref().unwrap();
-        assert!(!config_server.listen_on.is_empty());
-        let listen_on = config_server.listen_on[0];
+    fn set_item(_key: String, _item: StorageItem) -> bool {
+        false
+    }
 
-        let listener = TcpListener::bind(listen_on).unwrap();
-        flag_ready.store(true, Ordering::SeqCst);
+    fn get_item(_key: String) -> Option<StorageItem> {
+        None
+    }
+
+    fn remove_item(_key: String) -> bool {
+        true
+    }
+}
+
+impl<'a> PoncuTcpServer<'a> {
+    /// Server sharing the access settings with the file server of the node, reloads apply to both
+    pub fn with_access(config: &'a Config, access: Arc<Access>) -> std::io::Result<Self> {
+        let store = Arc::new(ItemStore::new());
+        let live = Arc::new(LiveConfig::new(config.clone()));
+        let coordinator = Coordinator::with_config(live.clone(), store, access)
+            .map_err(|msg| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg))?;
+        Ok(PoncuTcpServer {
+            _storage: HashMap::new(),
+            coordinator: Arc::new(coordinator),
+            config,
+            live,
+        })
+    }
 
-        log::info!("started listening on {} ...", listen_on);
+    fn accept_connections(
+        &self,
+        listener: &Listener,
+        tls: Option<&Arc<ServerConfig>>,
+        connections: &Arc<AtomicUsize>,
+        flag_shutdown: &Arc<AtomicBool>,
+    ) {
         // listener.set_nonblocking(true).unwrap();
 
         // using thread pooling
@@ -56,10 +148,27 @@ impl<'a> TcpServer<'a> for PoncuTcpServer<'a> {
         let mut handles = Vec::<JoinHandle<()>>::new();
         while !flag_shutdown.load(Ordering::SeqCst) {
             match listener.accept() {
-                Ok((stream, addr)) => {
+                Ok((stream, remote)) => {
+                    let peer = remote.map_or_else(|| listener.name(), |addr| addr.to_string());
+                    let connections_max = self
+                        .live
+                        .get()
+                        .server
+                        .as_ref()
+                        .map_or(usize::MAX, |server| server.connections_max);
+                    if connections.load(Ordering::SeqCst) >= connections_max {
+                        log::warn!("connections limit {} reached, refused {}", connections_max, peer);
+                        continue;
+                    }
+                    connections.fetch_add(1, Ordering::SeqCst);
+
+                    let tls = tls.cloned();
                     let connection_shutdown = flag_shutdown.clone();
+                    let connection_count = connections.clone();
+                    let coordinator = self.coordinator.clone();
                     let handle = thread::spawn(move|| {
-                        handle_connection(stream, addr, connection_shutdown)
+                        handle_connection(stream, tls, peer, remote, coordinator, connection_shutdown);
+                        connection_count.fetch_sub(1, Ordering::SeqCst);
                     });
                     handles.push(handle);
                 },
@@ -71,41 +180,155 @@ impl<'a> TcpServer<'a> for PoncuTcpServer<'a> {
                     continue;
                 }
                 */
-                Err(e) => log::error!("couldn't get client: {e:?}"),
+                Err(e) => log::error!("couldn't get client on {}: {e:?}", listener.name()),
             }
         }
 
         for handle in handles {
             handle.join().unwrap();
         }
+    }
 
+    /// Reloads the configuration of the running server on file changes and SIGHUP
+    pub fn watch_config(
+        &self,
+        options: Options,
+        log_handle: Option<log4rs::Handle>,
+        flag_shutdown: &Arc<AtomicBool>,
+    ) -> JoinHandle<()> {
+        start_config_watcher(
+            self.coordinator.clone(),
+            self.live.clone(),
+            options,
+            log_handle,
+            flag_shutdown.clone(),
+        )
     }
+}
 
-    fn stop() {}
+fn handle_connection(
+    stream: Stream,
+    tls: Option<Arc<ServerConfig>>,
+    addr: String,
+    remote: Option<SocketAddr>,
+    coordinator: Arc<Coordinator>,
+    flag_shutdown: Arc<AtomicBool>,
+) {
+    log::debug!("client connected: {}", addr);
+    let remote_ip = remote.map(|addr| addr.ip());
+    let (mut stream, caller) = match open_connection(stream, tls, &coordinator) {
+        Ok(opened) => opened,
+        Err(err) => {
+            log::error!("handshake with {} failed: {:?}", addr, err);
+            return;
+        }
+    };
 
-    fn set_item(_key: String, _item: StorageItem) -> bool {
-        false
-    }
+    while !flag_shutdown.load(Ordering::SeqCst) {
+        let request = match read_message::<Request>(&mut stream) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(err) => {
+                log::error!("invalid request from {}: {:?}", addr, err);
+                break;
+            }
+        };
+        log::debug!("received request from {} : {:?}", addr, request);
 
-    fn get_item(_key: String) -> Option<StorageItem> {
-        None
+        let response = if !caller.permits(&request) {
+            log::warn!("refused request from {} ({:?}): {:?}", addr, caller, request);
+            Response::Unauthorized("request not permitted".to_string())
+        } else if let Err((permission, resource)) = coordinator.acl().check_request(&caller, &request) {
+            log::warn!("access denied to {} ({:?}): {:?} {:?}", addr, caller, permission, resource);
+            Response::Forbidden(format!("access denied: {:?} {:?}", permission, resource))
+        } else {
+            match coordinator.limits().admit(&caller, remote_ip, &request) {
+                Ok(write) => {
+                    let response = coordinator.handle(request);
+                    coordinator.limits().complete(&caller, remote_ip, write, &response);
+                    response
+                }
+                Err(exceeded) => {
+                    log::debug!("limit exceeded by {} ({:?}): {:?}", addr, caller, exceeded);
+                    *exceeded
+                }
+            }
+        };
+        if let Err(err) = write_message(&mut stream, &response) {
+            log::error!("could not send response to {}: {:?}", addr, err);
+            break;
+        }
     }
+    log::debug!("client disconnected: {}", addr);
+}
 
-    fn remove_item(_key: String) -> bool {
-        true
-    }
+/// Sets up TLS on TCP connections and authenticates the caller.
+/// Both have to complete within the handshake timeout, so that stalled connections give their slots back.
+fn open_connection(
+    stream: Stream,
+    tls: Option<Arc<ServerConfig>>,
+    coordinator: &Coordinator,
+) -> std::io::Result<(Box<dyn Connection>, Caller)> {
+    let control = stream.try_clone()?;
+    control.set_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    let (mut connection, peer_cert): (Box<dyn Connection>, _) = match (stream, tls) {
+        (Stream::Tcp(stream), Some(tls)) => {
+            let mut stream = StreamOwned::new(ServerConnection::new(tls).map_err(std::io::Error::other)?, stream);
+            while stream.conn.is_handshaking() {
+                stream.conn.complete_io(&mut stream.sock)?;
+            }
+            // verified against the CA by the TLS handshake
+            let peer_cert = stream
+                .conn
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| cert.clone().into_owned());
+            (Box::new(stream), peer_cert)
+        }
+        (Stream::Tcp(stream), None) => (Box::new(stream), None),
+        #[cfg(unix)]
+        (Stream::Unix(stream), _) => (Box::new(stream), None),
+    };
+
+    let caller = handshake(&mut connection, peer_cert.as_ref(), coordinator)?;
+    control.set_timeout(None)?;
+    Ok((connection, caller))
 }
 
-fn handle_connection(mut stream: TcpStream, addr: SocketAddr, flag_shutdown: Arc<AtomicBool>) {
-    log::debug!("client connected: {}", addr);
-    let mut buf = [0;1024];
-    let addr = stream.peer_addr().unwrap();
-    while !flag_shutdown.load(Ordering::SeqCst) {
-        let count = stream.read(&mut buf).unwrap();
-        log::debug!("received bytes count from {} : {}", addr, count);
-        let mut vec = buf.to_vec();
-        vec.truncate(count);
-        let msg = String::from_utf8(vec).unwrap();
-        log::debug!("received message from {} : {}", addr, msg);
+/// Expects `Hello` as the first message, authenticates the caller
+/// and replies with the identity of the local node
+fn handshake(
+    stream: &mut impl Connection,
+    peer_cert: Option<&CertificateDer>,
+    coordinator: &Coordinator,
+) -> std::io::Result<Caller> {
+    let request = read_message::<Request>(stream)?.ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed")
+    })?;
+
+    let caller = match (&request, coordinator.authenticator()) {
+        (Request::Hello { node, credentials }, Some(authenticator)) => authenticator.authenticate(
+            node.as_ref(),
+            credentials.as_ref(),
+            peer_cert,
+            &coordinator.peers(),
+        ),
+        (Request::Hello { .. }, None) => Err("server is starting".to_string()),
+        (other, _) => Err(format!("handshake expected, received: {:?}", other)),
+    };
+
+    let response = match &caller {
+        Ok(_) => coordinator.handle(request),
+        Err(msg) => Response::Unauthorized(msg.clone()),
+    };
+    write_message(stream, &response)?;
+
+    match (caller, response) {
+        (Ok(caller), Response::Hello { .. }) => Ok(caller),
+        (_, other) => Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{:?}", other),
+        )),
     }
 }
@@ -0,0 +1,333 @@
+use std::cmp::Ordering;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::server::file_paths;
+
+/// Entry of a listed directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    /// zero for directories
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    fn as_str(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        }
+    }
+}
+
+/// Order and page of a listing, from the query string: `sort=name|size|modified&order=asc|desc&page=N`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Query {
+    pub sort: SortKey,
+    pub descending: bool,
+    /// starts at 1
+    pub page: usize,
+}
+
+impl Default for Query {
+    fn default() -> Self {
+        Query {
+            sort: SortKey::Name,
+            descending: false,
+            page: 1,
+        }
+    }
+}
+
+impl Query {
+    /// Unknown parameters and invalid values are ignored
+    pub fn parse(query: Option<&str>) -> Self {
+        let mut parsed = Query::default();
+        for (key, value) in query.unwrap_or_default().split('&').filter_map(|pair| pair.split_once('=')) {
+            match (key, value) {
+                ("sort", "name") => parsed.sort = SortKey::Name,
+                ("sort", "size") => parsed.sort = SortKey::Size,
+                ("sort", "modified") => parsed.sort = SortKey::Modified,
+                ("order", "asc") => parsed.descending = false,
+                ("order", "desc") => parsed.descending = true,
+                ("page", page) => parsed.page = page.parse().ok().filter(|&page| page > 0).unwrap_or(1),
+                _ => {}
+            }
+        }
+        parsed
+    }
+
+    /// Query string of a link in the HTML listing
+    fn href(self, page: usize) -> String {
+        let order = if self.descending { "desc" } else { "asc" };
+        format!("?sort={}&amp;order={}&amp;page={}", self.sort.as_str(), order, page)
+    }
+}
+
+/// Entries with UTF-8 names; symbolic links are listed only if they stay below the root
+/// and their targets are accepted by `follow`, `root` must be canonical
+pub async fn read_entries(dir: &Path, root: &Path, follow: impl Fn(&Path) -> bool) -> io::Result<Vec<Entry>> {
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    let mut entries = Vec::new();
+    while let Some(entry) = read_dir.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if entry.file_type().await?.is_symlink() {
+            let refused = tokio::fs::canonicalize(entry.path())
+                .await
+                .map_or(true, |target| !target.starts_with(root) || !follow(&target));
+            if refused {
+                continue;
+            }
+        }
+        // the entry may be gone already
+        let Ok(metadata) = tokio::fs::metadata(entry.path()).await else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+        });
+    }
+    Ok(entries)
+}
+
+/// Directories come first in either order, equal keys are ordered by name
+pub fn sort(entries: &mut [Entry], query: &Query) {
+    entries.sort_by(|a, b| {
+        let ordering = match query.sort {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let ordering = if query.descending { ordering.reverse() } else { ordering };
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    });
+}
+
+/// Page of sorted entries
+#[derive(Debug)]
+pub struct Page<'a> {
+    pub entries: &'a [Entry],
+    /// starts at 1, requests beyond the last page get the last one
+    pub number: usize,
+    pub count: usize,
+    pub total: usize,
+}
+
+pub fn page<'a>(entries: &'a [Entry], number: usize, page_size: usize) -> Page<'a> {
+    let count = entries.len().div_ceil(page_size).max(1);
+    let number = number.clamp(1, count);
+    let start = (number - 1) * page_size;
+    Page {
+        entries: &entries[start..entries.len().min(start + page_size)],
+        number,
+        count,
+        total: entries.len(),
+    }
+}
+
+/// Whether JSON is preferred to HTML by the `Accept` header, HTML is the default
+pub fn prefers_json(accept: &str) -> bool {
+    let weight_of = |wanted: &str| {
+        accept
+            .split(',')
+            .filter_map(|item| {
+                let mut params = item.split(';');
+                let media_type = params.next()?.trim();
+                let weight = params
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|weight| weight.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                media_type.eq_ignore_ascii_case(wanted).then_some(weight)
+            })
+            .fold(0.0, f32::max)
+    };
+    weight_of("application/json") > weight_of("text/html")
+}
+
+/// Listing page with sortable columns and links to the neighbouring pages, `path` is the decoded request path
+pub fn html(path: &str, page: &Page, query: &Query) -> String {
+    let title = escape_html(path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {0}</title>\n</head>\n<body>\n<h1>Index of {0}</h1>\n<table>\n<tr>",
+        title
+    );
+    for (key, label) in [(SortKey::Name, "Name"), (SortKey::Size, "Size"), (SortKey::Modified, "Modified")] {
+        // the current column toggles its order
+        let sorted_by = Query {
+            sort: key,
+            descending: key == query.sort && !query.descending,
+            page: 1,
+        };
+        let _ = write!(html, "<th><a href=\"{}\">{}</a></th>", sorted_by.href(1), label);
+    }
+    html.push_str("</tr>\n");
+    if path != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in page.entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir { "-".to_string() } else { entry.size.to_string() };
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            escape_html(&file_paths::encode(&entry.name)),
+            slash,
+            escape_html(&entry.name),
+            slash,
+            size,
+            httpdate::fmt_http_date(entry.modified)
+        );
+    }
+    html.push_str("</table>\n");
+    if page.count > 1 {
+        html.push_str("<p>");
+        if page.number > 1 {
+            let _ = write!(html, "<a href=\"{}\">previous</a> ", query.href(page.number - 1));
+        }
+        let _ = write!(html, "page {} of {}", page.number, page.count);
+        if page.number < page.count {
+            let _ = write!(html, " <a href=\"{}\">next</a>", query.href(page.number + 1));
+        }
+        html.push_str("</p>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// `{"path", "page", "pages", "total", "entries": [{"name", "type", "size", "modified"}]}`,
+/// the modification time in seconds since the Unix epoch
+pub fn json(path: &str, page: &Page) -> String {
+    let mut json = format!(
+        "{{\"path\":{},\"page\":{},\"pages\":{},\"total\":{},\"entries\":[",
+        json_string(path),
+        page.number,
+        page.count,
+        page.total
+    );
+    for (index, entry) in page.entries.iter().enumerate() {
+        let _ = write!(
+            json,
+            "{}{{\"name\":{},\"type\":\"{}\",\"size\":{},\"modified\":{}}}",
+            if index == 0 { "" } else { "," },
+            json_string(&entry.name),
+            if entry.is_dir { "directory" } else { "file" },
+            entry.size,
+            entry.modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+        );
+    }
+    json.push_str("]}");
+    json
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn entry(name: &str, is_dir: bool, size: u64, modified: u64) -> Entry {
+        Entry {
+            name: name.to_string(),
+            is_dir,
+            size,
+            modified: UNIX_EPOCH + Duration::from_secs(modified),
+        }
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn listings() {
+        assert_eq!(Query::parse(None), Query::default());
+        let query = Query::parse(Some("sort=size&order=desc&page=2&x=1"));
+        assert_eq!((query.sort, query.descending, query.page), (SortKey::Size, true, 2));
+        assert_eq!(Query::parse(Some("sort=owner&page=0")), Query::default());
+
+        let mut entries = vec![
+            entry("b.txt", false, 10, 3),
+            entry("sub", true, 0, 1),
+            entry("a.txt", false, 30, 2),
+            entry("c.txt", false, 20, 1),
+        ];
+        sort(&mut entries, &Query::default());
+        assert_eq!(names(&entries), ["sub", "a.txt", "b.txt", "c.txt"]);
+        sort(&mut entries, &Query::parse(Some("sort=size&order=desc")));
+        assert_eq!(names(&entries), ["sub", "a.txt", "c.txt", "b.txt"]);
+        sort(&mut entries, &Query::parse(Some("sort=modified")));
+        assert_eq!(names(&entries), ["sub", "c.txt", "a.txt", "b.txt"]);
+
+        let second = page(&entries, 2, 3);
+        assert_eq!((names(second.entries), second.number, second.count), (vec!["b.txt"], 2, 2));
+        assert_eq!(page(&entries, 9, 3).number, 2);
+        assert_eq!(page(&[], 1, 3).count, 1);
+
+        assert!(prefers_json("application/json"));
+        assert!(prefers_json("text/html;q=0.5, application/json"));
+        assert!(!prefers_json("text/html,application/xhtml+xml,application/json;q=0.9,*/*;q=0.8"));
+        assert!(!prefers_json("*/*"));
+
+        let odd = [entry("<a&b> \"c\".txt", false, 1, 0)];
+        let html = html("/x&y/", &page(&odd, 1, 10), &Query::default());
+        assert!(html.contains("<title>Index of /x&amp;y/</title>"));
+        assert!(html.contains("href=\"%3Ca%26b%3E%20%22c%22.txt\">&lt;a&amp;b&gt; &quot;c&quot;.txt</a>"));
+        assert_eq!(
+            json("/x\"/", &page(&odd, 1, 10)),
+            "{\"path\":\"/x\\\"/\",\"page\":1,\"pages\":1,\"total\":1,\"entries\":[{\"name\":\"<a&b> \\\"c\\\".txt\",\"type\":\"file\",\"size\":1,\"modified\":0}]}"
+        );
+    }
+}
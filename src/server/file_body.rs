@@ -0,0 +1,254 @@
+use std::io::{self, Read, SeekFrom};
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use hyper::body::{Body, Frame, SizeHint};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc;
+
+use crate::server::compression::{Compressor, Encoding};
+
+/// Bytes read from the file at once
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks read ahead of the connection, the memory of a response is bounded by both
+const CHUNKS_AHEAD: usize = 4;
+
+/// Part of a streamed body
+#[derive(Debug, Clone)]
+pub enum Segment {
+    Bytes(Bytes),
+    /// inclusive range of the file
+    Range(Range<u64>),
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        match self {
+            Segment::Bytes(bytes) => bytes.len() as u64,
+            Segment::Range(range) => range.end - range.start + 1,
+        }
+    }
+}
+
+/// Body of the file server responses: small bodies are kept in memory,
+/// files are read by a task while the connection takes the chunks
+pub enum FileBody {
+    Full(Option<Bytes>),
+    Chunks {
+        chunks: mpsc::Receiver<io::Result<Bytes>>,
+        remaining: u64,
+    },
+    /// compressed on the fly, the length is not known in advance
+    Compressed {
+        chunks: mpsc::Receiver<io::Result<Bytes>>,
+    },
+    /// counts the bytes of another body as they are sent
+    Charged {
+        body: Box<FileBody>,
+        charge: Box<dyn Fn(u64) + Send + Sync>,
+    },
+}
+
+impl FileBody {
+    pub fn empty() -> Self {
+        FileBody::Full(None)
+    }
+
+    pub fn full(bytes: impl Into<Bytes>) -> Self {
+        FileBody::Full(Some(bytes.into()))
+    }
+
+    /// Streams the segments, the ranges are read from the file
+    pub fn stream(mut file: File, segments: Vec<Segment>) -> Self {
+        let remaining = segments.iter().map(Segment::len).sum();
+        let (sender, chunks) = mpsc::channel(CHUNKS_AHEAD);
+        tokio::task::spawn(async move {
+            for segment in segments {
+                let sent = match segment {
+                    Segment::Bytes(bytes) => sender.send(Ok(bytes)).await.is_ok(),
+                    Segment::Range(range) => send_range(&mut file, range, &sender).await,
+                };
+                // the connection is closed or the file could not be read
+                if !sent {
+                    return;
+                }
+            }
+        });
+        FileBody::Chunks { chunks, remaining }
+    }
+
+    /// Streams the whole file compressed by a blocking task
+    pub async fn compressed(file: File, encoding: Encoding) -> Self {
+        let file = file.into_std().await;
+        let (sender, chunks) = mpsc::channel(CHUNKS_AHEAD);
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = send_compressed(file, encoding, &sender) {
+                log::error!("could not compress the file: {}", err);
+                let _ = sender.blocking_send(Err(err));
+            }
+        });
+        FileBody::Compressed { chunks }
+    }
+
+    /// Passes the length of each chunk to `charge` when it is sent
+    pub fn charged(self, charge: impl Fn(u64) + Send + Sync + 'static) -> Self {
+        FileBody::Charged {
+            body: Box::new(self),
+            charge: Box::new(charge),
+        }
+    }
+}
+
+/// Stops quietly if the chunks are no longer received
+fn send_compressed(
+    mut file: std::fs::File,
+    encoding: Encoding,
+    sender: &mpsc::Sender<io::Result<Bytes>>,
+) -> io::Result<()> {
+    let mut compressor = Compressor::new(encoding)?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        let chunk = compressor.compress(&buffer[..read])?;
+        if !chunk.is_empty() && sender.blocking_send(Ok(chunk)).is_err() {
+            return Ok(());
+        }
+    }
+    let _ = sender.blocking_send(Ok(compressor.finish()?));
+    Ok(())
+}
+
+/// Returns false if the chunks are no longer received or reading failed
+async fn send_range(file: &mut File, range: Range<u64>, sender: &mpsc::Sender<io::Result<Bytes>>) -> bool {
+    if let Err(err) = file.seek(SeekFrom::Start(range.start)).await {
+        let _ = sender.send(Err(err)).await;
+        return false;
+    }
+    let mut remaining = range.end - range.start + 1;
+    while remaining > 0 {
+        let mut chunk = BytesMut::zeroed(CHUNK_SIZE.min(remaining as usize));
+        let chunk = match file.read_exact(&mut chunk).await {
+            Ok(_) => chunk.freeze(),
+            // the file was truncated or could not be read, the response is aborted
+            Err(err) => {
+                log::error!("could not read the file at {}: {}", range.end + 1 - remaining, err);
+                let _ = sender.send(Err(err)).await;
+                return false;
+            }
+        };
+        remaining -= chunk.len() as u64;
+        if sender.send(Ok(chunk)).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+impl std::fmt::Debug for FileBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileBody::Full(bytes) => f.debug_tuple("Full").field(bytes).finish(),
+            FileBody::Chunks { remaining, .. } => f.debug_struct("Chunks").field("remaining", remaining).finish(),
+            FileBody::Compressed { .. } => f.write_str("Compressed"),
+            FileBody::Charged { body, .. } => f.debug_tuple("Charged").field(body).finish(),
+        }
+    }
+}
+
+impl Body for FileBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.get_mut() {
+            FileBody::Full(bytes) => Poll::Ready(bytes.take().map(|bytes| Ok(Frame::data(bytes)))),
+            FileBody::Chunks { chunks, remaining } => chunks.poll_recv(cx).map(|chunk| {
+                chunk.map(|chunk| {
+                    chunk.map(|chunk| {
+                        *remaining = remaining.saturating_sub(chunk.len() as u64);
+                        Frame::data(chunk)
+                    })
+                })
+            }),
+            FileBody::Compressed { chunks } => chunks.poll_recv(cx).map(|chunk| chunk.map(|chunk| chunk.map(Frame::data))),
+            FileBody::Charged { body, charge } => Pin::new(body.as_mut()).poll_frame(cx).map(|frame| {
+                if let Some(data) = frame.as_ref().and_then(|frame| frame.as_ref().ok()).and_then(Frame::data_ref) {
+                    charge(data.len() as u64);
+                }
+                frame
+            }),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self {
+            FileBody::Full(bytes) => bytes.is_none(),
+            FileBody::Chunks { remaining, .. } => *remaining == 0,
+            FileBody::Compressed { .. } => false,
+            FileBody::Charged { body, .. } => body.is_end_stream(),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            FileBody::Full(bytes) => SizeHint::with_exact(bytes.as_ref().map_or(0, |bytes| bytes.len() as u64)),
+            FileBody::Chunks { remaining, .. } => SizeHint::with_exact(*remaining),
+            FileBody::Compressed { .. } => SizeHint::default(),
+            FileBody::Charged { body, .. } => body.size_hint(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn streamed_segments() {
+        let path = std::env::temp_dir().join(format!("poncu-body-{}", std::process::id()));
+        let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+
+        let segments = vec![
+            Segment::Bytes(Bytes::from_static(b"--")),
+            Segment::Range(10..150_009),
+            Segment::Bytes(Bytes::from_static(b"--")),
+            Segment::Range(0..0),
+        ];
+        let body = FileBody::stream(File::open(&path).await.unwrap(), segments);
+        assert_eq!(body.size_hint().exact(), Some(150_004 + 1));
+        let collected = body.collect().await.unwrap().to_bytes();
+        let mut expected = b"--".to_vec();
+        expected.extend_from_slice(&contents[10..150_010]);
+        expected.extend_from_slice(b"--");
+        expected.push(contents[0]);
+        assert_eq!(collected, expected);
+
+        // compressed bodies are charged by the bytes sent
+        let charged = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let counter = charged.clone();
+        let body = FileBody::compressed(File::open(&path).await.unwrap(), Encoding::Gzip)
+            .await
+            .charged(move |sent| {
+                counter.fetch_add(sent, std::sync::atomic::Ordering::Relaxed);
+            });
+        let compressed = body.collect().await.unwrap().to_bytes();
+        assert_eq!(charged.load(std::sync::atomic::Ordering::Relaxed), compressed.len() as u64);
+        assert!(compressed.len() < contents.len());
+
+        // a range beyond the end of a truncated file fails the body
+        let body = FileBody::stream(File::open(&path).await.unwrap(), vec![Segment::Range(199_990..200_100)]);
+        assert!(body.collect().await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt::Write;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Decodes the path of a request URI and normalises it to `/folder/file`.
+/// Returns none for paths that must be refused: `..` segments, absolute paths or drive prefixes
+/// hidden in a segment, invalid escapes, NUL bytes and backslashes.
+pub fn normalize(uri_path: &str) -> Option<String> {
+    let decoded = String::from_utf8(percent_decode(uri_path)?).ok()?;
+    let mut normalized = String::with_capacity(decoded.len() + 1);
+    for segment in decoded.split('/') {
+        if segment.is_empty() || segment == "." {
+            continue;
+        }
+        if segment.contains(['\\', '\0']) {
+            return None;
+        }
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => {}
+            _ => return None,
+        }
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// Path of a normalised request path on disk.
+/// Fails with `PermissionDenied` if a symbolic link leads out of the root, `root` must be canonical.
+pub async fn resolve(root: &Path, path: &str) -> io::Result<PathBuf> {
+    let file_path = tokio::fs::canonicalize(root.join(path.trim_start_matches('/'))).await?;
+    if !file_path.starts_with(root) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} leads out of the root", path),
+        ));
+    }
+    Ok(file_path)
+}
+
+/// Request path of a resolved path on disk, none if it is not below the root or not UTF-8.
+/// The access rules are checked against it as well, so that symbolic links do not lead around them.
+pub fn request_path(root: &Path, file_path: &Path) -> Option<String> {
+    let mut path = String::new();
+    for component in file_path.strip_prefix(root).ok()?.components() {
+        match component {
+            Component::Normal(name) => {
+                path.push('/');
+                path.push_str(name.to_str()?);
+            }
+            _ => return None,
+        }
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    Some(path)
+}
+
+/// Path on disk of a file to be written: its parent directory must exist below the root.
+/// The file itself is not resolved, a symbolic link in its place is replaced.
+pub async fn resolve_new(root: &Path, path: &str) -> io::Result<PathBuf> {
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a file name is required"));
+    }
+    let dir = resolve(root, parent).await?;
+    if !tokio::fs::metadata(&dir).await?.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{:?} is not a directory", dir)));
+    }
+    Ok(dir.join(name))
+}
+
+/// Percent-encodes a normalised path or a file name for a URI, the unreserved characters and `/` are kept
+pub fn encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+    encoded
+}
+
+fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized_paths() {
+        assert_eq!(normalize("/").as_deref(), Some("/"));
+        assert_eq!(normalize("/docs//a%20b.txt").as_deref(), Some("/docs/a b.txt"));
+        assert_eq!(normalize("/./docs/%C3%A4.txt").as_deref(), Some("/docs/ä.txt"));
+        assert_eq!(normalize("//etc/passwd").as_deref(), Some("/etc/passwd"));
+        assert_eq!(normalize("/docs/../secret"), None);
+        assert_eq!(normalize("/docs/%2e%2e/secret"), None);
+        assert_eq!(normalize("/docs/..%2Fsecret"), None);
+        assert_eq!(normalize("/docs/..%5Csecret"), None);
+        assert_eq!(normalize("/docs/a%00.txt"), None);
+        assert_eq!(normalize("/docs/a%2"), None);
+        assert_eq!(normalize("/docs/%FF"), None);
+        assert_eq!(encode("/docs/a b/%ä?.txt"), "/docs/a%20b/%25%C3%A4%3F.txt");
+    }
+
+    #[tokio::test]
+    async fn symlink_escapes() {
+        let dir = std::env::temp_dir().join(format!("poncu-files-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/a.txt"), "a").unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("docs/link.txt")).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        let resolved = resolve(&root, "/docs/a.txt").await;
+        assert_eq!(resolved.unwrap(), root.join("docs/a.txt"));
+        let missing = resolve(&root, "/docs/b.txt").await;
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(resolve_new(&root, "/docs/b.txt").await.unwrap(), root.join("docs/b.txt"));
+        let no_parent = resolve_new(&root, "/docs/sub/b.txt").await;
+        assert_eq!(no_parent.unwrap_err().kind(), io::ErrorKind::NotFound);
+        #[cfg(unix)]
+        {
+            let escaped = resolve(&root, "/docs/link.txt").await;
+            assert_eq!(escaped.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+            // links within the root are followed, the rules are checked against their targets
+            std::os::unix::fs::symlink(root.join("docs"), root.join("public")).unwrap();
+            let linked = resolve(&root, "/public/a.txt").await.unwrap();
+            assert_eq!(request_path(&root, &linked).as_deref(), Some("/docs/a.txt"));
+        }
+        assert_eq!(request_path(&root, &root).as_deref(), Some("/"));
+        assert_eq!(request_path(&root, &dir.join("secret.txt")), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
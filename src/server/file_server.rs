@@ -1,133 +1,1099 @@
-use std::io::SeekFrom;
-use std::net::SocketAddr;
-use std::path::Path;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
 
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncSeekExt;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::runtime::Runtime;
+use tokio_rustls::TlsAcceptor;
 
+use hyper::body::Body;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, Result, StatusCode};
 use hyper_util::rt::TokioIo;
 
-use bytes::Bytes;
-use http_body_util::Full;
-
 use log;
 
-use crate::utils::config::Config;
+use crate::server::access::Access;
+use crate::server::acl::Resource;
+use crate::server::auth::Caller;
+use crate::server::byte_ranges;
+use crate::server::compression::{self, Encoding};
+use crate::server::conditional::{Precondition, Validators};
+use crate::server::dir_listing::{self, Query};
+use crate::server::file_body::{FileBody, Segment};
+use crate::server::file_paths;
+use crate::server::file_upload::{self, UploadError};
+use crate::server::upload_sessions::{self, Session, UploadSessions};
+use crate::server::mime_types::MimeTypes;
+use crate::server::listeners::{bind_all, Listener};
+use crate::utils::config::{self, Config, Permission};
+use crate::utils::tls;
 use http_common::http_range::{self, HttpRange};
 
+/// Headers of the resumable uploads
+const UPLOAD_OFFSET: &str = "upload-offset";
+const UPLOAD_LENGTH: &str = "upload-length";
+const UPLOAD_EXPIRES: &str = "upload-expires";
+
+/// How often expired upload sessions are looked for
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
 // A simple type alias so as to DRY.
 type FileServerResult<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
+/// The access settings are shared with the TCP server of the node, if it runs in the same process
 pub fn start_file_server(
     config: &Config,
+    access: Arc<Access>,
     flag_ready: Arc<AtomicBool>,
     flag_shutdown: Arc<AtomicBool>,
-) -> JoinHandle<()> {
+) -> std::io::Result<JoinHandle<()>> {
     assert!(config.file_server.is_some());
     let file_server_config = config.file_server.as_ref().unwrap();
-    assert!(!file_server_config.listen_on.is_empty());
-    let listen_on = file_server_config.listen_on[0];
 
     log::info!("Starting file server...");
+    let context = Arc::new(FileContext::with_config(file_server_config, access)?);
+    log::info!("Serving files from {:?}", context.root);
+    let tls = file_server_config
+        .tls
+        .as_ref()
+        .map(tls::server_config)
+        .transpose()?
+        .map(TlsAcceptor::from);
 
-    std::thread::spawn(move || {
+    let listeners = bind_all(
+        &file_server_config.listen_on(),
+        file_server_config.unix_socket.as_deref(),
+    )?;
+
+    Ok(std::thread::spawn(move || {
         let async_runtime = Runtime::new().unwrap();
         async_runtime.block_on(async {
-            if let Err(err) = start(listen_on, flag_ready, flag_shutdown).await {
+            if let Err(err) = start(listeners, tls, context, flag_ready, flag_shutdown).await {
                 log::error!("File server failed: {:?}", err);
             }
         });
-    })
+    }))
 }
 
 async fn start(
-    listen_on: SocketAddr,
+    listeners: Vec<Listener>,
+    tls: Option<TlsAcceptor>,
+    context: Arc<FileContext>,
     flag_ready: Arc<AtomicBool>,
     flag_shutdown: Arc<AtomicBool>,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(listen_on).await?;
-
-    flag_ready.store(true, Ordering::SeqCst);
-
-    log::info!("File server running on http://{}", listen_on);
+    let mut tasks = Vec::with_capacity(listeners.len());
+    for listener in listeners {
+        let name = listener.name();
+        let flag_shutdown = flag_shutdown.clone();
+        let context = context.clone();
+        match listener {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = TcpListener::from_std(listener)?;
+                let tls = tls.clone();
+                let scheme = if tls.is_some() { "https" } else { "http" };
+                log::info!("File server running on {}://{}", scheme, name);
+                tasks.push(tokio::task::spawn(async move {
+                    while !flag_shutdown.load(Ordering::SeqCst) {
+                        let (stream, addr) = listener.accept().await?;
+                        match tls.clone() {
+                            Some(acceptor) => {
+                                let context = context.clone();
+                                tokio::task::spawn(async move {
+                                    match acceptor.accept(stream).await {
+                                        Ok(stream) => serve_connection(stream, Some(addr.ip()), context),
+                                        Err(err) => log::warn!("TLS handshake with {} failed: {}", addr, err),
+                                    }
+                                });
+                            }
+                            None => serve_connection(stream, Some(addr.ip()), context.clone()),
+                        }
+                    }
+                    Ok::<(), std::io::Error>(())
+                }));
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                listener.set_nonblocking(true)?;
+                let listener = UnixListener::from_std(listener)?;
+                log::info!("File server running on {}", name);
+                tasks.push(tokio::task::spawn(async move {
+                    while !flag_shutdown.load(Ordering::SeqCst) {
+                        let (stream, _) = listener.accept().await?;
+                        serve_connection(stream, None, context.clone());
+                    }
+                    Ok::<(), std::io::Error>(())
+                }));
+            }
+        }
+    }
 
-    while !flag_shutdown.load(Ordering::SeqCst) {
-        let (stream, _) = listener.accept().await?;
+    if context.sessions.is_some() {
+        let context = context.clone();
+        let flag_shutdown = flag_shutdown.clone();
         tokio::task::spawn(async move {
-            let io = TokioIo::new(stream);
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(io, service_fn(file_service))
-                .await
-            {
-                log::error!("Failed to serve connection: {:?}", err);
+            let interval = SWEEP_INTERVAL.min(context.uploads.session_ttl);
+            while !flag_shutdown.load(Ordering::SeqCst) {
+                tokio::time::sleep(interval).await;
+                let Some(sessions) = context.sessions.as_ref() else {
+                    return;
+                };
+                match sessions.sweep().await {
+                    Ok(0) => {}
+                    Ok(removed) => log::info!("removed {} expired uploads", removed),
+                    Err(err) => log::error!("could not remove expired uploads: {}", err),
+                }
             }
         });
     }
+
+    flag_ready.store(true, Ordering::SeqCst);
+
+    for task in tasks {
+        task.await??;
+    }
     Ok(())
 }
 
-async fn file_service(req: Request<hyper::body::Incoming>) -> Result<Response<Full<Bytes>>> {
+/// Serves the requests of a connection, the remote address is none for Unix domain sockets
+fn serve_connection<S>(stream: S, remote: Option<IpAddr>, context: Arc<FileContext>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::task::spawn(async move {
+        let io = TokioIo::new(stream);
+        let service = service_fn(move |req| file_service(req, remote, context.clone()));
+        if let Err(err) = http1::Builder::new()
+            .serve_connection(io, service)
+            .await
+        {
+            log::error!("Failed to serve connection: {:?}", err);
+        }
+    });
+}
+
+/// State shared by the requests of the file server
+struct FileContext {
+    /// bearer tokens are required if JWTs are configured; the rules and limits apply to both servers of the node
+    access: Arc<Access>,
+    /// canonical path of the served directory
+    root: PathBuf,
+    content_range_requests: bool,
+    coalesce_ranges: bool,
+    mime_types: MimeTypes,
+    compression: config::Compression,
+    directories: config::Directories,
+    uploads: config::Uploads,
+    /// directory below the root receiving the bodies of uploads, it is not served
+    staging: PathBuf,
+    /// writes check their preconditions and replace files one at a time
+    commit_lock: tokio::sync::Mutex<()>,
+    /// resumable uploads, if uploads are enabled
+    sessions: Option<UploadSessions>,
+}
+
+/// Representation of a file chosen for a request
+struct Representation {
+    /// the file itself or its precompressed sidecar
+    path: PathBuf,
+    /// none if the file is compressed on the fly
+    length: Option<u64>,
+    encoding: Option<Encoding>,
+    validators: Validators,
+    /// the choice depends on `Accept-Encoding`
+    vary: bool,
+}
+
+impl Representation {
+    /// Chooses between a precompressed sidecar, compressing the file on the fly and the file as it is.
+    /// Ranges are always taken from the file as it is, so that their offsets hold.
+    async fn choose(
+        req: &Request<hyper::body::Incoming>,
+        file_path: &Path,
+        metadata: &std::fs::Metadata,
+        content_type: &str,
+        ranged: bool,
+        context: &FileContext,
+    ) -> Self {
+        let settings = &context.compression;
+        let on_the_fly = settings.enabled
+            && compression::compressible(content_type)
+            && metadata.len() >= settings.min_size.0
+            && (settings.max_size.0 == 0 || metadata.len() <= settings.max_size.0);
+        let mut sidecars = Vec::new();
+        if settings.precompressed {
+            let modified = metadata.modified().unwrap_or(std::time::UNIX_EPOCH);
+            for encoding in Encoding::ALL {
+                if let Some(sidecar) = compression::find_sidecar(&context.root, file_path, encoding, modified).await {
+                    sidecars.push((encoding, sidecar));
+                }
+            }
+        }
+
+        let vary = on_the_fly || !sidecars.is_empty();
+        let identity = Representation {
+            path: file_path.to_path_buf(),
+            length: Some(metadata.len()),
+            encoding: None,
+            validators: Validators::new(metadata),
+            vary,
+        };
+        if !vary || ranged {
+            return identity;
+        }
+        let accept_encoding = req
+            .headers()
+            .get(hyper::header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        let available: Vec<Encoding> = sidecars.iter().map(|(encoding, _)| *encoding).collect();
+        if let Some(encoding) = compression::negotiate(accept_encoding, &available) {
+            if let Some((_, (path, sidecar_metadata))) = sidecars.into_iter().find(|(sidecar, _)| *sidecar == encoding) {
+                return Representation {
+                    path,
+                    length: Some(sidecar_metadata.len()),
+                    encoding: Some(encoding),
+                    validators: Validators::new(&sidecar_metadata).encoded(encoding.token()),
+                    vary,
+                };
+            }
+        }
+        match compression::negotiate(accept_encoding, &Encoding::ALL).filter(|_| on_the_fly) {
+            Some(encoding) => Representation {
+                length: None,
+                encoding: Some(encoding),
+                validators: identity.validators.clone().encoded(encoding.token()),
+                ..identity
+            },
+            None => identity,
+        }
+    }
+
+    /// Validators, encoding and `Vary`
+    fn headers(&self, mut builder: hyper::http::response::Builder) -> hyper::http::response::Builder {
+        builder = builder
+            .header(hyper::header::ETAG, &self.validators.etag)
+            .header(hyper::header::LAST_MODIFIED, self.validators.http_date());
+        if let Some(encoding) = self.encoding {
+            builder = builder.header(hyper::header::CONTENT_ENCODING, encoding.token());
+        }
+        if self.vary {
+            builder = builder.header(hyper::header::VARY, "Accept-Encoding");
+        }
+        builder
+    }
+}
+
+impl FileContext {
+    /// Fails if the root or the staging directory is not available
+    fn with_config(file_server_config: &config::FileServer, access: Arc<Access>) -> std::io::Result<Self> {
+        let root = file_server_config.root.canonicalize().map_err(|err| {
+            std::io::Error::new(err.kind(), format!("file server root {:?}: {}", file_server_config.root, err))
+        })?;
+        let uploads = &file_server_config.uploads;
+        let staging = root.join(&uploads.staging_dir);
+        let sessions = if uploads.enabled {
+            std::fs::create_dir_all(&staging).map_err(|err| {
+                std::io::Error::new(err.kind(), format!("staging directory {:?}: {}", staging, err))
+            })?;
+            Some(UploadSessions::new(staging.clone(), uploads.session_ttl, uploads.sessions_max))
+        } else {
+            None
+        };
+        Ok(FileContext {
+            access,
+            root,
+            content_range_requests: file_server_config.content_range_requests,
+            coalesce_ranges: file_server_config.coalesce_ranges,
+            mime_types: MimeTypes::with_config(file_server_config),
+            compression: file_server_config.compression.clone(),
+            directories: file_server_config.directories.clone(),
+            uploads: file_server_config.uploads.clone(),
+            staging,
+            commit_lock: tokio::sync::Mutex::new(()),
+            sessions,
+        })
+    }
+
+    /// Identifies the caller by the `Authorization: Bearer` header
+    fn authenticate<T>(&self, req: &Request<T>) -> std::result::Result<Caller, String> {
+        let jwt = match self.access.jwt() {
+            Some(jwt) => jwt,
+            None => return Ok(Caller::Anonymous),
+        };
+        let authorization = req
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .ok_or_else(|| "authentication required".to_string())?
+            .to_str()
+            .map_err(|_| "invalid authorization header".to_string())?;
+        let token = authorization
+            .split_once(' ')
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+            .map(|(_, token)| token.trim())
+            .ok_or_else(|| "bearer token expected".to_string())?;
+        jwt.verify(token).map(Caller::from)
+    }
+}
+
+/// The caller is available to the handlers as a request extension
+async fn file_service(
+    mut req: Request<hyper::body::Incoming>,
+    remote: Option<IpAddr>,
+    context: Arc<FileContext>,
+) -> Result<Response<FileBody>> {
     if log::log_enabled!(log::Level::Trace) {
         log::trace!("recevied request:{:#?}", req);
     }
 
+    let caller = match context.authenticate(&req) {
+        Ok(caller) => caller,
+        Err(msg) => {
+            log::warn!("unauthorized request for {}: {}", req.uri(), msg);
+            let credentials = req.headers().contains_key(hyper::header::AUTHORIZATION);
+            return Ok(send_error_401(credentials));
+        }
+    };
+    if let Err((reason, retry_after)) = context.access.limits().check_rates(&caller, remote, 0) {
+        log::debug!("limit exceeded for {}: {}", req.uri(), reason);
+        return Ok(send_error_429(retry_after));
+    }
+
+    // the rules are checked against the decoded path, as it is resolved on disk
+    let path = match file_paths::normalize(req.uri().path()) {
+        Some(path) => path,
+        None => {
+            log::warn!("refused path: {}", req.uri().path());
+            return Ok(send_error_403());
+        }
+    };
+    // the data of unfinished uploads is not served
+    if upload_sessions::is_staging(&path, &context.uploads.staging_dir) {
+        return Ok(send_error_404());
+    }
+    let uploads = context.uploads.enabled;
+    let session = upload_session_id(req.uri().query()).filter(|_| uploads).map(str::to_string);
+    let permission = match (req.method(), &session) {
+        (&Method::HEAD | &Method::GET, None) => Permission::Read,
+        (&Method::PUT | &Method::POST, None) if uploads => Permission::Write,
+        (&Method::DELETE, None) if uploads => Permission::Delete,
+        (&Method::HEAD | &Method::PATCH | &Method::PUT | &Method::DELETE, Some(_)) => Permission::Write,
+        _ => return Ok(send_error_405(uploads)),
+    };
+    if permission != Permission::Read && matches!(caller, Caller::Anonymous) {
+        log::warn!("unauthenticated {} of {}", req.method(), path);
+        return Ok(send_error_401(false));
+    }
+    if !context.access.acl().allows(&caller, permission, Resource::Path(&path)) {
+        log::warn!("access denied to {:?}: {:?} {}", caller, permission, path);
+        return Ok(send_error_403());
+    }
+    req.extensions_mut().insert(caller.clone());
+
+    let mut response = match (req.method().clone(), session) {
+        (_, Some(id)) => upload_session(req, &path, &id, &caller, remote, &context).await,
+        (Method::POST, None) => create_upload_session(&req, &path, &caller, &context).await,
+        (Method::PUT, None) => upload(req, &path, &caller, remote, &context).await,
+        (Method::DELETE, None) => delete(&req, &path, &caller, &context).await,
+        _ => read(&req, &path, &caller, &context).await,
+    }?;
+    // browsers must not second-guess the content types
+    response.headers_mut().insert(
+        hyper::header::X_CONTENT_TYPE_OPTIONS,
+        hyper::header::HeaderValue::from_static("nosniff"),
+    );
+    // bodies compressed on the fly are charged as they are sent
+    match response.body().size_hint().exact() {
+        Some(sent) => context.access.limits().charge(&caller, remote, sent),
+        None => {
+            let access = context.access.clone();
+            let (parts, body) = response.into_parts();
+            let body = body.charged(move |sent| access.limits().charge(&caller, remote, sent));
+            response = Response::from_parts(parts, body);
+        }
+    }
+    Ok(response)
+}
+
+/// Sends a file, or a directory by its index file or listing
+async fn read(
+    req: &Request<hyper::body::Incoming>,
+    path: &str,
+    caller: &Caller,
+    context: &FileContext,
+) -> Result<Response<FileBody>> {
+    let file_path = match file_paths::resolve(&context.root, path).await {
+        Ok(file_path) => file_path,
+        Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+            log::warn!("refused path: {}", err);
+            return Ok(send_error_403());
+        }
+        Err(err) => {
+            log::error!("file not found: {}: {}", path, err);
+            return Ok(send_error_404());
+        }
+    };
+    if !allowed_on_disk(context, caller, Permission::Read, &file_path) {
+        log::warn!("access denied to {:?}: {} leads to {:?}", caller, path, file_path);
+        return Ok(send_error_403());
+    }
+    match tokio::fs::metadata(&file_path).await.is_ok_and(|metadata| metadata.is_dir()) {
+        true => directory(req, path, &file_path, caller, context).await,
+        false => file_response(req, &file_path, context).await,
+    }
+}
+
+/// Receives a file by PUT. The body goes to a temporary file that is renamed over the target,
+/// the preconditions are evaluated before the body is received and again before the rename.
+async fn upload(
+    req: Request<hyper::body::Incoming>,
+    path: &str,
+    caller: &Caller,
+    remote: Option<IpAddr>,
+    context: &FileContext,
+) -> Result<Response<FileBody>> {
+    if !context.uploads.enabled {
+        return Ok(send_error_405(false));
+    }
+    let (parts, body) = req.into_parts();
+    let headers = &parts.headers;
+    if parts.uri.path().ends_with('/') {
+        return Ok(blank_response(StatusCode::CONFLICT));
+    }
+    // partial and encoded bodies are not stored as they are
+    if headers.contains_key(hyper::header::CONTENT_RANGE) {
+        return Ok(blank_response(StatusCode::BAD_REQUEST));
+    }
+    let encoded = headers
+        .get(hyper::header::CONTENT_ENCODING)
+        .is_some_and(|encoding| encoding.to_str().map_or(true, |encoding| !encoding.trim().eq_ignore_ascii_case("identity")));
+    if encoded {
+        return Ok(blank_response(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+    let max_size = context.uploads.max_size.0;
+    let content_length = headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    if content_length.is_some_and(|length| length > max_size) {
+        return Ok(blank_response(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+    let expected = match file_upload::expected_digests(headers) {
+        Ok(expected) => expected,
+        Err(msg) => {
+            log::warn!("upload of {}: {}", path, msg);
+            return Ok(blank_response(StatusCode::BAD_REQUEST));
+        }
+    };
+
+    let target = match resolve_target(context, caller, Permission::Write, path).await {
+        Ok(target) => target,
+        Err(response) => return Ok(response),
+    };
+    if let Err(response) = write_precondition(&target, headers, context).await {
+        return Ok(response);
+    }
+    let temp = match file_upload::receive(body, &context.staging, max_size, &expected).await {
+        Ok(temp) => temp,
+        Err(err) => {
+            log::warn!("upload of {} failed: {:?}", path, err);
+            return Ok(match err {
+                UploadError::TooLarge => blank_response(StatusCode::PAYLOAD_TOO_LARGE),
+                UploadError::DigestMismatch(_) | UploadError::Body(_) => blank_response(StatusCode::BAD_REQUEST),
+                UploadError::Io(_) => send_error_500(),
+            });
+        }
+    };
+    context.access.limits().charge(caller, remote, temp.size);
+
+    let _commit = context.commit_lock.lock().await;
+    let existed = match write_precondition(&target, headers, context).await {
+        Ok(current) => current.is_some(),
+        Err(response) => return Ok(response),
+    };
+    let write = match context.access.limits().admit_file(caller, &target, Some(temp.size)) {
+        Ok(write) => write,
+        Err(reason) => {
+            log::warn!("upload of {} refused: {}", path, reason);
+            return Ok(blank_response(StatusCode::INSUFFICIENT_STORAGE));
+        }
+    };
+    let stored = temp.persist(&target).await;
+    context.access.limits().finish(write, stored.is_ok());
+    if let Err(err) = stored {
+        log::error!("could not store {:?}: {}", target, err);
+        return Ok(send_error_500());
+    }
+    log::debug!("stored {:?}", target);
+    Ok(send_stored(&target, path, existed).await)
+}
+
+/// 201 Created for a new file, 204 No Content for a replaced one, with the validators of the file
+async fn send_stored(target: &Path, path: &str, existed: bool) -> Response<FileBody> {
+    let status = if existed { StatusCode::NO_CONTENT } else { StatusCode::CREATED };
+    let mut builder = Response::builder().status(status);
+    if let Ok(metadata) = tokio::fs::metadata(target).await {
+        let validators = Validators::new(&metadata);
+        builder = builder
+            .header(hyper::header::ETAG, &validators.etag)
+            .header(hyper::header::LAST_MODIFIED, validators.http_date());
+    }
+    if !existed {
+        builder = builder.header(hyper::header::LOCATION, file_paths::encode(path));
+    }
+    match builder.body(FileBody::empty()) {
+        Ok(response) => response,
+        Err(_) => {
+            log::error!("unable to build response");
+            send_error_500()
+        }
+    }
+}
+
+/// `upload` query parameter naming a resumable upload session
+fn upload_session_id(query: Option<&str>) -> Option<&str> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find_map(|(key, value)| (key == "upload").then_some(value))
+}
+
+/// Client id bound to the sessions of a caller
+fn session_owner(caller: &Caller) -> Option<String> {
+    match caller {
+        Caller::Client { id, .. } => Some(id.clone()),
+        Caller::Anonymous | Caller::Node(_) => None,
+    }
+}
+
+/// Starts a resumable upload of a file, the total length may be given by `Upload-Length`.
+/// The session is addressed by the path of the file with the `upload` query parameter.
+async fn create_upload_session(
+    req: &Request<hyper::body::Incoming>,
+    path: &str,
+    caller: &Caller,
+    context: &FileContext,
+) -> Result<Response<FileBody>> {
+    let Some(sessions) = context.sessions.as_ref() else {
+        return Ok(send_error_405(false));
+    };
+    if req.uri().path().ends_with('/') {
+        return Ok(blank_response(StatusCode::CONFLICT));
+    }
+    let length = match req.headers().get(UPLOAD_LENGTH) {
+        Some(length) => match length.to_str().ok().and_then(|length| length.parse::<u64>().ok()) {
+            Some(length) => Some(length),
+            None => return Ok(blank_response(StatusCode::BAD_REQUEST)),
+        },
+        None => None,
+    };
+    if length.is_some_and(|length| length > context.uploads.max_size.0) {
+        return Ok(blank_response(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+    // e.g. If-None-Match: * before sending gigabytes for a file that exists meanwhile
+    let target = match resolve_target(context, caller, Permission::Write, path).await {
+        Ok(target) => target,
+        Err(response) => return Ok(response),
+    };
+    if let Err(response) = write_precondition(&target, req.headers(), context).await {
+        return Ok(response);
+    }
+
+    let session = match sessions.create(path, session_owner(caller), length).await {
+        Ok(session) => session,
+        Err(err) if err.kind() == std::io::ErrorKind::QuotaExceeded => {
+            log::warn!("upload session for {} refused: {}", path, err);
+            return Ok(blank_response(StatusCode::TOO_MANY_REQUESTS));
+        }
+        Err(err) => {
+            log::error!("could not create an upload session for {}: {}", path, err);
+            return Ok(send_error_500());
+        }
+    };
+    log::debug!("upload session {} for {}", session.id, path);
+    let location = format!("{}?upload={}", file_paths::encode(path), session.id);
+    let builder = Response::builder()
+        .status(StatusCode::CREATED)
+        .header(hyper::header::LOCATION, location);
+    Ok(send_session(builder, &session, 0))
+}
+
+/// Answers the requests of a session: HEAD for the committed offset, PATCH to append a chunk,
+/// PUT to finalise and DELETE to abort
+async fn upload_session(
+    req: Request<hyper::body::Incoming>,
+    path: &str,
+    id: &str,
+    caller: &Caller,
+    remote: Option<IpAddr>,
+    context: &FileContext,
+) -> Result<Response<FileBody>> {
+    let Some(sessions) = context.sessions.as_ref() else {
+        return Ok(send_error_405(false));
+    };
+    // sessions of other callers or files are not revealed
+    let mut session = match sessions.get(id).await {
+        Ok(Some(session)) if session.path == path && session.owner == session_owner(caller) => session,
+        Ok(_) => return Ok(send_error_404()),
+        Err(err) => {
+            log::error!("could not read upload session {}: {}", id, err);
+            return Ok(send_error_500());
+        }
+    };
+    // the offset may be asked for while a chunk is received
+    let claim = match *req.method() {
+        Method::HEAD => None,
+        _ => match sessions.claim(id) {
+            Some(claim) => Some(claim),
+            None => return Ok(blank_response(StatusCode::CONFLICT)),
+        },
+    };
+    let data_path = sessions.data_path(id);
+    let offset = match sessions.offset(&session).await {
+        Ok(offset) => offset,
+        Err(err) => {
+            log::error!("could not read the data of upload session {}: {}", id, err);
+            return Ok(send_error_500());
+        }
+    };
+
+    let response = match *req.method() {
+        Method::HEAD => Ok(send_session(Response::builder().status(StatusCode::OK), &session, offset)),
+        Method::PATCH => {
+            let chunk_offset = req
+                .headers()
+                .get(UPLOAD_OFFSET)
+                .and_then(|offset| offset.to_str().ok())
+                .and_then(|offset| offset.parse::<u64>().ok());
+            match chunk_offset {
+                None => return Ok(blank_response(StatusCode::BAD_REQUEST)),
+                // the client resumes from the committed offset
+                Some(chunk_offset) if chunk_offset != offset => {
+                    return Ok(send_session(Response::builder().status(StatusCode::CONFLICT), &session, offset));
+                }
+                Some(_) => {}
+            }
+            let mut file = match tokio::fs::OpenOptions::new().append(true).open(&data_path).await {
+                Ok(file) => file,
+                Err(err) => {
+                    log::error!("could not open {:?}: {}", data_path, err);
+                    return Ok(send_error_500());
+                }
+            };
+            let max_size = context.uploads.max_size.0;
+            let limit = session.length.map_or(max_size, |length| length.min(max_size));
+            let mut written = offset;
+            let result = file_upload::append(req.into_body(), &mut file, &mut written, limit).await;
+            context.access.limits().charge(caller, remote, written - offset);
+            if let Err(err) = sessions.touch(&mut session).await {
+                log::error!("could not update upload session {}: {}", id, err);
+            }
+            let status = match result {
+                Ok(()) => StatusCode::NO_CONTENT,
+                Err(err) => {
+                    log::warn!("chunk of upload session {} failed at {}: {:?}", id, written, err);
+                    match err {
+                        UploadError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+                        UploadError::DigestMismatch(_) | UploadError::Body(_) => StatusCode::BAD_REQUEST,
+                        UploadError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                    }
+                }
+            };
+            Ok(send_session(Response::builder().status(status), &session, written))
+        }
+        Method::PUT => {
+            let expected = match file_upload::expected_digests(req.headers()) {
+                Ok(expected) => expected,
+                Err(msg) => {
+                    log::warn!("upload session {}: {}", id, msg);
+                    return Ok(blank_response(StatusCode::BAD_REQUEST));
+                }
+            };
+            if session.length.is_some_and(|length| length != offset) {
+                return Ok(send_session(Response::builder().status(StatusCode::CONFLICT), &session, offset));
+            }
+            match file_upload::verify_file(&data_path, &expected).await {
+                Ok(()) => {}
+                Err(UploadError::DigestMismatch(algorithm)) => {
+                    log::warn!("upload session {}: {:?} digest mismatch", id, algorithm);
+                    return Ok(blank_response(StatusCode::BAD_REQUEST));
+                }
+                Err(err) => {
+                    log::error!("could not verify upload session {}: {:?}", id, err);
+                    return Ok(send_error_500());
+                }
+            }
+            let target = match resolve_target(context, caller, Permission::Write, path).await {
+                Ok(target) => target,
+                Err(response) => return Ok(response),
+            };
+
+            let _commit = context.commit_lock.lock().await;
+            let existed = match write_precondition(&target, req.headers(), context).await {
+                Ok(current) => current.is_some(),
+                Err(response) => return Ok(response),
+            };
+            let write = match context.access.limits().admit_file(caller, &target, Some(offset)) {
+                Ok(write) => write,
+                Err(reason) => {
+                    log::warn!("upload session {} refused: {}", id, reason);
+                    return Ok(blank_response(StatusCode::INSUFFICIENT_STORAGE));
+                }
+            };
+            let stored = tokio::fs::rename(&data_path, &target).await;
+            context.access.limits().finish(write, stored.is_ok());
+            if let Err(err) = stored {
+                log::error!("could not store {:?}: {}", target, err);
+                return Ok(send_error_500());
+            }
+            if let Err(err) = sessions.remove(id).await {
+                log::error!("could not remove upload session {}: {}", id, err);
+            }
+            log::debug!("stored {:?} from upload session {}", target, id);
+            Ok(send_stored(&target, path, existed).await)
+        }
+        Method::DELETE => match sessions.remove(id).await {
+            Ok(()) => Ok(blank_response(StatusCode::NO_CONTENT)),
+            Err(err) => {
+                log::error!("could not remove upload session {}: {}", id, err);
+                Ok(send_error_500())
+            }
+        },
+        _ => Ok(send_error_405(true)),
+    };
+    drop(claim);
+    response
+}
+
+/// Response of a session request, with the committed offset and the expiry of the session
+fn send_session(builder: hyper::http::response::Builder, session: &Session, offset: u64) -> Response<FileBody> {
+    let mut builder = builder
+        .header(UPLOAD_OFFSET, offset)
+        .header(UPLOAD_EXPIRES, httpdate::fmt_http_date(session.expires_at()))
+        .header(hyper::header::CACHE_CONTROL, "no-store");
+    if let Some(length) = session.length {
+        builder = builder.header(UPLOAD_LENGTH, length);
+    }
+    match builder.body(FileBody::empty()) {
+        Ok(response) => response,
+        Err(_) => {
+            log::error!("unable to build response");
+            send_error_500()
+        }
+    }
+}
+
+/// Removes a file, directories are not removed
+async fn delete(
+    req: &Request<hyper::body::Incoming>,
+    path: &str,
+    caller: &Caller,
+    context: &FileContext,
+) -> Result<Response<FileBody>> {
+    let target = match resolve_target(context, caller, Permission::Delete, path).await {
+        Ok(target) => target,
+        Err(response) => return Ok(response),
+    };
+    let _commit = context.commit_lock.lock().await;
+    match write_precondition(&target, req.headers(), context).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Ok(send_error_404()),
+        Err(response) => return Ok(response),
+    }
+    let write = context.access.limits().admit_file(caller, &target, None).unwrap_or_default();
+    let removed = tokio::fs::remove_file(&target).await;
+    context.access.limits().finish(write, removed.is_ok());
+    match removed {
+        Ok(()) => {
+            log::debug!("removed {:?}", target);
+            Ok(blank_response(StatusCode::NO_CONTENT))
+        }
+        Err(err) => {
+            log::error!("could not remove {:?}: {}", target, err);
+            Ok(send_error_500())
+        }
+    }
+}
+
+/// Path of a file to write or remove, its directory must exist.
+/// The directory may be reached by symbolic links, the caller needs the permission for the path on disk too.
+async fn resolve_target(
+    context: &FileContext,
+    caller: &Caller,
+    permission: Permission,
+    path: &str,
+) -> std::result::Result<PathBuf, Response<FileBody>> {
+    match file_paths::resolve_new(&context.root, path).await {
+        Ok(target) if allowed_on_disk(context, caller, permission, &target) => Ok(target),
+        Ok(target) => {
+            log::warn!("access denied to {:?}: {} leads to {:?}", caller, path, target);
+            Err(send_error_403())
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+            log::warn!("refused path: {}", err);
+            Err(send_error_403())
+        }
+        Err(err) => {
+            log::warn!("no directory for {}: {}", path, err);
+            Err(blank_response(StatusCode::CONFLICT))
+        }
+    }
+}
+
+/// Whether the caller has the permission for a resolved path on disk, unfinished uploads are not reachable either
+fn allowed_on_disk(context: &FileContext, caller: &Caller, permission: Permission, file_path: &Path) -> bool {
+    file_paths::request_path(&context.root, file_path).is_some_and(|path| {
+        !upload_sessions::is_staging(&path, &context.uploads.staging_dir)
+            && context.access.acl().allows(caller, permission, Resource::Path(&path))
+    })
+}
+
+/// Evaluates the preconditions of a write against the current file, which is returned if there is one.
+/// Fails with the response to send.
+async fn write_precondition(
+    target: &Path,
+    headers: &hyper::HeaderMap,
+    context: &FileContext,
+) -> std::result::Result<Option<std::fs::Metadata>, Response<FileBody>> {
+    // a symbolic link in place of the file is checked like the files that are read
+    if let Ok(resolved) = tokio::fs::canonicalize(target).await {
+        if !resolved.starts_with(&context.root) {
+            log::warn!("refused path: {:?} leads out of the root", target);
+            return Err(send_error_403());
+        }
+    }
+    let (current, precondition) = match tokio::fs::metadata(target).await {
+        Ok(metadata) if metadata.is_dir() => return Err(blank_response(StatusCode::CONFLICT)),
+        Ok(metadata) => {
+            let precondition = Validators::new(&metadata).evaluate(&Method::PUT, headers);
+            (Some(metadata), precondition)
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (None, Validators::evaluate_absent(headers)),
+        Err(err) => {
+            log::error!("could not read {:?}: {}", target, err);
+            return Err(send_error_500());
+        }
+    };
+    match precondition {
+        Precondition::Passed => Ok(current),
+        _ => Err(send_error_412()),
+    }
+}
+
+async fn file_response(
+    req: &Request<hyper::body::Incoming>,
+    file_path: &Path,
+    context: &FileContext,
+) -> Result<Response<FileBody>> {
     match *req.method() {
-        Method::HEAD => file_info(&req).await,
-        Method::GET => file_send(&req).await,
-        _ => Ok(send_error_404()),
+        Method::HEAD => file_info(req, file_path, context).await,
+        _ => file_send(req, file_path, context).await,
+    }
+}
+
+/// Sends the index file of a directory if there is one, otherwise its listing if enabled.
+/// Directories are requested with a trailing slash, so that relative links resolve below them.
+async fn directory(
+    req: &Request<hyper::body::Incoming>,
+    path: &str,
+    dir_path: &Path,
+    caller: &Caller,
+    context: &FileContext,
+) -> Result<Response<FileBody>> {
+    if !req.uri().path().ends_with('/') {
+        // from the normalised path, `//host` would be taken for another server
+        let location = format!("{}/", file_paths::encode(path));
+        let location = match req.uri().query() {
+            Some(query) => format!("{}?{}", location, query),
+            None => location,
+        };
+        return Ok(send_redirect_301(&location));
+    }
+
+    if let Some(index_file) = context.directories.index_file.as_deref() {
+        let index_path = format!("{}/{}", path.trim_end_matches('/'), index_file);
+        if context.access.acl().allows(caller, Permission::Read, Resource::Path(&index_path)) {
+            if let Ok(file_path) = file_paths::resolve(&context.root, &index_path).await {
+                let allowed = allowed_on_disk(context, caller, Permission::Read, &file_path);
+                if allowed && tokio::fs::metadata(&file_path).await.is_ok_and(|metadata| metadata.is_file()) {
+                    return file_response(req, &file_path, context).await;
+                }
+            }
+        }
+    }
+
+    if !context.directories.listing {
+        log::debug!("directory listing disabled: {}", path);
+        return Ok(send_error_404());
+    }
+    let follow = |target: &Path| allowed_on_disk(context, caller, Permission::Read, target);
+    let mut entries = match dir_listing::read_entries(dir_path, &context.root, follow).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::error!("could not list directory {:?}: {}", dir_path, err);
+            return Ok(send_error_500());
+        }
+    };
+    // entries the caller may not read are not shown
+    let parent = path.trim_end_matches('/');
+    let acl = context.access.acl();
+    entries.retain(|entry| {
+        let entry_path = format!("{}/{}", parent, entry.name);
+        !upload_sessions::is_staging(&entry_path, &context.uploads.staging_dir)
+            && acl.allows(caller, Permission::Read, Resource::Path(&entry_path))
+    });
+    let query = Query::parse(req.uri().query());
+    dir_listing::sort(&mut entries, &query);
+    let page = dir_listing::page(&entries, query.page, context.directories.page_size);
+
+    let accept = req
+        .headers()
+        .get(hyper::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let listing_path = format!("{}/", parent);
+    let (content_type, listing) = if dir_listing::prefers_json(accept) {
+        ("application/json", dir_listing::json(&listing_path, &page))
+    } else {
+        ("text/html; charset=utf-8", dir_listing::html(&listing_path, &page, &query))
+    };
+    let content_length = listing.len();
+    let body = match *req.method() {
+        Method::HEAD => FileBody::empty(),
+        _ => FileBody::full(listing),
+    };
+    match Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, content_type)
+        .header(hyper::header::CONTENT_LENGTH, content_length)
+        .header(hyper::header::CACHE_CONTROL, "no-cache")
+        .header(hyper::header::VARY, "Accept")
+        .body(body)
+    {
+        Ok(response) => Ok(response),
+        Err(_) => {
+            log::error!("unable to build response");
+            Ok(send_error_500())
+        }
+    }
+}
+
+/// HTTP status code 301, to the location
+fn send_redirect_301(location: &str) -> Response<FileBody> {
+    let mut response = blank_response(StatusCode::MOVED_PERMANENTLY);
+    if let Ok(location) = hyper::header::HeaderValue::from_str(location) {
+        response.headers_mut().insert(hyper::header::LOCATION, location);
+    }
+    response
+}
+
+/// HTTP status code 401, with the error code of RFC 6750 if credentials were given
+fn send_error_401(invalid_token: bool) -> Response<FileBody> {
+    let mut response = blank_response(StatusCode::UNAUTHORIZED);
+    let challenge = if invalid_token {
+        "Bearer realm=\"poncu\", error=\"invalid_token\""
+    } else {
+        "Bearer realm=\"poncu\""
+    };
+    response.headers_mut().insert(
+        hyper::header::WWW_AUTHENTICATE,
+        hyper::header::HeaderValue::from_static(challenge),
+    );
+    response
+}
+
+/// HTTP status code 304, with the validators of the representation
+fn send_not_modified(representation: &Representation) -> Response<FileBody> {
+    let builder = Response::builder().status(StatusCode::NOT_MODIFIED);
+    match representation.headers(builder).body(FileBody::empty()) {
+        Ok(response) => response,
+        Err(_) => blank_response(StatusCode::NOT_MODIFIED),
     }
 }
 
 /// HTTP status code 403
-fn send_error_403() -> Response<Full<Bytes>> {
+fn send_error_403() -> Response<FileBody> {
     blank_response(StatusCode::FORBIDDEN)
 }
 
 /// HTTP status code 404
-fn send_error_404() -> Response<Full<Bytes>> {
+fn send_error_404() -> Response<FileBody> {
     blank_response(StatusCode::NOT_FOUND)
 }
 
+/// HTTP status code 429, with the seconds to wait
+fn send_error_429(retry_after: std::time::Duration) -> Response<FileBody> {
+    let mut response = blank_response(StatusCode::TOO_MANY_REQUESTS);
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    response
+        .headers_mut()
+        .insert(hyper::header::RETRY_AFTER, hyper::header::HeaderValue::from(seconds));
+    response
+}
+
+/// HTTP status code 405, with the methods allowed
+fn send_error_405(uploads: bool) -> Response<FileBody> {
+    let mut response = blank_response(StatusCode::METHOD_NOT_ALLOWED);
+    let allow = if uploads { "GET, HEAD, PUT, DELETE, POST, PATCH" } else { "GET, HEAD" };
+    response
+        .headers_mut()
+        .insert(hyper::header::ALLOW, hyper::header::HeaderValue::from_static(allow));
+    response
+}
+
+/// HTTP status code 412
+fn send_error_412() -> Response<FileBody> {
+    blank_response(StatusCode::PRECONDITION_FAILED)
+}
+
 /// HTTP status code 500
-fn send_error_500() -> Response<Full<Bytes>> {
+fn send_error_500() -> Response<FileBody> {
     blank_response(StatusCode::INTERNAL_SERVER_ERROR)
 }
 
 /// A blank response with status code
-fn blank_response(status_code: StatusCode) -> Response<Full<Bytes>> {
-    let mut response = Response::<Full<Bytes>>::new(Full::new(Bytes::new()));
+fn blank_response(status_code: StatusCode) -> Response<FileBody> {
+    let mut response = Response::new(FileBody::empty());
     *response.status_mut() = status_code;
     response
 }
 
-async fn file_info(req: &Request<hyper::body::Incoming>) -> Result<Response<Full<Bytes>>> {
-    let path = req.uri().path().replace('/', "");
-    let file_path = Path::new(&path);
+async fn file_info(
+    req: &Request<hyper::body::Incoming>,
+    file_path: &Path,
+    context: &FileContext,
+) -> Result<Response<FileBody>> {
     if log::log_enabled!(log::Level::Debug) {
         log::debug!("file path:{:?}", file_path);
     }
 
-    if file_path.file_name().is_none() {
-        log::error!("filename is empty");
-        return Ok(send_error_403());
-    }
-
-    match get_file_len(file_path).await {
-        Ok(file_len) => {
-            if let Ok(response) = Response::builder()
+    match get_file_metadata(file_path).await {
+        Ok(metadata) => {
+            let content_type = context.mime_types.content_type(file_path).await;
+            let representation =
+                Representation::choose(req, file_path, &metadata, &content_type, false, context).await;
+            match representation.validators.evaluate(req.method(), req.headers()) {
+                Precondition::Passed => {}
+                Precondition::NotModified => return Ok(send_not_modified(&representation)),
+                Precondition::Failed => return Ok(send_error_412()),
+            }
+            let mut builder = Response::builder()
                 .status(StatusCode::OK)
                 .header(hyper::header::ACCEPT_RANGES, http_range::RANGE_UNIT)
-                .header(hyper::header::CONTENT_LENGTH, file_len)
-                .body(Full::new(Bytes::new()))
-            {
+                .header(hyper::header::CONTENT_TYPE, content_type);
+            if let Some(length) = representation.length {
+                builder = builder.header(hyper::header::CONTENT_LENGTH, length);
+            }
+            if let Ok(response) = representation.headers(builder).body(FileBody::empty()) {
                 if log::log_enabled!(log::Level::Trace) {
                     log::trace!("response:{:#?}", response);
                 }
@@ -144,90 +1110,126 @@ async fn file_info(req: &Request<hyper::body::Incoming>) -> Result<Response<Full
     }
 }
 
-async fn get_file_len(filename: &Path) -> FileServerResult<u64> {
+async fn get_file_metadata(filename: &Path) -> FileServerResult<std::fs::Metadata> {
     let file = tokio::fs::File::open(filename).await?;
     let metadata = file.metadata().await?;
     if metadata.is_file() {
-        let file_len = metadata.len();
         if log::log_enabled!(log::Level::Trace) {
             log::trace!(
                 "The length of the file {:?} is {} bytes",
                 filename,
-                file_len
+                metadata.len()
             )
         }
-        return Ok(file_len);
+        return Ok(metadata);
     }
     let err_msg = format!("Not a file: {:?}", filename);
     log::error!("{err_msg}");
     Err(err_msg.into())
 }
 
-async fn file_send(req: &Request<hyper::body::Incoming>) -> Result<Response<Full<Bytes>>> {
-    let content_type: &str = "text/html; charset=utf-8";
-
-    let path = req.uri().path().replace('/', "");
-    let file_path = Path::new(&path);
+async fn file_send(
+    req: &Request<hyper::body::Incoming>,
+    file_path: &Path,
+    context: &FileContext,
+) -> Result<Response<FileBody>> {
     if log::log_enabled!(log::Level::Debug) {
         log::debug!("file path: {:?}", file_path);
     }
 
-    if file_path.file_name().is_none() {
-        log::error!("filename is empty");
-        return Ok(send_error_403());
+    let metadata = match get_file_metadata(file_path).await {
+        Ok(metadata) => metadata,
+        Err(_err) => {
+            log::error!("file not found: {:?}", file_path);
+            return Ok(send_error_404());
+        }
+    };
+    let content_length = metadata.len();
+    let content_type = context.mime_types.content_type(file_path).await;
+    let content_type = content_type.as_str();
+    let headers = req.headers();
+    let ranged = headers.contains_key(hyper::header::RANGE)
+        || (context.content_range_requests && headers.contains_key(hyper::header::CONTENT_RANGE));
+    let representation = Representation::choose(req, file_path, &metadata, content_type, ranged, context).await;
+    let validators = &representation.validators;
+    match validators.evaluate(req.method(), headers) {
+        Precondition::Passed => {}
+        Precondition::NotModified => return Ok(send_not_modified(&representation)),
+        Precondition::Failed => return Ok(send_error_412()),
     }
 
-    let content_length: u64;
-    if let Ok(file_len) = get_file_len(file_path).await {
-        content_length = file_len;
-    } else {
-        log::error!("file not found: {:?}", file_path);
-        return Ok(send_error_404());
+    // a changed file is sent in full, instead of ranges of the version known to the client
+    let http_range_option = match headers.get(hyper::header::RANGE) {
+        Some(range) => range
+            .to_str()
+            .ok()
+            .and_then(|range| byte_ranges::parse(range, content_length)),
+        None if context.content_range_requests => headers
+            .get(hyper::header::CONTENT_RANGE)
+            .and_then(|content_range| content_range.to_str().ok())
+            .and_then(|content_range| HttpRange::from_header(content_range, content_length)),
+        None => None,
     }
-
-    let headers = req.headers();
-    let http_range_option = if headers.contains_key(hyper::header::CONTENT_RANGE) {
-        let content_range = headers.get(hyper::header::CONTENT_RANGE).unwrap();
-        HttpRange::from_header(content_range.to_str().unwrap(), content_length)
-    } else {
-        None
-    };
+    .filter(|_| validators.if_range(headers));
 
     match http_range_option {
         // send a response in ranges
         Some(http_range) => {
-            send_file_range(file_path, content_type, content_length, &http_range).await
+            send_file_range(
+                file_path,
+                content_type,
+                content_length,
+                &representation,
+                &http_range,
+                context.coalesce_ranges,
+            )
+            .await
         }
 
         // send a response with full content
-        None => send_file_full(file_path, content_type).await,
+        None => send_file_full(&representation, content_type).await,
     }
 }
 
-async fn send_file_full(filename: &Path, content_type: &str) -> Result<Response<Full<Bytes>>> {
-    if let Ok(contents) = tokio::fs::read(&filename).await {
-        let body = contents.into();
-        if let Ok(response) = Response::builder()
-            .status(StatusCode::OK)
-            .header(hyper::header::ACCEPT_RANGES, http_range::RANGE_UNIT)
-            .header(hyper::header::CONTENT_TYPE, content_type)
-            .body(Full::new(body))
-        {
-            return Ok(response);
-        } else {
+async fn send_file_full(representation: &Representation, content_type: &str) -> Result<Response<FileBody>> {
+    let file = match tokio::fs::File::open(&representation.path).await {
+        Ok(file) => file,
+        Err(err) => {
+            log::error!("could not open file {:?}: {}", representation.path, err);
+            return Ok(send_error_404());
+        }
+    };
+    let body = match (representation.length, representation.encoding) {
+        (None, Some(encoding)) => FileBody::compressed(file, encoding).await,
+        (length, _) => {
+            let segments = match length.unwrap_or(0) {
+                0 => vec![],
+                length => vec![Segment::Range(0..length - 1)],
+            };
+            FileBody::stream(file, segments)
+        }
+    };
+    let builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::ACCEPT_RANGES, http_range::RANGE_UNIT)
+        .header(hyper::header::CONTENT_TYPE, content_type);
+    match representation.headers(builder).body(body) {
+        Ok(response) => Ok(response),
+        Err(_) => {
             log::error!("unable to build response");
-            return Ok(send_error_500());
+            Ok(send_error_500())
         }
     }
-    Ok(send_error_404())
 }
 
 async fn send_file_range(
     filename: &Path,
     content_type: &str,
     content_length: u64,
+    representation: &Representation,
     http_range: &HttpRange,
-) -> Result<Response<Full<Bytes>>> {
+    coalesce: bool,
+) -> Result<Response<FileBody>> {
     if http_range.none_satisfiable(content_length) {
         if let Ok(response) = Response::builder()
             .status(StatusCode::RANGE_NOT_SATISFIABLE)
@@ -236,7 +1238,7 @@ async fn send_file_range(
                 hyper::header::CONTENT_RANGE,
                 format!("{} */{}", http_range::RANGE_UNIT, content_length),
             )
-            .body(Full::new(Bytes::new()))
+            .body(FileBody::empty())
         {
             if log::log_enabled!(log::Level::Debug) {
                 log::debug!("Range Not Satisfiable (416). Requested range is out of existing content, {:?} > {}", http_range, content_length);
@@ -248,58 +1250,251 @@ async fn send_file_range(
         }
     }
 
-    let ranges = &http_range.ranges;
+    let mut ranges: Vec<_> = http_range
+        .ranges
+        .iter()
+        .filter(|range| HttpRange::range_satisfiable(range, content_length))
+        .cloned()
+        .collect();
+    // overlapping ranges would send the same bytes again and again
+    if coalesce || byte_ranges::overlap(&ranges) {
+        ranges = byte_ranges::coalesce(&ranges);
+    }
+    if log::log_enabled!(log::Level::Trace) {
+        log::trace!("preparing the ranges to send {:?}", ranges);
+    }
+
+    let file = match tokio::fs::File::open(&filename).await {
+        Ok(file) => file,
+        Err(err) => {
+            log::error!("could not open file {:?}: {}", filename, err);
+            return Ok(send_error_404());
+        }
+    };
+
+    if let [range] = ranges.as_slice() {
+        let builder = Response::builder()
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(hyper::header::ACCEPT_RANGES, http_range::RANGE_UNIT)
+            .header(
+                hyper::header::CONTENT_RANGE,
+                byte_ranges::content_range(range, content_length),
+            )
+            .header(hyper::header::CONTENT_TYPE, content_type);
+        return match representation
+            .headers(builder)
+            .body(FileBody::stream(file, vec![Segment::Range(range.clone())]))
+        {
+            Ok(response) => Ok(response),
+            Err(_) => {
+                log::error!("unable to build response");
+                Ok(send_error_500())
+            }
+        };
+    }
+
+    // each part carries its own headers
+    let boundary = byte_ranges::boundary();
+    let mut segments = Vec::with_capacity(ranges.len() * 2 + 1);
     for range in ranges {
-        let capacity = (range.end - range.start + 1) as usize;
-        if log::log_enabled!(log::Level::Trace) {
-            log::trace!("preparing the range to send {:?}", range);
-            log::trace!("capacity {}", capacity);
+        let part_header = byte_ranges::part_header(&boundary, content_type, &range, content_length);
+        segments.push(Segment::Bytes(part_header.into()));
+        segments.push(Segment::Range(range));
+    }
+    segments.push(Segment::Bytes(byte_ranges::closing_boundary(&boundary).into()));
+
+    let builder = Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(hyper::header::ACCEPT_RANGES, http_range::RANGE_UNIT)
+        .header(
+            hyper::header::CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={}", boundary),
+        );
+    match representation.headers(builder).body(FileBody::stream(file, segments)) {
+        Ok(response) => Ok(response),
+        Err(_) => {
+            log::error!("unable to build response");
+            Ok(send_error_500())
         }
+    }
+}
 
-        if HttpRange::range_satisfiable(range, content_length) {
-            let mut buffer = vec![0; capacity];
-            if let Ok(mut file) = tokio::fs::File::open(&filename).await {
-                if let Ok(_seek) = file.seek(SeekFrom::Start(range.start)).await {
-                    if log::log_enabled!(log::Level::Trace) {
-                        log::trace!("seek result {}", _seek);
-                    }
-                    if let Ok(read_count) = file.read_exact(&mut buffer).await {
-                        if log::log_enabled!(log::Level::Trace) {
-                            log::trace!("read_count {}", read_count);
-                        }
-                        let body: Bytes = buffer.into();
-                        if let Ok(response) = Response::builder()
-                            .status(StatusCode::PARTIAL_CONTENT)
-                            .header(hyper::header::ACCEPT_RANGES, http_range::RANGE_UNIT)
-                            .header(
-                                hyper::header::CONTENT_RANGE,
-                                format!(
-                                    "{} {}-{}/{}",
-                                    http_range::RANGE_UNIT,
-                                    range.start,
-                                    range.end,
-                                    content_length
-                                ),
-                            )
-                            .header(hyper::header::CONTENT_TYPE, content_type)
-                            .body(Full::new(body))
-                        {
-                            return Ok(response);
-                        } else {
-                            log::error!("unable to build response");
-                            return Ok(send_error_500());
-                        }
-                    } else {
-                        log::error!("could not read bytes from file");
-                    }
-                } else {
-                    log::error!("could not seek file position: {}", range.start);
-                }
-            } else {
-                log::error!("could not open file: {:?}", filename);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use hyper::header;
+    use hyper::http::request::Builder;
+
+    /// File server on a temporary root with uploads and bearer tokens, talked to over an in-memory connection
+    struct TestServer {
+        dir: PathBuf,
+        context: Arc<FileContext>,
+        /// bearer token of the client app1
+        token: String,
+    }
+
+    impl TestServer {
+        fn new(name: &str) -> Self {
+            #[derive(serde::Serialize)]
+            struct TestClaims<'a> {
+                sub: &'a str,
+                iss: &'a str,
+                aud: &'a str,
+                exp: u64,
             }
+
+            let dir = std::env::temp_dir().join(format!("poncu-file-server-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(dir.join("root/docs")).unwrap();
+            let key_file = dir.join("jwt.key");
+            std::fs::write(&key_file, "jwt-secret\n").unwrap();
+            let source = format!(
+                "file_server:\n  root: {}\n  uploads:\n    enabled: true\n    sessions_max: 2\nlimits:\n  client_bytes_max: 16\nauth:\n  jwt:\n    algorithm: HS256\n    key_file: {}\n    issuer: idp\n    audience: poncu\n",
+                dir.join("root").display(),
+                key_file.display()
+            );
+            let config = config::parse_config(&source, Path::new("x"), &[]).unwrap();
+            let access = Arc::new(Access::with_config(&config).unwrap());
+            let context = FileContext::with_config(config.file_server.as_ref().unwrap(), access).unwrap();
+
+            let claims = TestClaims {
+                sub: "app1",
+                iss: "idp",
+                aud: "poncu",
+                exp: jsonwebtoken::get_current_timestamp() + 600,
+            };
+            let key = jsonwebtoken::EncodingKey::from_secret(b"jwt-secret");
+            let token = jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap();
+            TestServer {
+                dir,
+                context: Arc::new(context),
+                token,
+            }
+        }
+
+        fn root(&self) -> &Path {
+            &self.context.root
+        }
+
+        fn authorized(&self, method: Method, uri: &str) -> Builder {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {}", self.token))
+        }
+
+        async fn send(&self, request: Builder, body: &'static [u8]) -> Response<Bytes> {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            serve_connection(server, Some(IpAddr::from([127, 0, 0, 1])), self.context.clone());
+            let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(client)).await.unwrap();
+            tokio::task::spawn(connection);
+            let request = request.header(header::HOST, "localhost").body(Full::new(Bytes::from_static(body))).unwrap();
+            let (parts, body) = sender.send_request(request).await.unwrap().into_parts();
+            Response::from_parts(parts, body.collect().await.unwrap().to_bytes())
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
         }
     }
 
-    Ok(send_error_404())
+    #[tokio::test]
+    async fn uploads() {
+        let server = TestServer::new("uploads");
+
+        let response = server.send(server.authorized(Method::OPTIONS, "/docs/a.txt"), b"").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD, PUT, DELETE, POST, PATCH");
+        let anonymous = Request::builder().method(Method::PUT).uri("/docs/a.txt");
+        assert_eq!(server.send(anonymous, b"a").await.status(), StatusCode::UNAUTHORIZED);
+
+        let response = server.send(server.authorized(Method::PUT, "//docs/a.txt"), b"first").await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[header::LOCATION], "/docs/a.txt");
+        // the file may be replaced by its entity tag right away
+        let etag = response.headers()[header::ETAG].to_str().unwrap().to_string();
+        let replace = server.authorized(Method::PUT, "/docs/a.txt").header(header::IF_MATCH, &etag);
+        assert_eq!(server.send(replace, b"second").await.status(), StatusCode::NO_CONTENT);
+        let stale = server.authorized(Method::PUT, "/docs/a.txt").header(header::IF_MATCH, &etag);
+        assert_eq!(server.send(stale, b"third").await.status(), StatusCode::PRECONDITION_FAILED);
+        let create = server.authorized(Method::PUT, "/docs/a.txt").header(header::IF_NONE_MATCH, "*");
+        assert_eq!(server.send(create, b"third").await.status(), StatusCode::PRECONDITION_FAILED);
+        let missing = server.authorized(Method::PUT, "/docs/b.txt").header(header::IF_MATCH, "*");
+        assert_eq!(server.send(missing, b"third").await.status(), StatusCode::PRECONDITION_FAILED);
+
+        for uri in ["/docs/sub/b.txt", "/docs", "/docs/b.txt/"] {
+            let response = server.send(server.authorized(Method::PUT, uri), b"b").await;
+            assert_eq!(response.status(), StatusCode::CONFLICT, "{}", uri);
+        }
+        // the bodies were staged outside the served directories
+        let docs: Vec<_> = std::fs::read_dir(server.root().join("docs")).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(docs, ["a.txt"]);
+        assert_eq!(std::fs::read(server.root().join("docs/a.txt")).unwrap(), b"second");
+        // files count against the storage quota of the client
+        let full = server.authorized(Method::PUT, "/docs/b.txt");
+        assert_eq!(server.send(full, b"0123456789a").await.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert!(!server.root().join("docs/b.txt").exists());
+
+        let response = server.send(server.authorized(Method::GET, "/docs/a.txt"), b"").await;
+        assert_eq!(response.body().as_ref(), b"second");
+        assert_eq!(server.send(server.authorized(Method::DELETE, "/docs/a.txt"), b"").await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(server.send(server.authorized(Method::DELETE, "/docs/a.txt"), b"").await.status(), StatusCode::NOT_FOUND);
+        // until they are removed
+        let full = server.authorized(Method::PUT, "/docs/b.txt");
+        assert_eq!(server.send(full, b"0123456789a").await.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn resumable_uploads() {
+        let server = TestServer::new("sessions");
+        let offset = |response: &Response<Bytes>| response.headers()[UPLOAD_OFFSET].to_str().unwrap().to_string();
+
+        let create = server.authorized(Method::POST, "/docs/big.bin").header(UPLOAD_LENGTH, "10");
+        let response = server.send(create, b"").await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let location = response.headers()[header::LOCATION].to_str().unwrap().to_string();
+        assert!(location.starts_with("/docs/big.bin?upload="), "{}", location);
+        // other callers do not see the session
+        let anonymous = Request::builder().method(Method::HEAD).uri(&location);
+        assert_eq!(server.send(anonymous, b"").await.status(), StatusCode::UNAUTHORIZED);
+
+        // chunks are taken at the committed offset only
+        let chunk = server.authorized(Method::PATCH, &location).header(UPLOAD_OFFSET, "5");
+        let response = server.send(chunk, b"56789").await;
+        assert_eq!((response.status(), offset(&response)), (StatusCode::CONFLICT, "0".to_string()));
+        let chunk = server.authorized(Method::PATCH, &location).header(UPLOAD_OFFSET, "0");
+        let response = server.send(chunk, b"01234").await;
+        assert_eq!((response.status(), offset(&response)), (StatusCode::NO_CONTENT, "5".to_string()));
+        let response = server.send(server.authorized(Method::HEAD, &location), b"").await;
+        assert_eq!((response.status(), offset(&response)), (StatusCode::OK, "5".to_string()));
+
+        // finalised once complete and matching its checksum
+        let response = server.send(server.authorized(Method::PUT, &location), b"").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let chunk = server.authorized(Method::PATCH, &location).header(UPLOAD_OFFSET, "5");
+        assert_eq!(server.send(chunk, b"56789").await.status(), StatusCode::NO_CONTENT);
+        let mismatch = server.authorized(Method::PUT, &location).header("content-md5", "XUFAKrxLKna5cZ2REBfFkg==");
+        assert_eq!(server.send(mismatch, b"").await.status(), StatusCode::BAD_REQUEST);
+        let finish = server
+            .authorized(Method::PUT, &location)
+            .header("content-md5", "eB5eJF1ptWaXm4bijSPyxw==")
+            .header("content-digest", "sha-256=:hNiYd/DUBB77a/kaFvAkjy/Vc+avBcGflr7bn4gveII=:");
+        assert_eq!(server.send(finish, b"").await.status(), StatusCode::CREATED);
+        assert_eq!(std::fs::read(server.root().join("docs/big.bin")).unwrap(), b"0123456789");
+        assert_eq!(server.send(server.authorized(Method::HEAD, &location), b"").await.status(), StatusCode::NOT_FOUND);
+
+        // a client has a limited number of sessions at a time
+        for _ in 0..2 {
+            let response = server.send(server.authorized(Method::POST, "/docs/c.bin"), b"").await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+        let response = server.send(server.authorized(Method::POST, "/docs/c.bin"), b"").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // the staged data is not served, in any case
+        let staged = server.send(server.authorized(Method::GET, "/.UPLOADS/"), b"").await;
+        assert_eq!(staged.status(), StatusCode::NOT_FOUND);
+    }
 }
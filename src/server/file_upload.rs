@@ -0,0 +1,296 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use bytes::Buf;
+use http_body_util::BodyExt;
+use hyper::body::Body;
+use hyper::header::HeaderMap;
+use md5::Md5;
+use sha2::{Digest, Sha256, Sha512};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Digest algorithms of `Content-MD5` and `Content-Digest`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Sha256,
+    Sha512,
+}
+
+/// Digest of the body announced by the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expected {
+    pub algorithm: Algorithm,
+    pub digest: Vec<u8>,
+}
+
+/// Digests of `Content-MD5` (RFC 1864) and `Content-Digest` (RFC 9530), other algorithms are ignored.
+/// Fails on malformed values.
+pub fn expected_digests(headers: &HeaderMap) -> Result<Vec<Expected>, String> {
+    let base64 = base64::engine::general_purpose::STANDARD;
+    let mut expected = Vec::new();
+    if let Some(value) = headers.get("content-md5") {
+        let digest = value
+            .to_str()
+            .ok()
+            .and_then(|value| base64.decode(value.trim()).ok())
+            .filter(|digest| digest.len() == 16)
+            .ok_or("invalid Content-MD5")?;
+        expected.push(Expected {
+            algorithm: Algorithm::Md5,
+            digest,
+        });
+    }
+    for value in headers.get_all("content-digest") {
+        let value = value.to_str().map_err(|_| "invalid Content-Digest")?;
+        for member in value.split(',').map(str::trim).filter(|member| !member.is_empty()) {
+            let (key, digest) = member.split_once('=').ok_or("invalid Content-Digest")?;
+            let algorithm = match key.trim() {
+                "sha-256" => Algorithm::Sha256,
+                "sha-512" => Algorithm::Sha512,
+                _ => continue,
+            };
+            let digest = digest
+                .trim()
+                .strip_prefix(':')
+                .and_then(|digest| digest.strip_suffix(':'))
+                .and_then(|digest| base64.decode(digest).ok())
+                .ok_or("invalid Content-Digest")?;
+            expected.push(Expected { algorithm, digest });
+        }
+    }
+    Ok(expected)
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    /// the body is larger than allowed
+    TooLarge,
+    DigestMismatch(Algorithm),
+    /// the body could not be received
+    Body(String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for UploadError {
+    fn from(err: io::Error) -> Self {
+        UploadError::Io(err)
+    }
+}
+
+/// Received body in a temporary file in the staging directory, removed unless it is persisted
+#[derive(Debug)]
+pub struct TempUpload {
+    path: PathBuf,
+    pub size: u64,
+}
+
+impl TempUpload {
+    /// Renames the file over the target, readers see either the former file or the new one
+    pub async fn persist(mut self, target: &Path) -> io::Result<()> {
+        tokio::fs::rename(&self.path, target).await?;
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Streams a body to a temporary file in the staging directory, checking its size and digests on the way.
+/// The file is synced to disk before it is handed out.
+pub async fn receive<B>(
+    mut body: B,
+    staging: &Path,
+    max_size: u64,
+    expected: &[Expected],
+) -> Result<TempUpload, UploadError>
+where
+    B: Body + Unpin,
+    B::Error: std::fmt::Display,
+{
+    let mut upload = TempUpload {
+        path: temp_path(staging),
+        size: 0,
+    };
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&upload.path)
+        .await?;
+    let mut hashers: Vec<Hasher> = expected.iter().map(|expected| Hasher::new(expected.algorithm)).collect();
+    write_body(&mut body, &mut file, &mut upload.size, max_size, &mut hashers).await?;
+    check_digests(hashers, expected)?;
+    file.sync_all().await?;
+    Ok(upload)
+}
+
+/// Appends a body to a file holding `written` bytes, which counts the bytes appended.
+/// What was received stays in the file even if the body fails, it is synced to disk in any case.
+pub async fn append<B>(mut body: B, file: &mut File, written: &mut u64, max_size: u64) -> Result<(), UploadError>
+where
+    B: Body + Unpin,
+    B::Error: std::fmt::Display,
+{
+    let result = write_body(&mut body, file, written, max_size, &mut []).await;
+    file.sync_all().await?;
+    result
+}
+
+/// Checks the digests of a file assembled from several bodies
+pub async fn verify_file(path: &Path, expected: &[Expected]) -> Result<(), UploadError> {
+    let mut file = File::open(path).await?;
+    let mut hashers: Vec<Hasher> = expected.iter().map(|expected| Hasher::new(expected.algorithm)).collect();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hashers.iter_mut().for_each(|hasher| hasher.update(&buffer[..read]));
+    }
+    check_digests(hashers, expected)
+}
+
+/// The size is checked before a frame is written, so the file never exceeds it
+async fn write_body<B>(
+    body: &mut B,
+    file: &mut File,
+    written: &mut u64,
+    max_size: u64,
+    hashers: &mut [Hasher],
+) -> Result<(), UploadError>
+where
+    B: Body + Unpin,
+    B::Error: std::fmt::Display,
+{
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|err| UploadError::Body(err.to_string()))?;
+        let Ok(mut data) = frame.into_data() else {
+            continue;
+        };
+        if *written + data.remaining() as u64 > max_size {
+            return Err(UploadError::TooLarge);
+        }
+        while data.has_remaining() {
+            let chunk = data.chunk();
+            hashers.iter_mut().for_each(|hasher| hasher.update(chunk));
+            file.write_all(chunk).await?;
+            let len = chunk.len();
+            *written += len as u64;
+            data.advance(len);
+        }
+    }
+    Ok(())
+}
+
+fn check_digests(hashers: Vec<Hasher>, expected: &[Expected]) -> Result<(), UploadError> {
+    for (hasher, expected) in hashers.into_iter().zip(expected) {
+        if hasher.finalize() != expected.digest {
+            return Err(UploadError::DigestMismatch(expected.algorithm));
+        }
+    }
+    Ok(())
+}
+
+/// Unique name in the staging directory below the root: not served, and the rename stays on one file system
+fn temp_path(staging: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    staging.join(format!(
+        "{:x}{:04x}.upload",
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed) & 0xffff
+    ))
+}
+
+enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Md5 => Hasher::Md5(Md5::new()),
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Md5(hasher) => hasher.update(bytes),
+            Hasher::Sha256(hasher) => hasher.update(bytes),
+            Hasher::Sha512(hasher) => hasher.update(bytes),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::header::HeaderValue;
+
+    #[tokio::test]
+    async fn received_uploads() {
+        let mut headers = HeaderMap::new();
+        // MD5 and SHA-256 of "hello"
+        headers.insert("content-md5", HeaderValue::from_static("XUFAKrxLKna5cZ2REBfFkg=="));
+        headers.insert(
+            "content-digest",
+            HeaderValue::from_static("unixsum=:AA==:, sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:"),
+        );
+        let expected = expected_digests(&headers).unwrap();
+        assert_eq!(
+            expected.iter().map(|expected| expected.algorithm).collect::<Vec<_>>(),
+            [Algorithm::Md5, Algorithm::Sha256]
+        );
+        headers.insert("content-md5", HeaderValue::from_static("hello"));
+        assert!(expected_digests(&headers).is_err());
+
+        let dir = std::env::temp_dir().join(format!("poncu-upload-{}", std::process::id()));
+        let staging = dir.join(".uploads");
+        std::fs::create_dir_all(&staging).unwrap();
+        let target = dir.join("a.txt");
+        let files = || std::fs::read_dir(&dir).unwrap().count() + std::fs::read_dir(&staging).unwrap().count();
+
+        let body = Full::new(Bytes::from_static(b"hello"));
+        let upload = receive(body, &staging, 5, &expected).await.unwrap();
+        assert_eq!(upload.size, 5);
+        upload.persist(&target).await.unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"hello");
+
+        let body = Full::new(Bytes::from_static(b"hallo"));
+        let mismatch = receive(body, &staging, 5, &expected).await;
+        assert!(matches!(mismatch, Err(UploadError::DigestMismatch(Algorithm::Md5))));
+        let body = Full::new(Bytes::from_static(b"hello!"));
+        assert!(matches!(receive(body, &staging, 5, &[]).await, Err(UploadError::TooLarge)));
+        // temporary files are removed, the target and the staging directory are kept
+        assert_eq!(files(), 2);
+        let dropped = receive(Full::new(Bytes::from_static(b"x")), &staging, 5, &[]).await.unwrap();
+        drop(dropped);
+        assert_eq!((files(), std::fs::read(&target).unwrap()), (2, b"hello".to_vec()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
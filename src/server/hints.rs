@@ -0,0 +1,455 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::frame::{read_message, write_message};
+use crate::protocol::message::{HintMetrics, Request, Response, VersionedValue};
+use crate::server::membership::Membership;
+use crate::server::reload::LiveConfig;
+use crate::server::replication::{peer_request, Coordinator};
+use crate::utils::config;
+
+/// A write kept for a replica that was unreachable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Hint {
+    /// seconds since the UNIX epoch
+    created: u64,
+    key: String,
+    item: VersionedValue,
+}
+
+/// Hints stored on the local disk, one file per unreachable node
+pub struct HintedHandoff {
+    directory: PathBuf,
+    /// bytes, reloadable
+    size_max: AtomicU64,
+    /// seconds, reloadable
+    age_max: AtomicU64,
+    metrics: Mutex<HintMetrics>,
+    /// hint files that may hold hints, locked after the metrics
+    waiting: Mutex<HashSet<PathBuf>>,
+    /// nodes with hints that were seen alive, replayed before the next round
+    alive: Mutex<HashSet<SocketAddr>>,
+    alive_changed: Condvar,
+}
+
+impl HintedHandoff {
+    /// Opens the hints directory and accounts the hints left from a previous run
+    pub fn open(settings: &config::HintedHandoff) -> io::Result<Self> {
+        fs::create_dir_all(&settings.directory)?;
+
+        let mut metrics = HintMetrics::default();
+        let mut waiting = HashSet::new();
+        for entry in fs::read_dir(&settings.directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            let extension = path.extension().and_then(|extension| extension.to_str());
+            if !matches!(extension, Some("hints" | "replay")) || !entry.file_type()?.is_file() {
+                log::warn!("ignored {:?} in the hints directory, not a hint file", path);
+                continue;
+            }
+            for hint in read_hints(&path)? {
+                metrics.pending += 1;
+                metrics.pending_bytes += frame_len(&hint);
+            }
+            // a replay file left after a crash belongs to the hints of the node
+            waiting.insert(path.with_extension("hints"));
+        }
+
+        if metrics.pending > 0 {
+            log::info!(
+                "found {} pending hints ({} bytes) in {:?}",
+                metrics.pending,
+                metrics.pending_bytes,
+                settings.directory
+            );
+        }
+
+        Ok(HintedHandoff {
+            directory: settings.directory.clone(),
+            size_max: AtomicU64::new(settings.size_max.bytes()),
+            age_max: AtomicU64::new(settings.age_max.as_secs()),
+            metrics: Mutex::new(metrics),
+            waiting: Mutex::new(waiting),
+            alive: Mutex::new(HashSet::new()),
+            alive_changed: Condvar::new(),
+        })
+    }
+
+    /// Applies reloaded limits, the age limit covers the hints already stored as well
+    pub fn set_limits(&self, settings: &config::HintedHandoff) {
+        self.size_max.store(settings.size_max.bytes(), Ordering::SeqCst);
+        self.age_max.store(settings.age_max.as_secs(), Ordering::SeqCst);
+    }
+
+    pub fn metrics(&self) -> HintMetrics {
+        *self.metrics.lock().unwrap()
+    }
+
+    /// Keeps the write for the node until it is reachable again.
+    /// Returns `false` if the hint was dropped.
+    pub fn store(&self, node: SocketAddr, key: &str, item: &VersionedValue) -> bool {
+        let hint = Hint {
+            created: unix_time(),
+            key: key.to_string(),
+            item: item.clone(),
+        };
+        let len = frame_len(&hint);
+
+        let mut metrics = self.metrics.lock().unwrap();
+        if metrics.pending_bytes + len > self.size_max.load(Ordering::SeqCst) {
+            metrics.dropped += 1;
+            log::warn!("hints limit reached, dropped the hint of {} for {}", key, node);
+            return false;
+        }
+
+        if let Err(err) = append_hints(&self.hint_path(node), &[hint]) {
+            metrics.dropped += 1;
+            log::error!("could not store the hint of {} for {}: {:?}", key, node, err);
+            return false;
+        }
+
+        metrics.pending += 1;
+        metrics.pending_bytes += len;
+        self.waiting.lock().unwrap().insert(self.hint_path(node));
+        true
+    }
+
+    /// Notes that the node is reachable, its hints are replayed as soon as possible.
+    /// Nodes without hints are ignored.
+    pub fn node_alive(&self, node: SocketAddr) {
+        if !self.waiting.lock().unwrap().contains(&self.hint_path(node)) {
+            return;
+        }
+        if self.alive.lock().unwrap().insert(node) {
+            self.alive_changed.notify_all();
+        }
+    }
+
+    /// Waits up to the timeout for nodes seen alive, which are taken
+    fn wait_alive(&self, timeout: Duration) -> Vec<SocketAddr> {
+        let alive = self.alive.lock().unwrap();
+        let (mut alive, _) = self
+            .alive_changed
+            .wait_timeout_while(alive, timeout, |alive| alive.is_empty())
+            .unwrap();
+        alive.drain().collect()
+    }
+
+    /// Delivers the hints of the node, stops at the first failure and keeps the rest
+    pub fn replay(&self, membership: &Membership, node: SocketAddr) -> io::Result<()> {
+        let path = self.hint_path(node);
+        let replay_path = path.with_extension("replay");
+        {
+            let _metrics = self.metrics.lock().unwrap();
+            // a replay file left after a crash is replayed first
+            if !replay_path.exists() {
+                if !path.exists() {
+                    return Ok(());
+                }
+                fs::rename(&path, &replay_path)?;
+            }
+        }
+
+        let hints = read_hints(&replay_path)?;
+        let age_max = self.age_max.load(Ordering::SeqCst);
+        let now = unix_time();
+
+        let mut remaining = Vec::new();
+        let mut failed = false;
+        for hint in hints {
+            let len = frame_len(&hint);
+            if now.saturating_sub(hint.created) > age_max {
+                let mut metrics = self.metrics.lock().unwrap();
+                metrics.pending -= 1;
+                metrics.pending_bytes -= len;
+                metrics.dropped += 1;
+                continue;
+            }
+
+            if failed {
+                remaining.push(hint);
+                continue;
+            }
+
+            let request = Request::ReplicaSet {
+                key: hint.key.clone(),
+                item: hint.item.clone(),
+            };
+            match peer_request(membership, node, &request) {
+                Ok(Response::Done) => {
+                    let mut metrics = self.metrics.lock().unwrap();
+                    metrics.pending -= 1;
+                    metrics.pending_bytes -= len;
+                    metrics.replayed += 1;
+                }
+                other => {
+                    log::debug!("could not replay hints for {}: {:?}", node, other);
+                    failed = true;
+                    remaining.push(hint);
+                }
+            }
+        }
+
+        let _metrics = self.metrics.lock().unwrap();
+        if !remaining.is_empty() {
+            append_hints(&path, &remaining)?;
+        }
+        fs::remove_file(&replay_path)?;
+        // hints stored meanwhile are in a new hint file
+        if !path.exists() {
+            self.waiting.lock().unwrap().remove(&path);
+        }
+        Ok(())
+    }
+
+    fn hint_path(&self, node: SocketAddr) -> PathBuf {
+        let name = node
+            .to_string()
+            .replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+        self.directory.join(name).with_extension("hints")
+    }
+}
+
+/// Replays the hints of a node as soon as it is seen alive: it connects or acknowledges a write.
+/// The hints of all peers are retried periodically, starting with the hints left from a previous run.
+/// Until the claster heartbeat is in place, a node that stays unreachable is detected by a failed replay.
+pub fn start_hints_replay(
+    coordinator: Arc<Coordinator>,
+    hints: Arc<HintedHandoff>,
+    live: Arc<LiveConfig>,
+    flag_shutdown: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut round_start: Option<Instant> = None;
+        while !flag_shutdown.load(Ordering::SeqCst) {
+            let replay_interval = live
+                .get()
+                .hinted_handoff
+                .clone()
+                .unwrap_or_default()
+                .replay_interval;
+            if round_start.is_some_and(|start| start.elapsed() < replay_interval) {
+                for node in hints.wait_alive(Duration::from_millis(200)) {
+                    if let Err(err) = hints.replay(coordinator.membership(), node) {
+                        log::error!("could not replay hints for {}: {:?}", node, err);
+                    }
+                }
+                continue;
+            }
+            round_start = Some(Instant::now());
+
+            if hints.metrics().pending == 0 {
+                continue;
+            }
+
+            for peer in coordinator.peers() {
+                if let Err(err) = hints.replay(coordinator.membership(), peer) {
+                    log::error!("could not replay hints for {}: {:?}", peer, err);
+                }
+            }
+
+            let metrics = hints.metrics();
+            log::info!(
+                "hints pending: {} ({} bytes), replayed: {}, dropped: {}",
+                metrics.pending,
+                metrics.pending_bytes,
+                metrics.replayed,
+                metrics.dropped
+            );
+        }
+    })
+}
+
+/// Reads the hints of a file. A hint cut short by a crash while it was appended is removed from the file.
+fn read_hints(path: &Path) -> io::Result<Vec<Hint>> {
+    let bytes = fs::read(path)?;
+    let mut reader = io::Cursor::new(bytes.as_slice());
+    let mut hints = Vec::new();
+    let mut complete = 0;
+    loop {
+        match read_message(&mut reader) {
+            Ok(Some(hint)) => {
+                hints.push(hint);
+                complete = reader.position();
+            }
+            Ok(None) => break,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    if complete < bytes.len() as u64 {
+        log::warn!(
+            "removed an incomplete hint of {} bytes at the end of {:?}",
+            bytes.len() as u64 - complete,
+            path
+        );
+        OpenOptions::new().write(true).open(path)?.set_len(complete)?;
+    }
+    Ok(hints)
+}
+
+fn append_hints(path: &Path, hints: &[Hint]) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    for hint in hints {
+        write_message(&mut writer, hint)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_data()
+}
+
+/// Size of the hint on disk, including the length prefix
+fn frame_len(hint: &Hint) -> u64 {
+    4 + bincode::serialized_size(hint).unwrap_or_default()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::message::{ItemVersion, NodeInfo};
+    use std::net::TcpListener;
+
+    fn settings(name: &str, size_max: u64) -> config::HintedHandoff {
+        let directory = std::env::temp_dir().join(format!("poncu-hints-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        config::HintedHandoff {
+            directory,
+            size_max: config::ByteSize(size_max),
+            ..Default::default()
+        }
+    }
+
+    fn node(id: &str, addr: SocketAddr) -> NodeInfo {
+        NodeInfo {
+            id: id.to_string(),
+            name: id.to_string(),
+            description: String::new(),
+            addr,
+        }
+    }
+
+    fn item(value: &str) -> VersionedValue {
+        VersionedValue {
+            version: ItemVersion::now(1),
+            value: Some(value.as_bytes().to_vec()),
+        }
+    }
+
+    /// Peer answering the handshake and acknowledging the replicas, returns the keys received
+    fn start_peer(listener: TcpListener, connections: usize) -> JoinHandle<Vec<String>> {
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut keys = Vec::new();
+            for stream in listener.incoming().take(connections) {
+                let mut stream = stream.unwrap();
+                let _hello: Option<Request> = read_message(&mut stream).unwrap();
+                if let Some(Request::ReplicaSet { key, .. }) = read_message(&mut stream).unwrap() {
+                    keys.push(key);
+                }
+                let hello = Response::Hello {
+                    node: node("n2", addr),
+                    credentials: None,
+                };
+                write_message(&mut stream, &hello).unwrap();
+                write_message(&mut stream, &Response::Done).unwrap();
+            }
+            keys
+        })
+    }
+
+    #[test]
+    fn hints_are_limited() {
+        let settings = settings("limited", 100);
+        let hints = HintedHandoff::open(&settings).unwrap();
+        let peer: SocketAddr = "127.0.0.1:9192".parse().unwrap();
+        assert!(hints.store(peer, "a", &item("value")));
+        let metrics = hints.metrics();
+        assert_eq!((metrics.pending, metrics.dropped), (1, 0));
+        assert!(!hints.store(peer, "b", &item(&"x".repeat(100))));
+        assert_eq!((hints.metrics().pending, hints.metrics().dropped), (1, 1));
+
+        // stored hints are accounted after a restart, an incomplete hint at the end is removed
+        let path = hints.hint_path(peer);
+        let complete = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 20, 1, 2, 3]).unwrap();
+        drop(file);
+        // other files in the directory are left alone
+        let foreign = settings.directory.join("notes.txt");
+        fs::write(&foreign, [0, 0, 0, 20, 1, 2, 3]).unwrap();
+        fs::create_dir(settings.directory.join("old.hints")).unwrap();
+        let reopened = HintedHandoff::open(&settings).unwrap();
+        assert_eq!(reopened.metrics(), metrics);
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+        assert_eq!(fs::read(&foreign).unwrap(), [0, 0, 0, 20, 1, 2, 3]);
+
+        // only nodes with hints are woken
+        reopened.node_alive("127.0.0.1:9193".parse().unwrap());
+        assert!(reopened.wait_alive(Duration::ZERO).is_empty());
+        reopened.node_alive(peer);
+        assert_eq!(reopened.wait_alive(Duration::ZERO), [peer]);
+        fs::remove_dir_all(&settings.directory).unwrap();
+    }
+
+    #[test]
+    fn hints_are_replayed() {
+        let settings = settings("replayed", 1024 * 1024);
+        let hints = HintedHandoff::open(&settings).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = listener.local_addr().unwrap();
+        let membership = Membership::new(node("n1", "127.0.0.1:9191".parse().unwrap()), vec![peer], None, None);
+
+        // an unreachable node keeps its hints
+        let unreachable = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        assert!(hints.store(unreachable, "a", &item("1")));
+        hints.replay(&membership, unreachable).unwrap();
+        assert_eq!(hints.metrics().pending, 1);
+        assert!(hints.hint_path(unreachable).exists());
+
+        // a replay interrupted by a crash is finished first, hints beyond the age limit are dropped
+        let path = hints.hint_path(peer);
+        let stale = Hint {
+            created: 0,
+            key: "stale".to_string(),
+            item: item("2"),
+        };
+        let fresh = Hint {
+            created: unix_time(),
+            key: "fresh".to_string(),
+            item: item("3"),
+        };
+        append_hints(&path.with_extension("replay"), &[stale, fresh]).unwrap();
+        let hints = HintedHandoff::open(&settings).unwrap();
+        assert!(hints.store(peer, "later", &item("4")));
+        assert_eq!(hints.metrics().pending, 4);
+
+        let peer_thread = start_peer(listener, 2);
+        hints.replay(&membership, peer).unwrap();
+        assert!(!path.with_extension("replay").exists());
+        hints.replay(&membership, peer).unwrap();
+        assert_eq!(peer_thread.join().unwrap(), ["fresh", "later"]);
+        assert!(!path.exists());
+        let metrics = hints.metrics();
+        assert_eq!((metrics.pending, metrics.replayed, metrics.dropped), (1, 2, 1));
+        hints.node_alive(peer);
+        assert!(hints.wait_alive(Duration::ZERO).is_empty());
+        fs::remove_dir_all(&settings.directory).unwrap();
+    }
+}
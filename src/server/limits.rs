@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::hash::Hash;
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::frame::{read_message, write_message};
+use crate::protocol::message::{Request, Response};
+use crate::server::auth::Caller;
+use crate::utils::config::{self, Config};
+
+/// Buckets of a limit kept before the idle ones are dropped
+const BUCKETS_MAX: usize = 10_000;
+
+/// Token bucket holding up to one second of its rate
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated = now;
+    }
+}
+
+/// Buckets of one limit, by client id or by IP address
+struct Buckets<K> {
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K: Eq + Hash + Clone> Buckets<K> {
+    fn new() -> Self {
+        Buckets {
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes `cost` tokens, returns the time until they are available if the bucket is short.
+    /// Costs above the rate are accepted from a full bucket, leaving it in debt.
+    fn take(&self, key: &K, rate: u64, cost: u64) -> Result<(), Duration> {
+        if rate == 0 {
+            return Ok(());
+        }
+        let (rate, cost) = (rate as f64, cost as f64);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= BUCKETS_MAX && !buckets.contains_key(key) {
+            // full buckets are the same as new ones
+            buckets.retain(|_, bucket| {
+                bucket.refill(rate, now);
+                bucket.tokens < rate
+            });
+        }
+
+        let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+        bucket.refill(rate, now);
+        let needed = cost.min(rate);
+        if bucket.tokens < needed {
+            return Err(Duration::from_secs_f64((needed - bucket.tokens) / rate));
+        }
+        bucket.tokens -= cost;
+        Ok(())
+    }
+
+    /// Gives back tokens taken for a request refused by another limit
+    fn refund(&self, key: &K, rate: u64, cost: u64) {
+        if rate == 0 {
+            return;
+        }
+        if let Some(bucket) = self.buckets.lock().unwrap().get_mut(key) {
+            bucket.tokens = (bucket.tokens + cost as f64).min(rate as f64);
+        }
+    }
+
+    /// Takes the tokens even if the bucket goes into debt
+    fn charge(&self, key: &K, rate: u64, cost: u64) {
+        if rate == 0 || cost == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: rate as f64,
+            updated: now,
+        });
+        bucket.refill(rate as f64, now);
+        bucket.tokens -= cost as f64;
+    }
+}
+
+/// Change of the counted storage, appended to the usage file
+#[derive(Debug, Serialize, Deserialize)]
+struct UsageRecord {
+    key: String,
+    /// none once the item is removed
+    owner: Option<String>,
+    size: u64,
+}
+
+/// Storage of the clients, counted by the node coordinating their writes
+#[derive(Default)]
+struct Usage {
+    /// items and bytes of each client
+    clients: HashMap<String, (u64, u64)>,
+    /// owner and size of each item
+    items: HashMap<String, (String, u64)>,
+    /// items and bytes of each client's writes in progress
+    reserved: HashMap<String, (u64, u64)>,
+    /// records of the changes, none if the storage is counted in memory only
+    file: Option<PathBuf>,
+}
+
+impl Usage {
+    /// Counts the storage kept in the file, rewritten with one record per item
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut usage = Usage::default();
+        match fs::read(path) {
+            Ok(bytes) => {
+                let mut reader = io::Cursor::new(bytes.as_slice());
+                loop {
+                    match read_message::<UsageRecord>(&mut reader) {
+                        Ok(Some(UsageRecord { key, owner: Some(owner), size })) => usage.record(&owner, &key, size),
+                        Ok(Some(UsageRecord { key, owner: None, .. })) => usage.release(&key),
+                        Ok(None) => break,
+                        // a record cut short by a crash
+                        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let temp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(fs::File::create(&temp)?);
+        for (key, (owner, size)) in &usage.items {
+            write_message(&mut writer, &UsageRecord {
+                key: key.clone(),
+                owner: Some(owner.clone()),
+                size: *size,
+            })?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        fs::rename(&temp, path)?;
+        usage.file = Some(path.to_path_buf());
+        Ok(usage)
+    }
+
+    fn release(&mut self, key: &str) {
+        if let Some((owner, size)) = self.items.remove(key) {
+            if let Some((items, bytes)) = self.clients.get_mut(&owner) {
+                *items -= 1;
+                *bytes -= size;
+            }
+        }
+    }
+
+    fn record(&mut self, client: &str, key: &str, size: u64) {
+        self.release(key);
+        let (items, bytes) = self.clients.entry(client.to_string()).or_default();
+        *items += 1;
+        *bytes += size;
+        self.items.insert(key.to_string(), (client.to_string(), size));
+    }
+
+    fn unreserve(&mut self, client: &str, reserved: (u64, u64)) {
+        if let Some((items, bytes)) = self.reserved.get_mut(client) {
+            *items -= reserved.0;
+            *bytes -= reserved.1;
+            if (*items, *bytes) == (0, 0) {
+                self.reserved.remove(client);
+            }
+        }
+    }
+
+    /// Appends the change to the usage file, the count in memory stays right if it fails
+    fn append(&self, record: &UsageRecord) {
+        let Some(path) = self.file.as_ref() else {
+            return;
+        };
+        let result = OpenOptions::new().create(true).append(true).open(path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write_message(&mut writer, record)?;
+            writer.flush()?;
+            writer.get_ref().sync_data()
+        });
+        if let Err(err) = result {
+            log::error!("could not record the storage usage in {:?}: {}", path, err);
+        }
+    }
+}
+
+/// Write of a client counted against its quotas once it completes.
+/// Its storage is reserved meanwhile, so that concurrent writes can not exceed the quotas together.
+#[derive(Debug)]
+pub struct QuotaWrite {
+    client: String,
+    key: String,
+    /// size of the value, none for removals
+    size: Option<u64>,
+    /// items and bytes reserved
+    reserved: (u64, u64),
+}
+
+/// Request rates, bandwidth and storage quotas of the clients
+pub struct Limits {
+    settings: RwLock<config::Limits>,
+    client_requests: Buckets<String>,
+    ip_requests: Buckets<IpAddr>,
+    client_bandwidth: Buckets<String>,
+    ip_bandwidth: Buckets<IpAddr>,
+    usage: Mutex<Usage>,
+}
+
+impl Limits {
+    /// Fails if the usage file can not be read or rewritten
+    pub fn with_config(config: &Config) -> Result<Self, String> {
+        let settings = config.limits.clone().unwrap_or_default();
+        let usage = match settings.usage_file.as_ref() {
+            Some(path) => Usage::open(path).map_err(|err| format!("usage file {:?}: {}", path, err))?,
+            None => Usage::default(),
+        };
+        Ok(Limits {
+            settings: RwLock::new(settings),
+            client_requests: Buckets::new(),
+            ip_requests: Buckets::new(),
+            client_bandwidth: Buckets::new(),
+            ip_bandwidth: Buckets::new(),
+            usage: Mutex::new(usage),
+        })
+    }
+
+    /// Applies reloaded limits, the buckets and the counted storage are kept, as is the usage file
+    pub fn set_limits(&self, settings: &config::Limits) {
+        *self.settings.write().unwrap() = settings.clone();
+    }
+
+    /// Checks the rates of the caller and the quotas for writes before the request is handled.
+    /// Returns the write to count if quotas are enabled, or the exceeded limit as response.
+    pub fn admit(
+        &self,
+        caller: &Caller,
+        ip: Option<IpAddr>,
+        request: &Request,
+    ) -> Result<Option<QuotaWrite>, Box<Response>> {
+        let size = match request {
+            Request::SetItem { value, .. } => value.len() as u64,
+            _ => 0,
+        };
+        self.check_rates(caller, ip, size)
+            .map_err(|(reason, retry_after)| {
+                Box::new(Response::LimitExceeded {
+                    reason,
+                    retry_after_ms: Some(retry_after.as_millis() as u64 + 1),
+                })
+            })?;
+
+        let Caller::Client { id, .. } = caller else {
+            return Ok(None);
+        };
+        if !self.quotas_enabled() {
+            return Ok(None);
+        }
+        let (key, size, reserved) = match request {
+            Request::SetItem { key, .. } => {
+                let reserved = self.reserve(id, key, size)
+                    .map_err(|reason| {
+                        Box::new(Response::LimitExceeded {
+                            reason,
+                            retry_after_ms: None,
+                        })
+                    })?;
+                (key, Some(size), reserved)
+            }
+            Request::RemoveItem { key, .. } => (key, None, (0, 0)),
+            _ => return Ok(None),
+        };
+        Ok(Some(QuotaWrite {
+            client: id.clone(),
+            key: key.clone(),
+            size,
+            reserved,
+        }))
+    }
+
+    /// Checks the quotas for a file written by the file server, files count as items of the client writing them.
+    /// `size` is none for removals. Returns the write to count if quotas are enabled, or the exceeded quota.
+    pub fn admit_file(&self, caller: &Caller, path: &Path, size: Option<u64>) -> Result<Option<QuotaWrite>, String> {
+        let Caller::Client { id, .. } = caller else {
+            return Ok(None);
+        };
+        if !self.quotas_enabled() {
+            return Ok(None);
+        }
+        let key = format!("file:{}", path.display());
+        let reserved = match size {
+            Some(size) => self.reserve(id, &key, size)?,
+            None => (0, 0),
+        };
+        Ok(Some(QuotaWrite {
+            client: id.clone(),
+            key,
+            size,
+            reserved,
+        }))
+    }
+
+    /// Charges the values sent back and counts the storage of a completed write
+    pub fn complete(
+        &self,
+        caller: &Caller,
+        ip: Option<IpAddr>,
+        write: Option<QuotaWrite>,
+        response: &Response,
+    ) {
+        if let Response::Item(Some(item)) = response {
+            let size = item.value.as_ref().map_or(0, |value| value.len() as u64);
+            self.charge(caller, ip, size);
+        }
+
+        self.finish(write, matches!(response, Response::Done));
+    }
+
+    /// Releases the reserved storage of a write and counts the storage if the write was done
+    pub fn finish(&self, write: Option<QuotaWrite>, done: bool) {
+        let Some(write) = write else {
+            return;
+        };
+        let mut usage = self.usage.lock().unwrap();
+        usage.unreserve(&write.client, write.reserved);
+        if done {
+            let owner = match write.size {
+                Some(size) => {
+                    usage.record(&write.client, &write.key, size);
+                    Some(write.client)
+                }
+                None => {
+                    usage.release(&write.key);
+                    None
+                }
+            };
+            let size = write.size.unwrap_or_default();
+            usage.append(&UsageRecord { key: write.key, owner, size });
+        }
+    }
+
+    /// Takes a request and `bytes` from the rates of the caller, nothing if one of them is exceeded.
+    /// Returns the exceeded limit and when to try again.
+    pub fn check_rates(
+        &self,
+        caller: &Caller,
+        ip: Option<IpAddr>,
+        bytes: u64,
+    ) -> Result<(), (String, Duration)> {
+        if let Caller::Node(_) = caller {
+            return Ok(());
+        }
+        let settings = self.settings.read().unwrap().clone();
+        let client = match caller {
+            Caller::Client { id, .. } => Some(id),
+            _ => None,
+        };
+        let refund_client = || {
+            if let Some(id) = client {
+                self.client_requests.refund(id, settings.client_requests as u64, 1);
+                self.client_bandwidth.refund(id, settings.client_bandwidth.0, bytes);
+            }
+        };
+
+        if let Some(id) = client {
+            self.client_requests
+                .take(id, settings.client_requests as u64, 1)
+                .map_err(|retry_after| ("request rate of the client exceeded".to_string(), retry_after))?;
+            if let Err(retry_after) = self.client_bandwidth.take(id, settings.client_bandwidth.0, bytes) {
+                self.client_requests.refund(id, settings.client_requests as u64, 1);
+                return Err(("bandwidth of the client exceeded".to_string(), retry_after));
+            }
+        }
+        if let Some(ip) = ip {
+            if let Err(retry_after) = self.ip_requests.take(&ip, settings.ip_requests as u64, 1) {
+                refund_client();
+                return Err((format!("request rate of {} exceeded", ip), retry_after));
+            }
+            if let Err(retry_after) = self.ip_bandwidth.take(&ip, settings.ip_bandwidth.0, bytes) {
+                self.ip_requests.refund(&ip, settings.ip_requests as u64, 1);
+                refund_client();
+                return Err((format!("bandwidth of {} exceeded", ip), retry_after));
+            }
+        }
+        Ok(())
+    }
+
+    /// Charges bytes sent to the caller, the next requests wait if the bandwidth is exceeded
+    pub fn charge(&self, caller: &Caller, ip: Option<IpAddr>, bytes: u64) {
+        if let Caller::Node(_) = caller {
+            return;
+        }
+        let settings = self.settings.read().unwrap().clone();
+        if let Caller::Client { id, .. } = caller {
+            self.client_bandwidth.charge(id, settings.client_bandwidth.0, bytes);
+        }
+        if let Some(ip) = ip {
+            self.ip_bandwidth.charge(&ip, settings.ip_bandwidth.0, bytes);
+        }
+    }
+
+    fn quotas_enabled(&self) -> bool {
+        let settings = self.settings.read().unwrap();
+        settings.client_items_max > 0 || settings.client_bytes_max.0 > 0
+    }
+
+    /// Checks the quotas of the client including its writes in progress, and reserves the storage of the write
+    /// under the same lock. Returns the items and bytes reserved.
+    fn reserve(&self, client: &str, key: &str, size: u64) -> Result<(u64, u64), String> {
+        let settings = self.settings.read().unwrap().clone();
+        let mut usage = self.usage.lock().unwrap();
+        let (items, bytes) = usage.clients.get(client).copied().unwrap_or_default();
+        let (reserved_items, reserved_bytes) = usage.reserved.get(client).copied().unwrap_or_default();
+        let (new_items, replaced) = match usage.items.get(key) {
+            Some((owner, stored)) if owner == client => (0, *stored),
+            _ => (1, 0),
+        };
+        let items = items + reserved_items + new_items;
+        let bytes = bytes + reserved_bytes + size - replaced;
+        if settings.client_items_max > 0 && items > settings.client_items_max {
+            return Err(format!("quota of {} items exceeded", settings.client_items_max));
+        }
+        if settings.client_bytes_max.0 > 0 && bytes > settings.client_bytes_max.0 {
+            return Err(format!("quota of {} bytes exceeded", settings.client_bytes_max.0));
+        }
+        let reserved = usage.reserved.entry(client.to_string()).or_default();
+        reserved.0 += new_items;
+        reserved.1 += size;
+        Ok((new_items, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::message::ConsistencyLevel;
+    use crate::utils::config::parse_config;
+    use std::path::Path;
+
+    #[test]
+    fn rates_and_quotas() {
+        let source = "limits:\n  client_requests: 2\n  client_items_max: 1\n";
+        let limits = Limits::with_config(&parse_config(source, Path::new("x"), &[]).unwrap()).unwrap();
+        let client = Caller::Client {
+            id: "app1".to_string(),
+            roles: vec![],
+        };
+        let set = |key: &str| Request::SetItem {
+            key: key.to_string(),
+            value: b"value".to_vec(),
+            consistency: ConsistencyLevel::One,
+        };
+
+        let write = limits.admit(&client, None, &set("a")).unwrap();
+        limits.complete(&client, None, write, &Response::Done);
+        // overwriting the own item keeps the item count
+        assert!(limits.admit(&client, None, &set("a")).is_ok());
+        match limits.admit(&client, None, &set("b")) {
+            Err(exceeded) => match *exceeded {
+                Response::LimitExceeded { retry_after_ms, .. } => assert!(retry_after_ms.is_some()),
+                other => panic!("unexpected response: {:?}", other),
+            },
+            other => panic!("expected the request rate to be exceeded: {:?}", other),
+        }
+        assert!(limits.reserve("app1", "b", 5).is_err());
+        assert!(limits.reserve("app2", "b", 5).is_ok());
+        // anonymous callers are limited by their address only
+        assert!(limits.admit(&Caller::Anonymous, None, &set("b")).is_ok());
+    }
+
+    #[test]
+    fn refused_requests_take_no_tokens() {
+        let source = "limits:\n  client_requests: 2\n  ip_requests: 1\n";
+        let limits = Limits::with_config(&parse_config(source, Path::new("x"), &[]).unwrap()).unwrap();
+        let client = Caller::Client {
+            id: "app1".to_string(),
+            roles: vec![],
+        };
+        let ip = Some("127.0.0.1".parse().unwrap());
+
+        assert!(limits.check_rates(&client, ip, 0).is_ok());
+        let (limit, _) = limits.check_rates(&client, ip, 0).unwrap_err();
+        assert!(limit.contains("127.0.0.1"), "{}", limit);
+        // the request refused by the address limit left the client its token
+        assert!(limits.check_rates(&client, None, 0).is_ok());
+        assert!(limits.check_rates(&client, None, 0).is_err());
+    }
+
+    #[test]
+    fn quotas_are_reserved_and_kept() {
+        let usage_file = std::env::temp_dir().join(format!("poncu-usage-{}", std::process::id()));
+        let source = format!("limits:\n  client_items_max: 1\n  usage_file: {}\n", usage_file.display());
+        let config = parse_config(&source, Path::new("x"), &[]).unwrap();
+        let limits = Limits::with_config(&config).unwrap();
+        let client = Caller::Client {
+            id: "app1".to_string(),
+            roles: vec![],
+        };
+        let set = |key: &str| Request::SetItem {
+            key: key.to_string(),
+            value: b"value".to_vec(),
+            consistency: ConsistencyLevel::One,
+        };
+
+        // a write in progress counts until it completes
+        let write = limits.admit(&client, None, &set("a")).unwrap();
+        assert!(limits.admit(&client, None, &set("b")).is_err());
+        limits.complete(&client, None, write, &Response::Error("failed".to_string()));
+        let write = limits.admit(&client, None, &set("b")).unwrap();
+        limits.complete(&client, None, write, &Response::Done);
+        assert!(limits.admit(&client, None, &set("a")).is_err());
+
+        // the counted storage survives a restart
+        drop(limits);
+        let limits = Limits::with_config(&config).unwrap();
+        assert!(limits.admit(&client, None, &set("a")).is_err());
+        let remove = Request::RemoveItem {
+            key: "b".to_string(),
+            consistency: ConsistencyLevel::One,
+        };
+        let write = limits.admit(&client, None, &remove).unwrap();
+        limits.complete(&client, None, write, &Response::Done);
+        drop(limits);
+        let limits = Limits::with_config(&config).unwrap();
+        assert!(limits.admit(&client, None, &set("a")).is_ok());
+        fs::remove_file(&usage_file).unwrap();
+    }
+}
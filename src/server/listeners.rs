@@ -0,0 +1,213 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Pending connections queued by the kernel for each listener
+const BACKLOG: i32 = 1024;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// Accepted connection, before TLS is set up
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Limits the time a blocking read or write may take, none for no limit
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            #[cfg(unix)]
+            Stream::Unix(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+        }
+    }
+
+    /// Handle to the same socket, its options are shared
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+}
+
+impl Listener {
+    /// Waits for a connection, returns it with the address of the peer, if it has one
+    pub fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Stream::Tcp(stream), Some(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept()?;
+                Ok((Stream::Unix(stream), None))
+            }
+        }
+    }
+
+    /// Address of the listener, used in log messages
+    pub fn name(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+/// Binds all the addresses and the Unix domain socket, fails on the first one that can not be bound.
+/// An IPv6 address accepts IPv4 connections as well (dual-stack),
+/// unless IPv4 addresses are configured too and need sockets of their own.
+pub fn bind_all(listen_on: &[SocketAddr], unix_socket: Option<&Path>) -> io::Result<Vec<Listener>> {
+    let v6_only = listen_on.iter().any(|addr| addr.is_ipv4());
+
+    let mut listeners = Vec::with_capacity(listen_on.len() + 1);
+    for addr in listen_on {
+        let listener = bind_tcp(*addr, v6_only).map_err(|err| {
+            io::Error::new(err.kind(), format!("could not bind {}: {}", addr, err))
+        })?;
+        listeners.push(Listener::Tcp(listener));
+    }
+
+    if let Some(path) = unix_socket {
+        let listener = bind_unix(path).map_err(|err| {
+            io::Error::new(err.kind(), format!("could not bind {}: {}", path.display(), err))
+        })?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+fn bind_tcp(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    // allows restarting while the connections of the previous run are in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<Listener> {
+    // a socket left by a previous run is replaced, unless a server still accepts connections on it.
+    // Anything else at the path is kept.
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).is_err() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the path exists and is not a socket",
+            ))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let listener = UnixListener::bind(path)?;
+    Ok(Listener::Unix(listener, path.to_path_buf()))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path) -> io::Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::SockRef;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn tcp(listener: &Listener) -> &TcpListener {
+        match listener {
+            Listener::Tcp(listener) => listener,
+            #[cfg(unix)]
+            Listener::Unix(..) => panic!("not a TCP listener"),
+        }
+    }
+
+    #[test]
+    fn ipv6_listeners_are_dual_stack_alone() {
+        // hosts without IPv6 can not bind these. A socket bound to a single IPv6 address is always IPv6 only.
+        let Ok(listeners) = bind_all(&[addr("[::]:0")], None) else {
+            return;
+        };
+        assert!(!SockRef::from(tcp(&listeners[0])).only_v6().unwrap());
+
+        let listeners = bind_all(&[addr("127.0.0.1:0"), addr("[::]:0")], None).unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert!(SockRef::from(tcp(&listeners[1])).only_v6().unwrap());
+    }
+
+    #[test]
+    fn failed_binds_are_reported() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let _taken = bind_tcp(taken, false).unwrap();
+        let err = bind_all(&[addr("127.0.0.1:0"), taken], None).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(err.to_string().starts_with(&format!("could not bind {}", taken)), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_sockets_are_replaced() {
+        let dir = std::env::temp_dir().join(format!("poncu-listeners-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("poncu.sock");
+
+        let listener = bind_all(&[], Some(&path)).unwrap();
+        assert_eq!(listener[0].name(), format!("unix:{}", path.display()));
+        // a server still accepts connections on it
+        assert!(bind_all(&[], Some(&path)).is_err());
+        drop(listener);
+        assert!(bind_all(&[], Some(&path)).is_ok());
+
+        let file = dir.join("poncu.yaml");
+        std::fs::write(&file, "server:\n").unwrap();
+        let err = bind_all(&[], Some(&file)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "server:\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use rustls::ClientConfig;
+
+use crate::protocol::message::{Credentials, NodeInfo, NodeStatus, ReloadStatus};
+use crate::server::auth;
+
+/// Identity of the local node and of the peers learned from handshakes
+pub struct Membership {
+    local: NodeInfo,
+    /// signs the handshakes with the peers
+    node_token: Option<String>,
+    /// TLS to the peers, if the nodes listen with TLS
+    peer_tls: Option<Arc<ClientConfig>>,
+    /// configured peers
+    peers: RwLock<Vec<SocketAddr>>,
+    known: RwLock<HashMap<SocketAddr, NodeInfo>>,
+}
+
+impl Membership {
+    pub fn new(
+        local: NodeInfo,
+        peers: Vec<SocketAddr>,
+        node_token: Option<String>,
+        peer_tls: Option<Arc<ClientConfig>>,
+    ) -> Self {
+        Membership {
+            local,
+            node_token,
+            peer_tls,
+            peers: RwLock::new(peers),
+            known: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn local(&self) -> &NodeInfo {
+        &self.local
+    }
+
+    /// Credentials of the local node for a handshake, fresh for every connection
+    pub fn node_credentials(&self) -> Option<Credentials> {
+        self.node_token
+            .as_ref()
+            .map(|token| auth::node_credentials(token, &self.local))
+    }
+
+    /// Credentials for the reply to the handshake of a peer, bound to the nonce of the peer
+    pub fn reply_credentials(&self, hello: &Credentials) -> Option<Credentials> {
+        match (self.node_token.as_ref(), hello) {
+            (Some(token), Credentials::Node { nonce, .. }) => {
+                Some(auth::node_reply_credentials(token, &self.local, *nonce))
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks the reply of a peer to the handshake signed with `sent`.
+    /// Without a node token the peer is only authenticated by its TLS certificate, if the nodes use TLS.
+    pub fn verify_reply(
+        &self,
+        node: &NodeInfo,
+        sent: Option<&Credentials>,
+        reply: Option<&Credentials>,
+    ) -> Result<(), String> {
+        match self.node_token.as_ref() {
+            Some(token) => auth::verify_node_reply(token, node, sent, reply),
+            None => Ok(()),
+        }
+    }
+
+    pub fn peer_tls(&self) -> Option<&Arc<ClientConfig>> {
+        self.peer_tls.as_ref()
+    }
+
+    /// Replaces the configured peers, the identities already learned are kept
+    pub fn set_peers(&self, peers: Vec<SocketAddr>) {
+        *self.peers.write().unwrap() = peers;
+    }
+
+    /// Records the identity of a peer, rejecting ids already used by another node
+    pub fn register(&self, node: &NodeInfo) -> Result<(), String> {
+        if node.id == self.local.id && node.addr != self.local.addr {
+            return Err(format!(
+                "duplicate node id {}: used by the local node {}",
+                node.id, self.local.addr
+            ));
+        }
+
+        let mut known = self.known.write().unwrap();
+        if let Some((addr, _)) = known
+            .iter()
+            .find(|(addr, info)| info.id == node.id && **addr != node.addr)
+        {
+            return Err(format!(
+                "duplicate node id {}: used by {} and {}",
+                node.id, addr, node.addr
+            ));
+        }
+
+        if known.get(&node.addr) != Some(node) {
+            log::info!("node {} ({}) at {}", node.id, node.name, node.addr);
+            known.insert(node.addr, node.clone());
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> NodeStatus {
+        let known = self.known.read().unwrap();
+        NodeStatus {
+            node: self.local.clone(),
+            peers: self
+                .peers
+                .read()
+                .unwrap()
+                .iter()
+                .map(|addr| (*addr, known.get(addr).cloned()))
+                .collect(),
+            reload: ReloadStatus::default(),
+            hints: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str, addr: &str) -> NodeInfo {
+        NodeInfo {
+            id: id.to_string(),
+            name: id.to_string(),
+            description: String::new(),
+            addr: addr.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn duplicate_ids_are_rejected() {
+        let membership = Membership::new(node("n1", "127.0.0.1:9191"), vec![], None, None);
+        assert!(membership.register(&node("n2", "127.0.0.1:9192")).is_ok());
+        assert!(membership.register(&node("n2", "127.0.0.1:9192")).is_ok());
+        assert!(membership.register(&node("n1", "127.0.0.1:9193")).is_err());
+        assert!(membership.register(&node("n2", "127.0.0.1:9193")).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use crate::protocol::message::ItemVersion;
+
+/// Depth of the hash trees, 2^depth leaves per key range
+pub const TREE_DEPTH: u32 = 8;
+
+/// Binary hash tree over the item versions of a key range.
+/// Nodes are stored level by level, the children of node `i` are `2i+1` and `2i+2`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    nodes: Vec<u64>,
+}
+
+impl MerkleTree {
+    /// Builds the tree from item versions and the leaf index of each item
+    pub fn build<'a>(items: impl Iterator<Item = (u32, &'a str, ItemVersion)>) -> Self {
+        let leaves_count = leaves_count();
+        let mut nodes = vec![0; 2 * leaves_count - 1];
+
+        // leaves are combined with XOR, so the order of items does not matter
+        let first_leaf = leaves_count - 1;
+        for (leaf, key, version) in items {
+            nodes[first_leaf + leaf as usize] ^= item_hash(key, version);
+        }
+
+        for i in (0..first_leaf).rev() {
+            let mut bytes = [0; 16];
+            bytes[..8].copy_from_slice(&nodes[2 * i + 1].to_be_bytes());
+            bytes[8..].copy_from_slice(&nodes[2 * i + 2].to_be_bytes());
+            nodes[i] = hash_bytes(&bytes);
+        }
+
+        MerkleTree { nodes }
+    }
+
+    pub fn from_nodes(nodes: Vec<u64>) -> Option<Self> {
+        if nodes.len() == 2 * leaves_count() - 1 {
+            Some(MerkleTree { nodes })
+        } else {
+            None
+        }
+    }
+
+    pub fn into_nodes(self) -> Vec<u64> {
+        self.nodes
+    }
+
+    /// Returns the indexes of the leaves that differ, descending only into differing subtrees
+    pub fn diff(&self, other: &MerkleTree) -> Vec<u32> {
+        let first_leaf = leaves_count() - 1;
+        let mut leaves = Vec::new();
+        let mut pending = vec![0];
+        while let Some(i) = pending.pop() {
+            if self.nodes[i] == other.nodes[i] {
+                continue;
+            }
+            if i >= first_leaf {
+                leaves.push((i - first_leaf) as u32);
+            } else {
+                pending.push(2 * i + 2);
+                pending.push(2 * i + 1);
+            }
+        }
+        leaves
+    }
+}
+
+/// Returns the key range and the tree leaf of a key
+pub fn locate(key: &str, ranges_count: u32) -> (u32, u32) {
+    let hash = hash_bytes(key.as_bytes());
+    let range = (hash % ranges_count as u64) as u32;
+    let leaf = ((hash / ranges_count as u64) % leaves_count() as u64) as u32;
+    (range, leaf)
+}
+
+fn leaves_count() -> usize {
+    1 << TREE_DEPTH
+}
+
+fn item_hash(key: &str, version: ItemVersion) -> u64 {
+    let mut bytes = key.as_bytes().to_vec();
+    bytes.extend_from_slice(&version.timestamp.to_be_bytes());
+    bytes.extend_from_slice(&version.node.to_be_bytes());
+    hash_bytes(&bytes)
+}
+
+/// FNV-1a, stable across nodes and builds unlike the std hashers
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_finds_changed_leaves() {
+        let v1 = ItemVersion { timestamp: 1, node: 1 };
+        let v2 = ItemVersion { timestamp: 2, node: 1 };
+        let local = MerkleTree::build([(3, "a", v1), (7, "b", v1)].into_iter());
+        let remote = MerkleTree::build([(7, "b", v1), (3, "a", v2)].into_iter());
+        assert_eq!(local.diff(&local.clone()), Vec::<u32>::new());
+        assert_eq!(local.diff(&remote), vec![3]);
+
+        let missing = MerkleTree::build([(7, "b", v1)].into_iter());
+        assert_eq!(local.diff(&missing), vec![3]);
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use tokio::io::AsyncReadExt;
+
+use crate::utils::config::FileServer;
+
+/// Type of files without a known extension that could not be sniffed
+pub const DEFAULT_TYPE: &str = "application/octet-stream";
+
+/// Bytes read from the start of a file to sniff its type
+const SNIFF_LEN: usize = 512;
+
+/// Types of the common extensions, the configuration may add or replace entries
+const BUILT_IN: &[(&str, &str)] = &[
+    ("7z", "application/x-7z-compressed"),
+    ("avif", "image/avif"),
+    ("bin", "application/octet-stream"),
+    ("bmp", "image/bmp"),
+    ("bz2", "application/x-bzip2"),
+    ("css", "text/css; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("doc", "application/msword"),
+    ("docx", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+    ("epub", "application/epub+zip"),
+    ("flac", "audio/flac"),
+    ("gif", "image/gif"),
+    ("gz", "application/gzip"),
+    ("htm", "text/html; charset=utf-8"),
+    ("html", "text/html; charset=utf-8"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("ics", "text/calendar; charset=utf-8"),
+    ("jar", "application/java-archive"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("log", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("mkv", "video/x-matroska"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("mpeg", "video/mpeg"),
+    ("oga", "audio/ogg"),
+    ("ogg", "audio/ogg"),
+    ("ogv", "video/ogg"),
+    ("opus", "audio/opus"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("rar", "application/vnd.rar"),
+    ("rs", "text/plain; charset=utf-8"),
+    ("rtf", "application/rtf"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("toml", "application/toml"),
+    ("ttf", "font/ttf"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("wasm", "application/wasm"),
+    ("wav", "audio/wav"),
+    ("weba", "audio/webm"),
+    ("webm", "video/webm"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xhtml", "application/xhtml+xml"),
+    ("xls", "application/vnd.ms-excel"),
+    ("xlsx", "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+    ("xml", "application/xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("zip", "application/zip"),
+    ("zst", "application/zstd"),
+];
+
+/// Content types of the served files
+#[derive(Debug, Default)]
+pub struct MimeTypes {
+    /// configured types by lowercase extension
+    configured: HashMap<String, String>,
+    sniff: bool,
+}
+
+impl MimeTypes {
+    pub fn with_config(config: &FileServer) -> Self {
+        MimeTypes {
+            configured: config
+                .mime_types
+                .iter()
+                .map(|(extension, mime_type)| (extension.trim_start_matches('.').to_lowercase(), mime_type.clone()))
+                .collect(),
+            sniff: config.sniff_content_type,
+        }
+    }
+
+    /// Type by the extension of the file, case insensitive
+    pub fn by_extension(&self, path: &Path) -> Option<&str> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        if let Some(mime_type) = self.configured.get(&extension) {
+            return Some(mime_type);
+        }
+        BUILT_IN
+            .binary_search_by_key(&extension.as_str(), |(extension, _)| extension)
+            .ok()
+            .map(|index| BUILT_IN[index].1)
+    }
+
+    /// Type by the extension, otherwise by the leading bytes if sniffing is enabled
+    pub async fn content_type(&self, path: &Path) -> String {
+        if let Some(mime_type) = self.by_extension(path) {
+            return mime_type.to_string();
+        }
+        if !self.sniff {
+            return DEFAULT_TYPE.to_string();
+        }
+        let mut buffer = Vec::with_capacity(SNIFF_LEN);
+        match tokio::fs::File::open(path).await {
+            Ok(file) => match file.take(SNIFF_LEN as u64).read_to_end(&mut buffer).await {
+                Ok(_) => sniff(&buffer).to_string(),
+                Err(_) => DEFAULT_TYPE.to_string(),
+            },
+            Err(_) => DEFAULT_TYPE.to_string(),
+        }
+    }
+}
+
+/// Type by the signatures of common formats, text is recognised as valid UTF-8 without control bytes
+pub fn sniff(bytes: &[u8]) -> &'static str {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\x28\xb5\x2f\xfd", "application/zstd"),
+        (b"BZh", "application/x-bzip2"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (b"\x00asm", "application/wasm"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"ID3", "audio/mpeg"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+    ];
+    if let Some((_, mime_type)) = SIGNATURES.iter().find(|(signature, _)| bytes.starts_with(signature)) {
+        return mime_type;
+    }
+    if bytes.len() >= 12 && bytes.starts_with(b"RIFF") {
+        match &bytes[8..12] {
+            b"WEBP" => return "image/webp",
+            b"WAVE" => return "audio/wav",
+            _ => {}
+        }
+    }
+    if bytes.len() >= 8 && &bytes[4..8] == b"ftyp" {
+        return "video/mp4";
+    }
+
+    let text = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    // the last character may be cut off
+    let valid = match std::str::from_utf8(text) {
+        Ok(_) => true,
+        Err(err) => err.error_len().is_none() && bytes.len() == SNIFF_LEN,
+    };
+    if !valid || text.iter().any(|&byte| byte < 0x20 && !b"\t\n\r\x0c\x1b".contains(&byte)) {
+        return DEFAULT_TYPE;
+    }
+    let start = String::from_utf8_lossy(&text[..text.len().min(64)]).trim_start().to_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        "text/html; charset=utf-8"
+    } else if start.starts_with("<?xml") {
+        "application/xml"
+    } else {
+        "text/plain; charset=utf-8"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::config::parse_config;
+
+    #[test]
+    fn content_types() {
+        assert!(BUILT_IN.windows(2).all(|pair| pair[0].0 < pair[1].0), "the table must stay sorted");
+
+        let source = "file_server:\n  mime_types:\n    .RAW: image/x-raw\n    txt: text/plain\n";
+        let config = parse_config(source, Path::new("x"), &[]).unwrap();
+        let mime_types = MimeTypes::with_config(config.file_server.as_ref().unwrap());
+        assert_eq!(mime_types.by_extension(Path::new("a/b.PNG")), Some("image/png"));
+        assert_eq!(mime_types.by_extension(Path::new("b.raw")), Some("image/x-raw"));
+        assert_eq!(mime_types.by_extension(Path::new("b.txt")), Some("text/plain"));
+        assert_eq!(mime_types.by_extension(Path::new("README")), None);
+        assert_eq!(mime_types.by_extension(Path::new("b.unknown")), None);
+
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff(b"  <!DOCTYPE html><html>"), "text/html; charset=utf-8");
+        assert_eq!(sniff("plain text, ä\n".as_bytes()), "text/plain; charset=utf-8");
+        assert_eq!(sniff(b"\x00\x01\x02binary"), DEFAULT_TYPE);
+        assert_eq!(sniff(b"text\xff\xfe"), DEFAULT_TYPE);
+    }
+}
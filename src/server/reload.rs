@@ -0,0 +1,256 @@
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::protocol::message::ReloadStatus;
+use crate::server::access;
+use crate::server::auth::Authenticator;
+use crate::server::replication::Coordinator;
+use crate::utils::cli::Options;
+use crate::utils::config::{self, Config};
+
+/// Pause between the checks of the configuration files
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The configuration in effect, replaced as a whole by reloads
+pub struct LiveConfig {
+    current: RwLock<Arc<Config>>,
+    status: Mutex<ReloadStatus>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config) -> Self {
+        LiveConfig {
+            current: RwLock::new(Arc::new(config)),
+            status: Mutex::new(ReloadStatus::default()),
+        }
+    }
+
+    /// Returns the current configuration, settings read from it are consistent with each other
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn reload_status(&self) -> ReloadStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn record(&self, result: &Result<(), String>) {
+        let mut status = self.status.lock().unwrap();
+        status.last_attempt = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .ok();
+        match result {
+            Ok(()) => {
+                status.reloads += 1;
+                status.last_error = None;
+            }
+            Err(msg) => status.last_error = Some(msg.clone()),
+        }
+    }
+}
+
+/// Reloads the configuration when its files change or the process receives SIGHUP.
+/// The logging configuration is reloaded as well if the handle is given.
+pub fn start_config_watcher(
+    coordinator: Arc<Coordinator>,
+    live: Arc<LiveConfig>,
+    options: Options,
+    log_handle: Option<log4rs::Handle>,
+    flag_shutdown: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    let flag_hangup = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    if let Err(err) = signal_hook::flag::register(signal_hook::consts::SIGHUP, flag_hangup.clone()) {
+        log::error!("could not handle SIGHUP, only file changes trigger reloads: {:?}", err);
+    }
+
+    thread::spawn(move || {
+        let mut modified = modified_times(&options);
+        while !flag_shutdown.load(Ordering::SeqCst) {
+            thread::sleep(WATCH_INTERVAL);
+
+            let hangup = flag_hangup.swap(false, Ordering::SeqCst);
+            let current = modified_times(&options);
+            if !hangup && current == modified {
+                continue;
+            }
+            modified = current;
+
+            log::info!(
+                "reloading configuration from {:?} ({})",
+                options.config,
+                if hangup { "SIGHUP" } else { "file changed" }
+            );
+            let result = reload(&coordinator, &live, &options, log_handle.as_ref());
+            match &result {
+                Ok(()) => log::info!("configuration reloaded"),
+                Err(msg) => log::error!("configuration reload rejected: {}", msg),
+            }
+            live.record(&result);
+        }
+    })
+}
+
+/// Validates the configuration files and applies them if only reloadable settings changed
+fn reload(
+    coordinator: &Coordinator,
+    live: &LiveConfig,
+    options: &Options,
+    log_handle: Option<&log4rs::Handle>,
+) -> Result<(), String> {
+    let config = config::load_config(&options.config, &options.overrides)
+        .map_err(|err| err.to_string())?;
+
+    let changes = restart_required(&live.get(), &config);
+    if !changes.is_empty() {
+        return Err(format!("restart required to change: {}", changes.join(", ")));
+    }
+
+    let authenticator = Authenticator::with_config(&config, coordinator.used_macs())?;
+    let jwt = access::jwt_verifier(&config)?;
+
+    let log_config = match log_handle {
+        Some(_) => Some(
+            log4rs::config::load_config_file(&options.log_config, Default::default())
+                .map_err(|err| format!("{}: {}", options.log_config.display(), err))?,
+        ),
+        None => None,
+    };
+
+    *live.current.write().unwrap() = Arc::new(config.clone());
+    coordinator.reconfigure(&config);
+    coordinator.set_authenticator(authenticator);
+    coordinator.access().set_jwt(jwt);
+    if let (Some(handle), Some(log_config)) = (log_handle, log_config) {
+        handle.set_config(log_config);
+    }
+    Ok(())
+}
+
+/// Settings changed in `new` that take effect only after a restart
+fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut changes = Vec::new();
+
+    // the connections limit and the access token are the only reloadable server settings
+    let server = |config: &Config| {
+        config.server.clone().map(|server| config::Server {
+            connections_max: 0,
+            access_token: None,
+            ..server
+        })
+    };
+    if server(old) != server(new) {
+        changes.push("server");
+    }
+    if old.file_server != new.file_server {
+        changes.push("file_server");
+    }
+    if old.redundancy != new.redundancy {
+        changes.push("redundancy");
+    }
+
+    let node_token = |config: &Config| config.auth.as_ref().and_then(|auth| auth.node_token.clone());
+    if node_token(old) != node_token(new) {
+        changes.push("auth.node_token");
+    }
+
+    let usage_file = |config: &Config| config.limits.as_ref().and_then(|limits| limits.usage_file.clone());
+    if usage_file(old) != usage_file(new) {
+        changes.push("limits.usage_file");
+    }
+
+    let hinted_handoff = |config: &Config| {
+        let settings = config.hinted_handoff.clone().unwrap_or_default();
+        (settings.enabled, settings.directory)
+    };
+    if hinted_handoff(old) != hinted_handoff(new) {
+        changes.push("hinted_handoff.enabled or hinted_handoff.directory");
+    }
+    changes
+}
+
+fn modified_times(options: &Options) -> [Option<SystemTime>; 2] {
+    [&options.config, &options.log_config]
+        .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::message::Credentials;
+    use crate::server::access::Access;
+    use crate::server::auth::{self, Caller};
+    use crate::server::store::ItemStore;
+    use std::path::Path;
+
+    fn parse(source: &str) -> Config {
+        config::parse_config(source, Path::new("config.yaml"), &[]).unwrap()
+    }
+
+    #[test]
+    fn reloadable_settings() {
+        let auth = "auth:\n  node_token: 0123456789abcdef\n";
+        let old = parse(&format!("server:\n  listen_port: 9191\n  connections_max: 20\n{}", auth));
+
+        let new = parse(&format!(
+            "server:\n  listen_port: 9191\n  connections_max: 50\n\
+             remote:\n  nodes: 127.0.0.1:9192\n\
+             anti_entropy:\n  interval: 10\n{}",
+            auth
+        ));
+        assert!(restart_required(&old, &new).is_empty());
+
+        let new = parse(&format!(
+            "server:\n  listen_port: 9292\n  connections_max: 50\n\
+             redundancy:\n  strategy: paranoid\n{}",
+            auth
+        ));
+        assert_eq!(restart_required(&old, &new), vec!["server", "redundancy"]);
+
+        // the other limits are reloadable
+        let new = parse(&format!(
+            "server:\n  listen_port: 9191\n  connections_max: 20\n\
+             limits:\n  client_requests: 10\n  usage_file: /var/poncu/usage\n{}",
+            auth
+        ));
+        assert_eq!(restart_required(&old, &new), vec!["limits.usage_file"]);
+    }
+
+    #[test]
+    fn node_signatures_survive_reloads() {
+        let dir = std::env::temp_dir().join(format!("poncu-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let options = Options {
+            config: dir.join("config.yaml"),
+            log_config: dir.join("log4rs.yaml"),
+            overrides: Vec::new(),
+            ignored: Vec::new(),
+        };
+        let source = "server:\n  listen_port: 9191\nauth:\n  node_token: 0123456789abcdef\n\
+                      hinted_handoff:\n  enabled: false\n";
+        fs::write(&options.config, source).unwrap();
+
+        let config = parse(source);
+        let access = Arc::new(Access::with_config(&config).unwrap());
+        let live = Arc::new(LiveConfig::new(config.clone()));
+        let coordinator = Coordinator::with_config(live.clone(), Arc::new(ItemStore::new()), access).unwrap();
+        coordinator.set_authenticator(Authenticator::with_config(&config, coordinator.used_macs()).unwrap());
+
+        let node = coordinator.membership().local().clone();
+        let credentials = auth::node_credentials("0123456789abcdef", &node);
+        let authenticate = |credentials: &Credentials| {
+            coordinator.authenticator().unwrap().authenticate(Some(&node), Some(credentials), None, &[])
+        };
+        assert_eq!(authenticate(&credentials), Ok(Caller::Node(node.clone())));
+
+        reload(&coordinator, &live, &options, None).unwrap();
+        assert!(authenticate(&credentials).is_err());
+        assert!(authenticate(&auth::node_credentials("0123456789abcdef", &node)).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
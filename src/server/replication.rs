@@ -0,0 +1,528 @@
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConnection, StreamOwned};
+
+use crate::protocol::frame::{read_message, write_message, Connection};
+use crate::protocol::message::{
+    ConsistencyLevel, ItemVersion, NodeInfo, Request, Response, VersionedValue,
+};
+use crate::server::access::Access;
+use crate::server::acl::Acl;
+use crate::server::anti_entropy;
+use crate::server::auth::{Authenticator, UsedMacs};
+use crate::server::hints::HintedHandoff;
+use crate::server::limits::Limits;
+use crate::server::membership::Membership;
+use crate::server::merkle::hash_bytes;
+use crate::server::reload::LiveConfig;
+use crate::server::store::ItemStore;
+use crate::utils::config::{Config, Redundancy};
+use crate::utils::tls;
+
+/// Timeout for connecting, sending and receiving replica messages
+const PEER_TIMEOUT: Duration = Duration::from_secs(2);
+
+type Reply = (SocketAddr, std::io::Result<Response>);
+
+/// Coordinates client requests between the replicas of an item
+pub struct Coordinator {
+    /// address identifying the local node in the claster
+    local: SocketAddr,
+    membership: Arc<Membership>,
+    /// version tie-breaker for the writes coordinated by the local node
+    node: u64,
+    /// all nodes of the claster, including the local one, reloadable
+    nodes: RwLock<Vec<SocketAddr>>,
+    redundancy: Redundancy,
+    store: Arc<ItemStore>,
+    /// writes kept for unreachable replicas
+    hints: Option<Arc<HintedHandoff>>,
+    live: Arc<LiveConfig>,
+    /// installed when the server starts, replaced by reloads
+    authenticator: RwLock<Option<Arc<Authenticator>>>,
+    /// node signatures already accepted, outlive the authenticators
+    used_macs: Arc<UsedMacs>,
+    access: Arc<Access>,
+}
+
+impl Coordinator {
+    pub fn with_config(live: Arc<LiveConfig>, store: Arc<ItemStore>, access: Arc<Access>) -> Result<Self, String> {
+        let config = live.get();
+        let config_server = config.server.as_ref().ok_or("the server section is missing")?;
+        let local = *config_server
+            .listen_on()
+            .first()
+            .ok_or("the server has no listen address")?;
+
+        let nodes = claster_nodes(&config);
+        let redundancy = config.redundancy.clone().unwrap_or_default();
+
+        if log::log_enabled!(log::Level::Debug) {
+            log::debug!(
+                "claster nodes: {:?}, replicas per item: {}",
+                nodes,
+                redundancy.replicas_count(nodes.len())
+            );
+        }
+
+        let hinted_handoff = config.hinted_handoff.clone().unwrap_or_default();
+        // opened without peers as well, they may be added by a reload
+        let hints = if hinted_handoff.enabled {
+            match HintedHandoff::open(&hinted_handoff) {
+                Ok(hints) => Some(Arc::new(hints)),
+                Err(err) => {
+                    log::error!(
+                        "hinted handoff disabled, could not open {:?}: {:?}",
+                        hinted_handoff.directory,
+                        err
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let node = NodeInfo {
+            id: config_server.id.clone(),
+            name: config_server.name.clone(),
+            description: config_server.description.clone(),
+            addr: local,
+        };
+        let node_token = config
+            .auth
+            .as_ref()
+            .and_then(|auth| auth.node_token.as_ref())
+            .map(|token| token.0.clone());
+        let peer_tls = config_server.tls.as_ref().and_then(|settings| {
+            tls::peer_config(settings)
+                .map_err(|err| log::error!("TLS to the peer nodes disabled: {}", err))
+                .ok()
+        });
+        let membership = Membership::new(node, nodes[1..].to_vec(), node_token, peer_tls);
+
+        Ok(Coordinator {
+            local,
+            node: hash_bytes(config_server.id.as_bytes()),
+            membership: Arc::new(membership),
+            nodes: RwLock::new(nodes),
+            redundancy,
+            store,
+            hints,
+            live,
+            authenticator: RwLock::new(None),
+            used_macs: Arc::default(),
+            access,
+        })
+    }
+
+    /// Applies the reloadable settings of the configuration
+    pub fn reconfigure(&self, config: &Config) {
+        let nodes = claster_nodes(config);
+        if *self.nodes.read().unwrap() != nodes {
+            log::info!(
+                "claster nodes: {:?}, replicas per item: {}",
+                nodes,
+                self.redundancy.replicas_count(nodes.len())
+            );
+            self.membership.set_peers(nodes[1..].to_vec());
+            *self.nodes.write().unwrap() = nodes;
+        }
+
+        self.access.reconfigure(config);
+
+        if let Some(hints) = self.hints.as_ref() {
+            hints.set_limits(&config.hinted_handoff.clone().unwrap_or_default());
+        }
+    }
+
+    pub fn authenticator(&self) -> Option<Arc<Authenticator>> {
+        self.authenticator.read().unwrap().clone()
+    }
+
+    pub fn used_macs(&self) -> Arc<UsedMacs> {
+        self.used_macs.clone()
+    }
+
+    pub fn acl(&self) -> Arc<Acl> {
+        self.access.acl()
+    }
+
+    pub fn limits(&self) -> &Limits {
+        self.access.limits()
+    }
+
+    pub fn access(&self) -> &Arc<Access> {
+        &self.access
+    }
+
+    pub fn set_authenticator(&self, authenticator: Authenticator) {
+        *self.authenticator.write().unwrap() = Some(Arc::new(authenticator));
+    }
+
+    pub fn local(&self) -> SocketAddr {
+        self.local
+    }
+
+    pub fn membership(&self) -> &Arc<Membership> {
+        &self.membership
+    }
+
+    /// Returns the other nodes of the claster
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.nodes
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|node| **node != self.local)
+            .copied()
+            .collect()
+    }
+
+    pub fn store(&self) -> &Arc<ItemStore> {
+        &self.store
+    }
+
+    pub fn hints(&self) -> Option<&Arc<HintedHandoff>> {
+        self.hints.as_ref()
+    }
+
+    /// Returns the replica nodes of the key using rendezvous hashing
+    pub fn replicas(&self, key: &str) -> Vec<SocketAddr> {
+        let nodes = self.nodes.read().unwrap();
+        let mut scored = nodes
+            .iter()
+            .map(|node| {
+                let mut bytes = key.as_bytes().to_vec();
+                bytes.extend_from_slice(node.to_string().as_bytes());
+                (hash_bytes(&bytes), *node)
+            })
+            .collect::<Vec<_>>();
+        scored.sort_unstable_by(|a, b| b.cmp(a));
+        scored
+            .into_iter()
+            .take(self.redundancy.replicas_count(nodes.len()))
+            .map(|(_, node)| node)
+            .collect()
+    }
+
+    pub fn handle(&self, request: Request) -> Response {
+        match request {
+            Request::Hello { node, credentials } => {
+                let mut reply_credentials = None;
+                if let Some(node) = node {
+                    if let Err(msg) = self.membership.register(&node) {
+                        log::error!("{}", msg);
+                        return Response::Error(msg);
+                    }
+                    self.node_alive(node.addr);
+                    reply_credentials = credentials.and_then(|credentials| self.membership.reply_credentials(&credentials));
+                }
+                Response::Hello {
+                    node: self.membership.local().clone(),
+                    credentials: reply_credentials,
+                }
+            }
+            Request::Status => {
+                let mut status = self.membership.status();
+                status.reload = self.live.reload_status();
+                status.hints = self.hints.as_ref().map(|hints| hints.metrics());
+                Response::Status(status)
+            }
+            Request::SetItem {
+                key,
+                value,
+                consistency,
+            } => self.write(key, Some(value), consistency),
+            Request::RemoveItem { key, consistency } => self.write(key, None, consistency),
+            Request::GetItem { key, consistency } => self.read(key, consistency),
+            Request::ReplicaSet { key, item } => {
+                self.store.apply(&key, item);
+                Response::Done
+            }
+            Request::ReplicaGet { key } => Response::Item(self.store.get(&key)),
+            Request::SyncTree { range, peer } => {
+                Response::SyncTree(anti_entropy::range_tree(self, range, peer).into_nodes())
+            }
+            Request::SyncKeys {
+                range,
+                leaves,
+                peer,
+            } => Response::SyncKeys(anti_entropy::range_keys(self, range, &leaves, peer)),
+        }
+    }
+
+    fn write(&self, key: String, value: Option<Vec<u8>>, consistency: ConsistencyLevel) -> Response {
+        let item = VersionedValue {
+            version: ItemVersion::now(self.node),
+            value,
+        };
+        let replicas = self.replicas(&key);
+        let required = consistency.required(replicas.len());
+        let request = Request::ReplicaSet {
+            key: key.clone(),
+            item: item.clone(),
+        };
+        let replies = self.fan_out(&replicas, request);
+
+        let mut replied = 0;
+        let mut failed = Vec::new();
+        while replied < required {
+            match replies.recv() {
+                Ok((replica, Ok(Response::Done))) => {
+                    replied += 1;
+                    self.node_alive(replica);
+                }
+                Ok((replica, other)) => {
+                    log::warn!("replica {} failed to store item: {:?}", replica, other);
+                    failed.push(replica);
+                }
+                Err(_) => break,
+            }
+        }
+
+        // replies are still expected or some replicas failed
+        if replied < replicas.len() {
+            self.hinted_handoff(key, item, failed, replies);
+        }
+
+        if replied >= required {
+            Response::Done
+        } else {
+            Response::Unavailable { required, replied }
+        }
+    }
+
+    /// Replays the hints kept for the node without waiting for the next replay round
+    fn node_alive(&self, node: SocketAddr) {
+        if let Some(hints) = self.hints.as_ref() {
+            hints.node_alive(node);
+        }
+    }
+
+    /// Waits for the remaining replies in the background and keeps hints for the failed replicas
+    fn hinted_handoff(
+        &self,
+        key: String,
+        item: VersionedValue,
+        failed: Vec<SocketAddr>,
+        replies: Receiver<Reply>,
+    ) {
+        let hints = match self.hints.as_ref() {
+            Some(hints) => hints.clone(),
+            None => return,
+        };
+        thread::spawn(move || {
+            let late_failed = replies.iter().filter_map(|(replica, reply)| match reply {
+                Ok(Response::Done) => {
+                    hints.node_alive(replica);
+                    None
+                }
+                other => {
+                    log::warn!("replica {} failed to store item: {:?}", replica, other);
+                    Some(replica)
+                }
+            });
+            for replica in failed.into_iter().chain(late_failed) {
+                hints.store(replica, &key, &item);
+            }
+        });
+    }
+
+    fn read(&self, key: String, consistency: ConsistencyLevel) -> Response {
+        let replicas = self.replicas(&key);
+        let required = consistency.required(replicas.len());
+        let replies = self.fan_out(&replicas, Request::ReplicaGet { key: key.clone() });
+
+        let mut items = Vec::<(SocketAddr, Option<VersionedValue>)>::with_capacity(replicas.len());
+        while items.len() < required {
+            match replies.recv() {
+                Ok((replica, Ok(Response::Item(item)))) => items.push((replica, item)),
+                Ok((replica, other)) => log::warn!("replica {} failed to read item: {:?}", replica, other),
+                Err(_) => break,
+            }
+        }
+
+        if items.len() < required {
+            return Response::Unavailable {
+                required,
+                replied: items.len(),
+            };
+        }
+
+        let latest = latest_item(&items);
+        self.read_repair(key, items, replies);
+        Response::Item(latest.filter(|item| item.value.is_some()))
+    }
+
+    /// Waits for the remaining replies in the background and updates the stale replicas
+    fn read_repair(
+        &self,
+        key: String,
+        mut items: Vec<(SocketAddr, Option<VersionedValue>)>,
+        replies: Receiver<Reply>,
+    ) {
+        let membership = self.membership.clone();
+        let store = self.store.clone();
+        thread::spawn(move || {
+            for (replica, reply) in replies.iter() {
+                if let Ok(Response::Item(item)) = reply {
+                    items.push((replica, item));
+                }
+            }
+
+            let latest = match latest_item(&items) {
+                Some(latest) => latest,
+                None => return,
+            };
+
+            for (replica, item) in items {
+                let stale = item.is_none_or(|item| item.version < latest.version);
+                if !stale {
+                    continue;
+                }
+                log::debug!("read repair of {} on replica {}", key, replica);
+                let request = Request::ReplicaSet {
+                    key: key.clone(),
+                    item: latest.clone(),
+                };
+                if let Err(err) = send_to_replica(&membership, &store, replica, request) {
+                    log::warn!("read repair on replica {} failed: {:?}", replica, err);
+                }
+            }
+        });
+    }
+
+    /// Sends the request to the replicas in parallel, replies arrive in order of completion
+    fn fan_out(&self, replicas: &[SocketAddr], request: Request) -> Receiver<Reply> {
+        let (sender, receiver) = mpsc::channel();
+        for replica in replicas {
+            let replica = *replica;
+            let membership = self.membership.clone();
+            let store = self.store.clone();
+            let request = request.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let reply = send_to_replica(&membership, &store, replica, request);
+                // the coordinator may have already replied to the client
+                let _ = sender.send((replica, reply));
+            });
+        }
+        receiver
+    }
+}
+
+/// The local node followed by the configured remote nodes
+fn claster_nodes(config: &Config) -> Vec<SocketAddr> {
+    let listen_on = config.server.as_ref().map(|server| server.listen_on()).unwrap_or_default();
+    let mut nodes = listen_on.iter().take(1).copied().collect::<Vec<_>>();
+    if let Some(remote) = config.remote.as_ref() {
+        for node in &remote.nodes {
+            if !listen_on.contains(node) && !nodes.contains(node) {
+                nodes.push(*node);
+            }
+        }
+    }
+    nodes
+}
+
+fn send_to_replica(
+    membership: &Membership,
+    store: &ItemStore,
+    replica: SocketAddr,
+    request: Request,
+) -> std::io::Result<Response> {
+    if replica == membership.local().addr {
+        return Ok(match request {
+            Request::ReplicaSet { key, item } => {
+                store.apply(&key, item);
+                Response::Done
+            }
+            Request::ReplicaGet { key } => Response::Item(store.get(&key)),
+            _ => Response::Error("not a replica request".to_string()),
+        });
+    }
+    peer_request(membership, replica, &request)
+}
+
+/// Sends a single request to a peer node and waits for the response.
+/// Both nodes present their identity in the handshake, signed with the node token if configured;
+/// the signature of the peer covers the nonce of the local node.
+/// The connection uses TLS if the nodes listen with TLS.
+pub fn peer_request(
+    membership: &Membership,
+    peer: SocketAddr,
+    request: &Request,
+) -> std::io::Result<Response> {
+    let stream = TcpStream::connect_timeout(&peer, PEER_TIMEOUT)?;
+    stream.set_read_timeout(Some(PEER_TIMEOUT))?;
+    stream.set_write_timeout(Some(PEER_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let mut stream: Box<dyn Connection> = match membership.peer_tls() {
+        Some(config) => {
+            let connection = ClientConnection::new(config.clone(), ServerName::from(peer.ip()))
+                .map_err(std::io::Error::other)?;
+            Box::new(StreamOwned::new(connection, stream))
+        }
+        None => Box::new(stream),
+    };
+
+    let credentials = membership.node_credentials();
+    let hello = Request::Hello {
+        node: Some(membership.local().clone()),
+        credentials: credentials.clone(),
+    };
+    write_message(&mut stream, &hello)?;
+    write_message(&mut stream, request)?;
+
+    match read_response(&mut stream)? {
+        Response::Hello { node, credentials: reply } if node.addr == peer => {
+            membership
+                .verify_reply(&node, credentials.as_ref(), reply.as_ref())
+                .map_err(|msg| std::io::Error::new(std::io::ErrorKind::PermissionDenied, msg))?;
+            membership
+                .register(&node)
+                .map_err(|msg| std::io::Error::new(std::io::ErrorKind::InvalidData, msg))?
+        }
+        Response::Hello { node, .. } => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("node {} at {} advertises address {}", node.id, peer, node.addr),
+            ))
+        }
+        Response::Error(msg) => return Err(std::io::Error::other(msg)),
+        Response::Unauthorized(msg) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, msg))
+        }
+        other => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unexpected handshake response: {:?}", other),
+            ))
+        }
+    }
+
+    read_response(&mut stream)
+}
+
+fn read_response(stream: &mut impl Connection) -> std::io::Result<Response> {
+    read_message(stream)?.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "connection closed by peer",
+        )
+    })
+}
+
+fn latest_item(items: &[(SocketAddr, Option<VersionedValue>)]) -> Option<VersionedValue> {
+    items
+        .iter()
+        .filter_map(|(_, item)| item.as_ref())
+        .max_by_key(|item| item.version)
+        .cloned()
+}
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::protocol::message::{ItemVersion, VersionedValue};
+
+/// Items stored on the local node, reconciled by item versions (the last write wins)
+#[derive(Default)]
+pub struct ItemStore {
+    items: RwLock<HashMap<String, VersionedValue>>,
+}
+
+impl ItemStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores the item if it is newer than the local one.
+    /// Returns `false` if the local item is the same or newer.
+    pub fn apply(&self, key: &str, item: VersionedValue) -> bool {
+        let mut items = self.items.write().unwrap();
+        match items.get(key) {
+            Some(current) if current.version >= item.version => false,
+            _ => {
+                items.insert(key.to_string(), item);
+                true
+            }
+        }
+    }
+
+    /// Returns the local item including removed ones (tombstones)
+    pub fn get(&self, key: &str) -> Option<VersionedValue> {
+        self.items.read().unwrap().get(key).cloned()
+    }
+
+    /// Returns the versions of the local items accepted by the filter
+    pub fn versions(&self, filter: impl Fn(&str) -> bool) -> Vec<(String, ItemVersion)> {
+        self.items
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| filter(key))
+            .map(|(key, item)| (key.clone(), item.version))
+            .collect()
+    }
+}
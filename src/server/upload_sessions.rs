@@ -0,0 +1,327 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+/// Resumable upload of a file: its data grows in the staging directory until the session is finalised
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    /// normalised request path of the file
+    pub path: String,
+    /// client id of the creator, none for peer nodes
+    pub owner: Option<String>,
+    /// total length announced by `Upload-Length`
+    pub length: Option<u64>,
+    /// seconds since the Unix epoch, moved on by every chunk
+    pub expires: u64,
+}
+
+impl Session {
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.expires)
+    }
+}
+
+/// Sessions kept as `<id>.session` and `<id>.data` in the staging directory, so that they survive restarts
+#[derive(Debug)]
+pub struct UploadSessions {
+    dir: PathBuf,
+    ttl: Duration,
+    /// sessions of an owner at a time, 0 - unlimited
+    sessions_max: usize,
+    /// sessions taking a chunk, being finalised or removed
+    busy: Mutex<HashSet<String>>,
+    /// sessions are counted and created one at a time
+    creating: tokio::sync::Mutex<()>,
+    random: SystemRandom,
+}
+
+/// Exclusive use of a session, released when dropped
+#[derive(Debug)]
+pub struct Claim<'a> {
+    sessions: &'a UploadSessions,
+    id: String,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.sessions.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl UploadSessions {
+    pub fn new(dir: PathBuf, ttl: Duration, sessions_max: usize) -> Self {
+        UploadSessions {
+            dir,
+            ttl,
+            sessions_max,
+            busy: Mutex::new(HashSet::new()),
+            creating: tokio::sync::Mutex::new(()),
+            random: SystemRandom::new(),
+        }
+    }
+
+    /// New session with empty data.
+    /// Fails with `QuotaExceeded` if the owner has as many sessions as allowed already.
+    pub async fn create(&self, path: &str, owner: Option<String>, length: Option<u64>) -> io::Result<Session> {
+        let _creating = self.creating.lock().await;
+        if self.sessions_max > 0 && self.count(owner.as_deref()).await? >= self.sessions_max {
+            return Err(io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                format!("{} upload sessions at a time", self.sessions_max),
+            ));
+        }
+        let session = Session {
+            id: self.new_id()?,
+            path: path.to_string(),
+            owner,
+            length,
+            expires: unix_seconds(SystemTime::now() + self.ttl),
+        };
+        tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.data_path(&session.id))
+            .await?;
+        self.store(&session).await?;
+        Ok(session)
+    }
+
+    /// Session by id, none if it is unknown or expired
+    pub async fn get(&self, id: &str) -> io::Result<Option<Session>> {
+        // the id becomes part of file names
+        if id.len() != 32 || !id.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+        let source = match tokio::fs::read_to_string(self.session_path(id)).await {
+            Ok(source) => source,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let session: Session =
+            serde_yaml::from_str(&source).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if session.expires <= unix_seconds(SystemTime::now()) {
+            // a session in use is left to its request
+            if let Some(_claim) = self.claim(id) {
+                self.remove(id).await?;
+            }
+            return Ok(None);
+        }
+        Ok(Some(session))
+    }
+
+    /// Unexpired sessions of an owner
+    async fn count(&self, owner: Option<&str>) -> io::Result<usize> {
+        let now = unix_seconds(SystemTime::now());
+        let mut count = 0;
+        let mut read_dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            if entry.path().extension().is_none_or(|extension| extension != "session") {
+                continue;
+            }
+            // the session may have been removed meanwhile
+            let Ok(source) = tokio::fs::read_to_string(entry.path()).await else {
+                continue;
+            };
+            let owned = serde_yaml::from_str::<Session>(&source)
+                .is_ok_and(|session| session.owner.as_deref() == owner && session.expires > now);
+            if owned {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// None while another request uses the session
+    pub fn claim(&self, id: &str) -> Option<Claim<'_>> {
+        self.busy.lock().unwrap().insert(id.to_string()).then(|| Claim {
+            sessions: self,
+            id: id.to_string(),
+        })
+    }
+
+    pub fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.data", id))
+    }
+
+    /// Committed offset: the length of the data
+    pub async fn offset(&self, session: &Session) -> io::Result<u64> {
+        tokio::fs::metadata(self.data_path(&session.id)).await.map(|metadata| metadata.len())
+    }
+
+    /// Moves the expiry on after a chunk
+    pub async fn touch(&self, session: &mut Session) -> io::Result<()> {
+        session.expires = unix_seconds(SystemTime::now() + self.ttl);
+        self.store(session).await
+    }
+
+    /// Removes the session and its data, the data may have been moved into place already
+    pub async fn remove(&self, id: &str) -> io::Result<()> {
+        for path in [self.session_path(id), self.data_path(id)] {
+            match tokio::fs::remove_file(path).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes expired and unreadable sessions, and data or received bodies left behind for longer than the expiry time.
+    /// Sessions in use are skipped. Returns the number of sessions and orphaned files removed.
+    pub async fn sweep(&self) -> io::Result<usize> {
+        let now = SystemTime::now();
+        let mut removed = 0;
+        let mut read_dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            let Some((id, extension)) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split_once('.'))
+            else {
+                continue;
+            };
+            // a chunk may be taken or the session finalised right now
+            let Some(_claim) = self.claim(id) else {
+                continue;
+            };
+            match extension {
+                "session" => {
+                    let expired = match tokio::fs::read_to_string(&path).await {
+                        Ok(source) => serde_yaml::from_str::<Session>(&source)
+                            .map_or(true, |session| session.expires <= unix_seconds(now)),
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+                        Err(err) => return Err(err),
+                    };
+                    if expired {
+                        self.remove(id).await?;
+                        removed += 1;
+                    }
+                }
+                // the session may have been removed along with its data meanwhile
+                "data" if !tokio::fs::try_exists(self.session_path(id)).await? && self.is_stale(&entry, now).await => {
+                    self.remove(id).await?;
+                    removed += 1;
+                }
+                // left by a crash while a body was received or a session stored
+                "upload" | "session.tmp" if self.is_stale(&entry, now).await => match tokio::fs::remove_file(&path).await {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => removed += 1,
+                },
+                _ => {}
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Whether a file was not modified for longer than the expiry time, false if it is gone
+    async fn is_stale(&self, entry: &tokio::fs::DirEntry, now: SystemTime) -> bool {
+        let Ok(metadata) = entry.metadata().await else {
+            return false;
+        };
+        let modified = metadata.modified().unwrap_or(now);
+        now.duration_since(modified).unwrap_or_default() > self.ttl
+    }
+
+    /// 128 random bits from the system, sessions are bound to their owner as well
+    fn new_id(&self) -> io::Result<String> {
+        let mut bytes = [0u8; 16];
+        self.random
+            .fill(&mut bytes)
+            .map_err(|_| io::Error::other("no random numbers available"))?;
+        let mut id = String::with_capacity(32);
+        for byte in bytes {
+            let _ = write!(id, "{:02x}", byte);
+        }
+        Ok(id)
+    }
+
+    fn session_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.session", id))
+    }
+
+    /// Replaces the session file at once, a crash leaves the former one
+    async fn store(&self, session: &Session) -> io::Result<()> {
+        let source = serde_yaml::to_string(session).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let path = self.session_path(&session.id);
+        let temp = path.with_extension("session.tmp");
+        tokio::fs::write(&temp, source).await?;
+        tokio::fs::rename(&temp, &path).await
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Whether a normalised request path lies in the staging directory below the root.
+/// The case is ignored, as file systems may do.
+pub fn is_staging(path: &str, staging_dir: &str) -> bool {
+    let first = path.trim_start_matches('/').split('/').next().unwrap_or_default();
+    first.eq_ignore_ascii_case(staging_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn session_lifecycle() {
+        let dir = std::env::temp_dir().join(format!("poncu-sessions-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sessions = UploadSessions::new(dir.clone(), Duration::from_secs(3600), 2);
+
+        let mut session = sessions.create("/docs/a.iso", Some("app1".into()), Some(10)).await.unwrap();
+        assert_eq!(sessions.get(&session.id).await.unwrap().as_ref(), Some(&session));
+        assert_eq!(sessions.offset(&session).await.unwrap(), 0);
+        std::fs::write(sessions.data_path(&session.id), b"12345").unwrap();
+        assert_eq!(sessions.offset(&session).await.unwrap(), 5);
+        sessions.touch(&mut session).await.unwrap();
+        assert_eq!(sessions.get("../../etc/passwd").await.unwrap(), None);
+        assert_eq!(sessions.get(&"0".repeat(32)).await.unwrap(), None);
+
+        let claim = sessions.claim(&session.id);
+        assert!(claim.is_some());
+        assert!(sessions.claim(&session.id).is_none());
+        drop(claim);
+        assert!(sessions.claim(&session.id).is_some());
+
+        // expired sessions are gone, with their data
+        let expired = UploadSessions::new(dir.clone(), Duration::ZERO, 0);
+        let stale = expired.create("/docs/b.iso", None, None).await.unwrap();
+        // not while it is in use
+        let claim = sessions.claim(&stale.id);
+        assert_eq!(sessions.sweep().await.unwrap(), 0);
+        drop(claim);
+        assert_eq!(sessions.sweep().await.unwrap(), 1);
+        assert_eq!(sessions.get(&stale.id).await.unwrap(), None);
+        assert!(sessions.get(&session.id).await.unwrap().is_some());
+
+        // as are the files left by a crash, once they are older than the expiry time
+        let hour_ago = SystemTime::now() - Duration::from_secs(3601);
+        for name in [format!("{}.session.tmp", stale.id), "17f0c2a90001.upload".to_string()] {
+            let file = std::fs::File::create(dir.join(name)).unwrap();
+            file.set_modified(hour_ago).unwrap();
+        }
+        std::fs::write(dir.join("17f0c2a90002.upload"), b"").unwrap();
+        assert_eq!(sessions.sweep().await.unwrap(), 2);
+        std::fs::remove_file(dir.join("17f0c2a90002.upload")).unwrap();
+
+        sessions.remove(&session.id).await.unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(is_staging("/.uploads", ".uploads"));
+        assert!(is_staging("/.uploads/x.data", ".uploads"));
+        assert!(!is_staging("/.uploads2", ".uploads"));
+        assert!(!is_staging("/docs/.uploads", ".uploads"));
+        assert!(is_staging("/.UPLOADS/x.data", ".uploads"));
+        assert!(!is_staging("/", ".uploads"));
+    }
+}
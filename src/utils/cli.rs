@@ -0,0 +1,197 @@
+use std::path::PathBuf;
+
+use crate::utils::config::{CONFIG_FILE, SECTIONS};
+
+pub const LOG_CONFIG_FILE: &str = "log.yaml";
+
+/// Prefix of the environment variables overriding settings
+pub const ENV_PREFIX: &str = "PONCU_";
+
+pub const USAGE: &str = "\
+Options:
+  --config <path>          configuration file (default: config.yaml, env: PONCU_CONFIG)
+  --log-config <path>      logging configuration file (default: log.yaml, env: PONCU_LOG_CONFIG)
+  --<section>.<key> <value>
+                           overrides a setting, e.g. --server.listen_port 9292,
+                           nested keys are joined with dots, e.g.
+                           --file_server.compression.enabled false
+  --help                   prints this message
+
+Any setting can also be overridden with an environment variable named
+PONCU_<SECTION>_<KEY>, e.g. PONCU_SERVER_LISTEN_PORT=9292, nested keys are
+joined with double underscores, e.g. PONCU_FILE_SERVER_COMPRESSION__ENABLED=false.
+Other variables starting with PONCU_ are ignored with a warning.
+
+Precedence, from the highest: command line flags, environment variables,
+configuration file, built-in defaults.";
+
+/// A setting given outside of the configuration file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    /// the flag or the environment variable, used in error messages
+    pub origin: String,
+    pub section: String,
+    /// nested keys are separated by dots
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub config: PathBuf,
+    pub log_config: PathBuf,
+    /// in the order of application, the later ones win
+    pub overrides: Vec<Override>,
+    /// environment variables with the prefix that name no setting
+    pub ignored: Vec<String>,
+}
+
+impl Options {
+    /// Reads the options of the process, prints usage and exits on `--help` or invalid flags
+    pub fn from_env() -> Options {
+        let vars = std::env::vars().collect::<Vec<_>>();
+        let args = std::env::args().skip(1).collect::<Vec<_>>();
+        match Options::parse(&vars, &args) {
+            Ok(Some(options)) => options,
+            Ok(None) => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("{}\n\n{}", err, USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    /// Parses environment variables and command line arguments.
+    /// Returns `Ok(None)` if help was requested.
+    pub fn parse(vars: &[(String, String)], args: &[String]) -> Result<Option<Options>, String> {
+        let mut options = Options {
+            config: PathBuf::from(CONFIG_FILE),
+            log_config: PathBuf::from(LOG_CONFIG_FILE),
+            overrides: Vec::new(),
+            ignored: Vec::new(),
+        };
+
+        for (name, value) in vars {
+            let setting = match name.strip_prefix(ENV_PREFIX) {
+                Some(setting) => setting.to_ascii_lowercase(),
+                None => continue,
+            };
+            match setting.as_str() {
+                "config" => options.config = PathBuf::from(value),
+                "log_config" => options.log_config = PathBuf::from(value),
+                _ => match split_env_setting(&setting) {
+                    Some((section, key)) => options.overrides.push(Override {
+                        origin: name.clone(),
+                        section: section.to_string(),
+                        key: key.replace("__", "."),
+                        value: value.clone(),
+                    }),
+                    None => options.ignored.push(name.clone()),
+                },
+            }
+        }
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                return Ok(None);
+            }
+
+            let flag = arg
+                .strip_prefix("--")
+                .ok_or_else(|| format!("unexpected argument: {}", arg))?;
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name, value.to_string()),
+                None => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("missing value for {}", arg))?;
+                    (flag, value.clone())
+                }
+            };
+
+            match name {
+                "config" => options.config = PathBuf::from(value),
+                "log-config" => options.log_config = PathBuf::from(value),
+                _ => {
+                    let (section, key) = name
+                        .split_once('.')
+                        .filter(|(section, key)| SECTIONS.contains(section) && is_key_path(key))
+                        .ok_or_else(|| format!("unknown flag: --{}", name))?;
+                    options.overrides.push(Override {
+                        origin: format!("--{}", name),
+                        section: section.to_string(),
+                        key: key.to_string(),
+                        value,
+                    });
+                }
+            }
+        }
+
+        Ok(Some(options))
+    }
+}
+
+/// Splits `file_server_listen_port` into the longest matching section and the key
+fn split_env_setting(setting: &str) -> Option<(&str, &str)> {
+    SECTIONS
+        .iter()
+        .filter_map(|section| {
+            setting
+                .strip_prefix(section)
+                .and_then(|rest| rest.strip_prefix('_'))
+                .filter(|key| is_key_path(&key.replace("__", ".")))
+                .map(|key| (*section, key))
+        })
+        .max_by_key(|(section, _)| section.len())
+}
+
+/// `key` or `nested.key`, without empty keys
+fn is_key_path(path: &str) -> bool {
+    path.split('.').all(|key| !key.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn flags_and_environment() {
+        let vars = vec![
+            ("PONCU_LOG_CONFIG".to_string(), "node2-log.yaml".to_string()),
+            ("PONCU_FILE_SERVER_LISTEN_PORT".to_string(), "8282".to_string()),
+            ("PONCU_FILE_SERVER_COMPRESSION__MIN_SIZE".to_string(), "1K".to_string()),
+            ("PONCU_VERSION".to_string(), "1".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let args = strings(&["--config", "node2.yaml", "--server.listen_port=9292", "--file_server.tls.cert_file", "a.pem"]);
+        let options = Options::parse(&vars, &args).unwrap().unwrap();
+
+        assert_eq!(options.config, PathBuf::from("node2.yaml"));
+        assert_eq!(options.log_config, PathBuf::from("node2-log.yaml"));
+        assert_eq!(options.overrides.len(), 4);
+        assert_eq!(options.overrides[0].section, "file_server");
+        assert_eq!(options.overrides[0].key, "listen_port");
+        assert_eq!(options.overrides[1].key, "compression.min_size");
+        assert_eq!(options.overrides[2].origin, "--server.listen_port");
+        assert_eq!(options.overrides[2].value, "9292");
+        assert_eq!(options.overrides[3].key, "tls.cert_file");
+        assert_eq!(options.ignored, ["PONCU_VERSION"]);
+    }
+
+    #[test]
+    fn invalid_flags() {
+        assert!(Options::parse(&[], &strings(&["--unknown.key", "1"])).is_err());
+        assert!(Options::parse(&[], &strings(&["--server.listen_port"])).is_err());
+        assert!(Options::parse(&[], &strings(&["--server.tls..cert_file", "a.pem"])).is_err());
+        assert!(Options::parse(&[], &strings(&["config.yaml"])).is_err());
+        assert_eq!(Options::parse(&[], &strings(&["--help"])), Ok(None));
+    }
+}
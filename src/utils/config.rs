@@ -1,135 +1,1086 @@
+use serde::{Deserialize, Deserializer};
+use serde_yaml::{Mapping, Value};
 use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Debug)]
+use crate::utils::cli::{Options, Override};
+
+pub const CONFIG_FILE: &str = "config.yaml";
+
+/// Top level sections of the configuration file
+pub const SECTIONS: [&str; 10] = [
+    "server",
+    "file_server",
+    "remote",
+    "client",
+    "redundancy",
+    "anti_entropy",
+    "hinted_handoff",
+    "auth",
+    "acl",
+    "limits",
+];
+
+const LISTEN_PORT_DEFAULT: u16 = 7311;
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub server: Option<Server>,
     pub file_server: Option<FileServer>,
     pub remote: Option<Remote>,
+    pub client: Option<Client>,
+    pub redundancy: Option<Redundancy>,
+    pub anti_entropy: Option<AntiEntropy>,
+    pub hinted_handoff: Option<HintedHandoff>,
+    pub auth: Option<Auth>,
+    pub acl: Option<Acl>,
+    pub limits: Option<Limits>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Server {
-    pub listen_on: Vec<SocketAddr>,
+    /// unique node id in the claster, defaults to the first listen address
+    pub id: String,
+    /// defaults to the node id
+    pub name: String,
+    pub description: String,
+    /// SHA-256 of the access token accepted from any client, see also the `auth` section
+    pub access_token: Option<TokenHash>,
+    /// comma separated list or sequence of IP addresses
+    #[serde(deserialize_with = "comma_separated")]
+    pub listen_addresses: Vec<IpAddr>,
+    pub listen_port: u16,
+    /// path of an additional Unix domain socket for local clients
+    pub unix_socket: Option<PathBuf>,
+    /// TLS on the TCP listeners and to the peer nodes, the Unix domain socket stays plain
+    pub tls: Option<ServerTls>,
+    pub connections_max: usize,
+    pub threads_max: usize,
+    pub ram_max: ByteSize,
+    pub disk_max: ByteSize,
+    pub disk_root: PathBuf,
 }
 
-#[derive(Debug)]
+impl Server {
+    pub fn listen_on(&self) -> Vec<SocketAddr> {
+        listen_on(&self.listen_addresses, self.listen_port)
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            id: String::new(),
+            name: String::new(),
+            description: String::new(),
+            access_token: None,
+            listen_addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            listen_port: LISTEN_PORT_DEFAULT,
+            unix_socket: None,
+            tls: None,
+            connections_max: 20,
+            threads_max: 4,
+            ram_max: ByteSize(512 * 1024 * 1024),
+            disk_max: ByteSize(2 * 1024 * 1024 * 1024),
+            disk_root: PathBuf::from("/var/poncu"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct FileServer {
-    pub listen_on: Vec<SocketAddr>,
+    /// comma separated list or sequence of IP addresses
+    #[serde(deserialize_with = "comma_separated")]
+    pub listen_addresses: Vec<IpAddr>,
+    pub listen_port: u16,
+    /// path of an additional Unix domain socket for local clients
+    pub unix_socket: Option<PathBuf>,
+    /// directory of the served files, symbolic links may not lead out of it
+    pub root: PathBuf,
+    /// also take the requested byte range from the `Content-Range` header, as sent by earlier clients
+    pub content_range_requests: bool,
+    /// merge adjacent ranges of a request before sending them, overlapping ones are always merged
+    pub coalesce_ranges: bool,
+    /// content types by file extension, added to the built-in table or replacing its entries
+    pub mime_types: HashMap<String, String>,
+    /// guess the type of files without a known extension from their first bytes
+    pub sniff_content_type: bool,
+    pub compression: Compression,
+    pub directories: Directories,
+    pub uploads: Uploads,
+    /// HTTPS on the TCP listeners
+    pub tls: Option<ServerTls>,
 }
 
-#[derive(Debug)]
+impl FileServer {
+    pub fn listen_on(&self) -> Vec<SocketAddr> {
+        listen_on(&self.listen_addresses, self.listen_port)
+    }
+}
+
+impl Default for FileServer {
+    fn default() -> Self {
+        FileServer {
+            listen_addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            listen_port: LISTEN_PORT_DEFAULT,
+            unix_socket: None,
+            root: PathBuf::from("."),
+            content_range_requests: false,
+            coalesce_ranges: true,
+            mime_types: HashMap::new(),
+            sniff_content_type: true,
+            compression: Compression::default(),
+            directories: Directories::default(),
+            uploads: Uploads::default(),
+            tls: None,
+        }
+    }
+}
+
+/// Compression of the file server responses, negotiated by `Accept-Encoding`: brotli, zstd and gzip
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Compression {
+    /// compress files of compressible types on the fly
+    pub enabled: bool,
+    /// smaller files are sent as they are
+    pub min_size: ByteSize,
+    /// larger files are not compressed on the fly but sent as they are, 0 - unlimited
+    pub max_size: ByteSize,
+    /// send `.br`, `.zst` and `.gz` files next to a file instead, if they are not older
+    pub precompressed: bool,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            enabled: true,
+            min_size: ByteSize(1024),
+            max_size: ByteSize(64 * 1024 * 1024),
+            precompressed: true,
+        }
+    }
+}
+
+/// Requests for directories of the file server
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Directories {
+    /// file sent for a directory if it exists there
+    pub index_file: Option<String>,
+    /// list the entries of directories without an index file, as HTML or JSON
+    pub listing: bool,
+    /// entries on a page of a listing
+    pub page_size: usize,
+}
+
+impl Default for Directories {
+    fn default() -> Self {
+        Directories {
+            index_file: Some("index.html".to_string()),
+            listing: false,
+            page_size: 100,
+        }
+    }
+}
+
+/// Writes to the file server by PUT and DELETE, for callers authenticated by JWT
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Uploads {
+    pub enabled: bool,
+    /// larger request bodies are refused with 413, resumable uploads are limited in total
+    pub max_size: ByteSize,
+    /// directory below the root holding the data of resumable uploads, it is not served
+    pub staging_dir: String,
+    /// resumable uploads without a chunk for this long are removed, in seconds
+    #[serde(deserialize_with = "seconds")]
+    pub session_ttl: Duration,
+    /// resumable uploads of a client at a time, 0 - unlimited
+    pub sessions_max: usize,
+}
+
+impl Default for Uploads {
+    fn default() -> Self {
+        Uploads {
+            enabled: false,
+            max_size: ByteSize(1 << 30),
+            staging_dir: ".uploads".to_string(),
+            session_ttl: Duration::from_secs(24 * 60 * 60),
+            sessions_max: 16,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Remote {
+    /// comma separated list or sequence of socket addresses
+    #[serde(deserialize_with = "comma_separated")]
     pub nodes: Vec<SocketAddr>,
 }
 
-pub fn get_config() -> Arc<Config> {
-    let config_file = std::fs::File::open("config.yaml").expect("Could not open config file.");
-    let config_map: HashMap<String, HashMap<String, String>> =
-        serde_yaml::from_reader(config_file).expect("Could not parse config file.");
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Client {
+    pub id: String,
+    pub name: String,
+    pub access_token: Option<String>,
+    /// JWT presented instead of the access token
+    pub bearer_token: Option<String>,
+    /// TLS to the remote nodes and the file servers
+    pub tls: Option<ClientTls>,
+}
 
-    if log::log_enabled!(log::Level::Trace) {
-        log::trace!("loaded config:\n{:#?}", config_map);
+/// Certificate and key of a server, files in PEM format
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTls {
+    /// certificate chain, starting with the certificate of the server
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// CA certificates verifying the client certificates and the certificates of the peer nodes
+    pub ca_file: Option<PathBuf>,
+    #[serde(default)]
+    pub client_certs: ClientCerts,
+}
+
+/// Verification of the client certificates by a server
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientCerts {
+    /// not requested
+    #[default]
+    Ignored,
+    /// verified if presented
+    Optional,
+    /// connections without a valid client certificate are refused
+    Required,
+}
+
+/// TLS settings of the clients, files in PEM format
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientTls {
+    /// CA certificates verifying the servers, defaults to the Mozilla root certificates
+    pub ca_file: Option<PathBuf>,
+    /// client certificate, presented to servers verifying them
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    /// name in the certificates of the servers, defaults to the IP address of the node
+    pub server_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedundancyStrategy {
+    /// replicate item on `replica_min` nodes
+    Normal,
+    /// replicate item on `replica_max` nodes if there are enough resources
+    Maximum,
+    /// replicate item on all nodes if there are enough resources
+    Paranoid,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Redundancy {
+    pub strategy: RedundancyStrategy,
+    pub replica_min: usize,
+    pub replica_max: usize,
+}
+
+impl Redundancy {
+    /// Returns the number of replicas for an item in a claster of `nodes_count` nodes
+    pub fn replicas_count(&self, nodes_count: usize) -> usize {
+        let replicas = match self.strategy {
+            RedundancyStrategy::Normal => self.replica_min,
+            RedundancyStrategy::Maximum => self.replica_max,
+            RedundancyStrategy::Paranoid => nodes_count,
+        };
+        replicas.clamp(1, nodes_count.max(1))
     }
+}
 
-    let mut config = Config {
-        server: None,
-        file_server: None,
-        remote: None,
-    };
+impl Default for Redundancy {
+    fn default() -> Self {
+        Redundancy {
+            strategy: RedundancyStrategy::Normal,
+            replica_min: 1,
+            replica_max: 1,
+        }
+    }
+}
 
-    let map_key = "server";
-    if config_map.contains_key(map_key) {
-        let config_node = &config_map[map_key];
-        let listen_on = parse_listen_on(config_node);
-        config.server = Some(Server{listen_on});
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AntiEntropy {
+    pub enabled: bool,
+    /// pause between synchronisation rounds, in seconds
+    #[serde(deserialize_with = "seconds")]
+    pub interval: Duration,
+    /// number of key ranges, each one is compared with its own hash tree
+    pub ranges: u32,
+    /// bytes per second, 0 - unlimited
+    pub bandwidth_max: ByteSize,
+    /// percent of a single CPU core
+    pub cpu_max: u8,
+}
+
+impl Default for AntiEntropy {
+    fn default() -> Self {
+        AntiEntropy {
+            enabled: true,
+            interval: Duration::from_secs(60),
+            ranges: 16,
+            bandwidth_max: ByteSize(0),
+            cpu_max: 100,
+        }
     }
+}
 
-    let map_key = "file_server";
-    if config_map.contains_key(map_key) {
-        let config_node = &config_map[map_key];
-        let listen_on = parse_listen_on(config_node);
-        config.file_server = Some(FileServer{listen_on});
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HintedHandoff {
+    pub enabled: bool,
+    /// directory for the hints of unreachable replicas
+    pub directory: PathBuf,
+    /// upper limit for the hints of all nodes
+    pub size_max: ByteSize,
+    /// hints older than this are dropped, in seconds
+    #[serde(deserialize_with = "seconds")]
+    pub age_max: Duration,
+    /// pause between attempts to replay hints, in seconds
+    #[serde(deserialize_with = "seconds")]
+    pub replay_interval: Duration,
+}
+
+impl Default for HintedHandoff {
+    fn default() -> Self {
+        HintedHandoff {
+            enabled: true,
+            directory: PathBuf::from("/var/poncu/hints"),
+            size_max: ByteSize(64 * 1024 * 1024),
+            age_max: Duration::from_secs(3 * 60 * 60),
+            replay_interval: Duration::from_secs(10),
+        }
     }
+}
 
-    let map_key = "remote";
-    if config_map.contains_key(map_key) {
-        let config_node = &config_map[map_key];
-        let remote = parse_remote(config_node);
-        config.remote = Some(remote);
+/// Limits of the clients, 0 - unlimited. Peer nodes are not limited.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// requests per second of a client id
+    pub client_requests: u32,
+    /// requests per second from an IP address
+    pub ip_requests: u32,
+    /// bytes per second of a client id, values sent and received
+    pub client_bandwidth: ByteSize,
+    /// bytes per second from an IP address
+    pub ip_bandwidth: ByteSize,
+    /// items stored by a client id
+    pub client_items_max: u64,
+    /// bytes of the values stored by a client id
+    pub client_bytes_max: ByteSize,
+    /// file keeping the storage counted for the quotas across restarts, counted in memory only if unset
+    pub usage_file: Option<PathBuf>,
+}
+
+/// Authentication of the clients and of the peer nodes
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    /// clients with their own access tokens
+    pub clients: Vec<ClientAuth>,
+    /// bearer tokens issued by an identity provider
+    pub jwt: Option<Jwt>,
+    /// secret shared by all nodes of the claster, peers sign their handshakes with it
+    pub node_token: Option<Secret>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuth {
+    pub id: String,
+    /// SHA-256 of the access token of the client
+    pub token_sha256: TokenHash,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Jwt {
+    /// HS256, HS384, HS512, RS256, RS384, RS512, PS256, PS384, PS512, ES256, ES384 or EdDSA
+    pub algorithm: jsonwebtoken::Algorithm,
+    /// the secret for the HMAC algorithms, the public key in PEM format for the others
+    pub key_file: PathBuf,
+    pub issuer: String,
+    pub audience: String,
+    /// allowed clock difference when checking the expiry, in seconds
+    #[serde(default = "jwt_leeway", deserialize_with = "seconds")]
+    pub leeway: Duration,
+}
+
+fn jwt_leeway() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Access control, everything is allowed to everybody without rules
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Acl {
+    /// grants are added up, anything not granted is denied
+    pub rules: Vec<AclRule>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclRule {
+    /// client ids, `*` - any client including unauthenticated ones
+    #[serde(deserialize_with = "comma_separated")]
+    pub clients: Vec<String>,
+    #[serde(deserialize_with = "comma_separated")]
+    pub roles: Vec<String>,
+    /// item keys, `prefix*` - keys starting with the prefix
+    #[serde(deserialize_with = "comma_separated")]
+    pub items: Vec<String>,
+    /// file server paths, a folder covers everything beneath it
+    #[serde(deserialize_with = "comma_separated")]
+    pub paths: Vec<String>,
+    #[serde(deserialize_with = "comma_separated")]
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+    /// node status and administration, not bound to items or paths
+    Admin,
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Permission::Read),
+            "write" => Ok(Permission::Write),
+            "delete" => Ok(Permission::Delete),
+            "admin" => Ok(Permission::Admin),
+            _ => Err(format!("unknown permission: {:?}", s)),
+        }
     }
+}
 
-    if log::log_enabled!(log::Level::Trace) {
-        log::trace!("parsed config:\n{:#?}", config);
+/// SHA-256 digest written as 64 hex digits, only digests of secrets are kept in the configuration
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TokenHash(pub [u8; 32]);
+
+impl fmt::Debug for TokenHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TokenHash(..)")
+    }
+}
+
+impl FromStr for TokenHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || "expected a SHA-256 digest as 64 hex digits".to_string();
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let mut hash = [0u8; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(TokenHash(hash))
     }
+}
 
-    Arc::new(config)
+impl<'de> Deserialize<'de> for TokenHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
 }
 
-fn parse_listen_on(node: &HashMap<String, String>) -> Vec::<SocketAddr> {
-    let node_key = "listen_addresses";
-    let listen_addresses = if node.contains_key(node_key) {
-        node[node_key]
-            .split(',')
-            .map(|s| s.trim())
-            .collect::<Vec<_>>()
-    } else {
-        vec!["127.0.0.1"]
+/// Secret kept in plain text, hidden from debug output
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// Size in bytes, accepts plain numbers and units: `512K`, `512M`, `2G`, `1T`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    pub fn bytes(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(digits);
+        let number: u64 = number
+            .parse()
+            .map_err(|_| format!("invalid size: {:?}", s))?;
+
+        let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+            "" | "B" => 1,
+            "K" | "KB" | "KIB" => 1 << 10,
+            "M" | "MB" | "MIB" => 1 << 20,
+            "G" | "GB" | "GIB" => 1 << 30,
+            "T" | "TB" | "TIB" => 1 << 40,
+            _ => return Err(format!("invalid size unit: {:?}", s)),
+        };
+
+        number
+            .checked_mul(multiplier)
+            .map(ByteSize)
+            .ok_or_else(|| format!("size is too large: {:?}", s))
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Text(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(bytes) => Ok(ByteSize(bytes)),
+            Raw::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Configuration error with the location of the offending setting
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: PathBuf,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file.display(), line, self.message),
+            None => write!(f, "{}: {}", self.file.display(), self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Loads the configuration file given in the options and applies the overrides
+pub fn get_config(options: &Options) -> Result<Arc<Config>, ConfigError> {
+    for name in &options.ignored {
+        log::warn!("ignored environment variable {}: no such setting", name);
+    }
+    load_config(&options.config, &options.overrides).map(Arc::new)
+}
+
+pub fn load_config(path: &Path, overrides: &[Override]) -> Result<Config, ConfigError> {
+    let source = std::fs::read_to_string(path).map_err(|err| ConfigError {
+        file: path.to_path_buf(),
+        line: None,
+        message: format!("could not read config file: {}", err),
+    })?;
+    parse_config(&source, path, overrides)
+}
+
+/// Parses the configuration, applies the overrides in order and validates the result.
+/// `path` is used in error messages only.
+pub fn parse_config(
+    source: &str,
+    path: &Path,
+    overrides: &[Override],
+) -> Result<Config, ConfigError> {
+    let yaml_error = |err: serde_yaml::Error| {
+        let message = err.to_string();
+        // the location is reported separately
+        let message = match message.rfind(" at line ") {
+            Some(position) => message[..position].to_string(),
+            None => message,
+        };
+        ConfigError {
+            file: path.to_path_buf(),
+            line: err.location().map(|location| location.line()),
+            message,
+        }
     };
 
+    // the file alone is checked first, so that its errors point to the line
+    let mut config: Config = serde_yaml::from_str(source).map_err(yaml_error)?;
+
+    if !overrides.is_empty() {
+        let mut value: Value = serde_yaml::from_str(source).map_err(yaml_error)?;
+        for setting in overrides {
+            let override_error = |message: String| ConfigError {
+                file: path.to_path_buf(),
+                line: None,
+                message: format!("{}={}: {}", setting.origin, setting.value, message),
+            };
+            // the value is taken as a string if its YAML type does not fit the setting, e.g. a numeric id
+            let typed = serde_yaml::from_str(&setting.value).unwrap_or_else(|_| Value::String(setting.value.clone()));
+            let mut candidate = value.clone();
+            apply_override(&mut candidate, setting, typed.clone()).map_err(override_error)?;
+            config = match serde_yaml::from_value(candidate.clone()) {
+                Ok(config) => {
+                    value = candidate;
+                    config
+                }
+                Err(err) if !typed.is_string() => {
+                    apply_override(&mut value, setting, Value::String(setting.value.clone())).map_err(override_error)?;
+                    serde_yaml::from_value(value.clone()).map_err(|_| override_error(err.to_string()))?
+                }
+                Err(err) => return Err(override_error(err.to_string())),
+            };
+        }
+    }
+
+    config.validate().map_err(|(section, key, message)| {
+        let message = format!("{}.{}: {}", section, key, message);
+        let setting = overrides
+            .iter()
+            .rev()
+            .find(|setting| {
+                setting.section == section
+                    && (setting.key == key || setting.key.strip_prefix(key).is_some_and(|rest| rest.starts_with('.')))
+            });
+        match setting {
+            Some(setting) => ConfigError {
+                file: path.to_path_buf(),
+                line: None,
+                message: format!("{}={}: {}", setting.origin, setting.value, message),
+            },
+            None => ConfigError {
+                file: path.to_path_buf(),
+                line: find_line(source, section, key),
+                message,
+            },
+        }
+    })?;
+
     if log::log_enabled!(log::Level::Trace) {
-        log::trace!("config: listen_addresses: {:?}", listen_addresses);
+        log::trace!("parsed config:\n{:#?}", config);
     }
 
-    let node_key = "listen_port";
-    let mut port: u16 = 7311;
-    if node.contains_key(node_key) {
-        port = node[node_key].parse().unwrap();
+    Ok(config)
+}
+
+/// Sets `section.key` in the parsed file, nested keys are separated by dots.
+/// Missing sections and mappings are created.
+fn apply_override(root: &mut Value, setting: &Override, value: Value) -> Result<(), String> {
+    let mut path = setting.key.split('.').collect::<Vec<_>>();
+    path.insert(0, &setting.section);
+    let last = path.pop().ok_or("empty key")?;
+
+    let mut mapping = root;
+    for (depth, key) in path.iter().enumerate() {
+        if mapping.is_null() {
+            *mapping = Value::Mapping(Mapping::new());
+        }
+        mapping = mapping
+            .as_mapping_mut()
+            .ok_or_else(|| format!("{} is not a mapping", path[..depth].join(".")))?
+            .entry(Value::String(key.to_string()))
+            .or_insert(Value::Null);
+    }
+    if mapping.is_null() {
+        *mapping = Value::Mapping(Mapping::new());
     }
+    mapping
+        .as_mapping_mut()
+        .ok_or_else(|| format!("{} is not a mapping", path.join(".")))?
+        .insert(Value::String(last.to_string()), value);
+    Ok(())
+}
 
-    if log::log_enabled!(log::Level::Trace) {
-        log::trace!("config: listen_port: {}", port);
+impl Config {
+    /// Checks the values and fills the defaults depending on other settings.
+    /// Errors name the section and the key of the invalid setting.
+    fn validate(&mut self) -> Result<(), (&'static str, &'static str, String)> {
+        if let Some(server) = self.server.as_mut() {
+            check_addresses(&server.listen_addresses)
+                .map_err(|msg| ("server", "listen_addresses", msg))?;
+            if server.id.trim().is_empty() {
+                server.id = server.listen_on()[0].to_string();
+            }
+            if server.name.is_empty() {
+                server.name = server.id.clone();
+            }
+            check_server_tls(server.tls.as_ref()).map_err(|msg| ("server", "tls", msg))?;
+        }
+
+        if let Some(file_server) = self.file_server.as_ref() {
+            check_addresses(&file_server.listen_addresses)
+                .map_err(|msg| ("file_server", "listen_addresses", msg))?;
+            check_server_tls(file_server.tls.as_ref()).map_err(|msg| ("file_server", "tls", msg))?;
+            check_mime_types(&file_server.mime_types).map_err(|msg| ("file_server", "mime_types", msg))?;
+            check_directories(&file_server.directories).map_err(|msg| ("file_server", "directories", msg))?;
+            let jwt = self.auth.as_ref().is_some_and(|auth| auth.jwt.is_some());
+            if file_server.uploads.enabled && !jwt {
+                return Err(("file_server", "uploads", "uploads require the jwt settings of the auth section".into()));
+            }
+            check_uploads(&file_server.uploads).map_err(|msg| ("file_server", "uploads", msg))?;
+        }
+
+        if let Some(tls) = self.client.as_ref().and_then(|client| client.tls.as_ref()) {
+            if tls.cert_file.is_some() != tls.key_file.is_some() {
+                return Err(("client", "tls", "cert_file and key_file go together".into()));
+            }
+        }
+
+        // peer nodes are trusted only by the signatures of their handshakes or by their certificates
+        let listen_on = self.server.as_ref().map(Server::listen_on).unwrap_or_default();
+        let peers = self.remote.as_ref().is_some_and(|remote| {
+            remote.nodes.iter().any(|node| !listen_on.contains(node))
+        });
+        let node_token = self.auth.as_ref().is_some_and(|auth| auth.node_token.is_some());
+        let mutual_tls = self
+            .server
+            .as_ref()
+            .and_then(|server| server.tls.as_ref())
+            .is_some_and(|tls| tls.ca_file.is_some() && tls.client_certs != ClientCerts::Ignored);
+        if peers && !node_token && !mutual_tls {
+            return Err((
+                "auth",
+                "node_token",
+                "required once remote nodes are configured, unless the nodes verify their certificates".into(),
+            ));
+        }
+
+        if let Some(auth) = self.auth.as_ref() {
+            if auth.node_token.as_ref().is_some_and(|token| token.0.len() < 16) {
+                return Err(("auth", "node_token", "must be at least 16 characters".into()));
+            }
+            for (i, client) in auth.clients.iter().enumerate() {
+                if client.id.trim().is_empty() {
+                    return Err(("auth", "clients", "client id must not be empty".into()));
+                }
+                if auth.clients[..i].iter().any(|other| other.id == client.id) {
+                    return Err(("auth", "clients", format!("duplicate client id {}", client.id)));
+                }
+            }
+        }
+
+        if let Some(acl) = self.acl.as_ref() {
+            for (i, rule) in acl.rules.iter().enumerate() {
+                if rule.clients.is_empty() && rule.roles.is_empty() {
+                    return Err(("acl", "rules", format!("rule {}: no clients or roles", i + 1)));
+                }
+                if rule.permissions.is_empty() {
+                    return Err(("acl", "rules", format!("rule {}: no permissions", i + 1)));
+                }
+            }
+        }
+
+        if let Some(redundancy) = self.redundancy.as_ref() {
+            if redundancy.replica_min == 0 {
+                return Err(("redundancy", "replica_min", "must be positive".into()));
+            }
+            if redundancy.replica_max < redundancy.replica_min {
+                return Err((
+                    "redundancy",
+                    "replica_max",
+                    format!("must not be less than replica_min ({})", redundancy.replica_min),
+                ));
+            }
+        }
+
+        if let Some(hinted_handoff) = self.hinted_handoff.as_ref() {
+            if hinted_handoff.replay_interval.is_zero() {
+                return Err(("hinted_handoff", "replay_interval", "must be positive".into()));
+            }
+        }
+
+        if let Some(anti_entropy) = self.anti_entropy.as_ref() {
+            if anti_entropy.interval.is_zero() {
+                return Err(("anti_entropy", "interval", "must be positive".into()));
+            }
+            if anti_entropy.ranges == 0 {
+                return Err(("anti_entropy", "ranges", "must be positive".into()));
+            }
+            if !(1..=100).contains(&anti_entropy.cpu_max) {
+                return Err(("anti_entropy", "cpu_max", "must be in range 1..=100".into()));
+            }
+        }
+
+        Ok(())
     }
+}
 
-    let mut listen_on = Vec::<SocketAddr>::with_capacity(listen_addresses.len());
-    for listen_addres in listen_addresses {
-        let ip_address: IpAddr = listen_addres.parse().unwrap();
-        let socket_addres = SocketAddr::new(ip_address, port);
-        listen_on.push(socket_addres);
+/// Returns the line of `key` inside of the top level `section`
+fn find_line(source: &str, section: &str, key: &str) -> Option<usize> {
+    let mut in_section = false;
+    let mut section_line = None;
+    for (index, line) in source.lines().enumerate() {
+        let indented = line.starts_with(' ') || line.starts_with('\t');
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if !indented {
+            in_section = trimmed.strip_suffix(':') == Some(section)
+                || trimmed.starts_with(&format!("{}:", section));
+            if in_section {
+                section_line = Some(index + 1);
+            }
+        } else if in_section && trimmed.starts_with(&format!("{}:", key)) {
+            return Some(index + 1);
+        }
     }
+    section_line
+}
 
-    if log::log_enabled!(log::Level::Trace) {
-        log::trace!("parsed: listen_on: {:?}", listen_on);
+fn check_addresses(addresses: &[IpAddr]) -> Result<(), String> {
+    if addresses.is_empty() {
+        return Err("must not be empty".into());
+    }
+    for (i, address) in addresses.iter().enumerate() {
+        if addresses[..i].contains(address) {
+            return Err(format!("duplicate address {}", address));
+        }
+    }
+    Ok(())
+}
+
+fn check_server_tls(tls: Option<&ServerTls>) -> Result<(), String> {
+    match tls {
+        Some(tls) if tls.client_certs != ClientCerts::Ignored && tls.ca_file.is_none() => {
+            Err("ca_file is required to verify client certificates".into())
+        }
+        _ => Ok(()),
     }
+}
 
-    listen_on
+/// Types are sent as header values: `type/subtype` with optional parameters, printable ASCII only
+fn check_mime_types(mime_types: &HashMap<String, String>) -> Result<(), String> {
+    for (extension, mime_type) in mime_types {
+        let essence = mime_type.split(';').next().unwrap_or_default().trim();
+        let valid = essence.split_once('/').is_some_and(|(kind, subtype)| !kind.is_empty() && !subtype.is_empty())
+            && mime_type.bytes().all(|byte| (0x20..0x7f).contains(&byte));
+        if !valid {
+            return Err(format!("invalid type for {}: {}", extension, mime_type));
+        }
+    }
+    Ok(())
 }
 
-fn parse_remote(node: &HashMap<String, String>) -> Remote {
-    let node_key = "nodes";
-    let remote_nodes = if node.contains_key(node_key) {
-        node[node_key]
+fn check_directories(directories: &Directories) -> Result<(), String> {
+    if let Some(index_file) = directories.index_file.as_deref() {
+        if !is_file_name(index_file) {
+            return Err(format!("index_file must be a file name: {:?}", index_file));
+        }
+    }
+    if directories.page_size == 0 {
+        return Err("page_size must be positive".into());
+    }
+    Ok(())
+}
+
+fn check_uploads(uploads: &Uploads) -> Result<(), String> {
+    if !is_file_name(&uploads.staging_dir) {
+        return Err(format!("staging_dir must be a directory name: {:?}", uploads.staging_dir));
+    }
+    if uploads.session_ttl.is_zero() {
+        return Err("session_ttl must be positive".into());
+    }
+    Ok(())
+}
+
+/// A single path component
+fn is_file_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['/', '\\', '\0']) && name != "." && name != ".."
+}
+
+fn listen_on(addresses: &[IpAddr], port: u16) -> Vec<SocketAddr> {
+    addresses
+        .iter()
+        .map(|address| SocketAddr::new(*address, port))
+        .collect()
+}
+
+/// Accepts a comma separated string as well as a sequence
+fn comma_separated<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Text(String),
+        List(Vec<String>),
+    }
+
+    let items = match Raw::deserialize(deserializer)? {
+        Raw::Text(text) => text
             .split(',')
-            .map(|s| s.trim())
-            .collect::<Vec<_>>()
-    } else {
-        vec![]
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        Raw::List(list) => list,
     };
 
-    if log::log_enabled!(log::Level::Trace) {
-        log::trace!("config: remote nodes: {:?}", remote_nodes);
+    items
+        .iter()
+        .map(|item| {
+            item.parse()
+                .map_err(|err| serde::de::Error::custom(format!("{:?}: {}", item, err)))
+        })
+        .collect()
+}
+
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<Config, ConfigError> {
+        parse_config(source, Path::new("test.yaml"), &[])
     }
 
-    let mut nodes = Vec::<SocketAddr>::with_capacity(remote_nodes.len());
-    for node in remote_nodes {
-        let socket_addr: SocketAddr = node.parse().unwrap();
-        nodes.push(socket_addr);
+    fn setting(origin: &str, section: &str, key: &str, value: &str) -> Override {
+        Override {
+            origin: origin.to_string(),
+            section: section.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+        }
     }
 
-    if log::log_enabled!(log::Level::Trace) {
-        log::trace!("parsed: remote nodes: {:?}", nodes);
+    #[test]
+    fn sizes_with_units() {
+        assert_eq!("512".parse::<ByteSize>().unwrap(), ByteSize(512));
+        assert_eq!("512M".parse::<ByteSize>().unwrap(), ByteSize(512 << 20));
+        assert_eq!("2G".parse::<ByteSize>().unwrap(), ByteSize(2 << 30));
+        assert_eq!("64kb".parse::<ByteSize>().unwrap(), ByteSize(64 << 10));
+        assert!("2X".parse::<ByteSize>().is_err());
+        assert!("G".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn sample_config_is_valid() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(CONFIG_FILE);
+        if let Err(err) = load_config(&path, &[]) {
+            panic!("{}", err);
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_line() {
+        let err = parse("server:\n  listen_port: 9191\n  listen_prot: 9191\n").unwrap_err();
+        assert_eq!(err.line, Some(3));
+        assert!(err.message.contains("listen_prot"), "{}", err);
+        assert!(err.to_string().starts_with("test.yaml:3: "), "{}", err);
     }
 
-    Remote { nodes }
+    #[test]
+    fn invalid_values_are_reported_with_line() {
+        let err = parse("server:\n  listen_addresses: 127.0.0.1, localhost\n").unwrap_err();
+        assert_eq!(err.line, Some(2));
+
+        let source = "redundancy:\n  replica_min: 3\n  replica_max: 2\n";
+        let err = parse(source).unwrap_err();
+        assert_eq!(err.line, Some(3));
+        assert!(err.message.starts_with("redundancy.replica_max"), "{}", err);
+
+        let err = parse("anti_entropy:\n  enabled: true\n  interval: 0\n").unwrap_err();
+        assert_eq!(err.line, Some(3));
+        assert!(err.message.starts_with("anti_entropy.interval"), "{}", err);
+
+        let err = parse("hinted_handoff:\n  replay_interval: 0\n").unwrap_err();
+        assert_eq!(err.line, Some(2));
+        assert!(err.message.starts_with("hinted_handoff.replay_interval"), "{}", err);
+    }
+
+    #[test]
+    fn remote_nodes_need_a_node_token() {
+        let source = "server:\n  listen_port: 9191\nremote:\n  nodes: 127.0.0.1:9191, 127.0.0.1:9192\n";
+        let err = parse(source).unwrap_err();
+        assert!(err.message.starts_with("auth.node_token: required"), "{}", err);
+        assert!(parse(&format!("{}auth:\n  node_token: 0123456789abcdef\n", source)).is_ok());
+        // or the nodes verify each other's certificates
+        let tls = "  tls:\n    cert_file: node.pem\n    key_file: node.key\n    ca_file: ca.pem\n";
+        let mutual = source.replace("remote:", &format!("{}    client_certs: required\nremote:", tls));
+        assert!(parse(&mutual).is_ok(), "{}", mutual);
+        let one_way = source.replace("remote:", &format!("{}    client_certs: ignored\nremote:", tls));
+        assert!(parse(&one_way).is_err());
+        // the local node alone is no peer
+        assert!(parse("server:\n  listen_port: 9191\nremote:\n  nodes: 127.0.0.1:9191\n").is_ok());
+    }
+
+    #[test]
+    fn overrides_take_precedence_in_order() {
+        let source = "server:\n  listen_port: 9191\n";
+        let overrides = [
+            setting("PONCU_SERVER_LISTEN_PORT", "server", "listen_port", "9292"),
+            setting("--server.listen_port", "server", "listen_port", "9393"),
+            setting("--file_server.listen_addresses", "file_server", "listen_addresses", "::1"),
+        ];
+        let config = parse_config(source, Path::new("test.yaml"), &overrides).unwrap();
+        assert_eq!(config.server.unwrap().listen_port, 9393);
+        assert_eq!(
+            config.file_server.unwrap().listen_on(),
+            vec!["[::1]:7311".parse().unwrap()]
+        );
+
+        let overrides = [setting("--server.listen_port", "server", "listen_port", "x")];
+        let err = parse_config(source, Path::new("test.yaml"), &overrides).unwrap_err();
+        assert!(err.message.starts_with("--server.listen_port=x: "), "{}", err);
+
+        // values are strings where the setting is one, nested keys are created
+        let overrides = [
+            setting("--server.id", "server", "id", "1"),
+            setting("PONCU_SERVER_NAME", "server", "name", "true"),
+            setting("--file_server.compression.min_size", "file_server", "compression.min_size", "2K"),
+            setting("--file_server.directories.listing", "file_server", "directories.listing", "true"),
+        ];
+        let config = parse_config(source, Path::new("test.yaml"), &overrides).unwrap();
+        let server = config.server.unwrap();
+        assert_eq!((server.id.as_str(), server.name.as_str()), ("1", "true"));
+        let file_server = config.file_server.unwrap();
+        assert_eq!(file_server.compression.min_size, ByteSize(2048));
+        assert!(file_server.directories.listing);
+
+        let overrides = [setting("--server.listen_port.x", "server", "listen_port.x", "1")];
+        let err = parse_config(source, Path::new("test.yaml"), &overrides).unwrap_err();
+        assert!(err.message.contains("server.listen_port is not a mapping"), "{}", err);
+    }
 }
@@ -1 +1,3 @@
+pub mod cli;
 pub mod config;
+pub mod tls;
\ No newline at end of file
@@ -0,0 +1,226 @@
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+use crate::utils::config::{ClientCerts, ClientTls, ServerTls};
+
+/// TLS settings of a server, fails if a certificate or the key can not be loaded
+pub fn server_config(settings: &ServerTls) -> io::Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(to_io_error)?;
+
+    let builder = match (settings.client_certs, settings.ca_file.as_deref()) {
+        (ClientCerts::Ignored, _) | (_, None) => builder.with_no_client_auth(),
+        (client_certs, Some(ca_file)) => {
+            let roots = Arc::new(load_roots(ca_file)?);
+            let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider());
+            let verifier = match client_certs {
+                ClientCerts::Optional => verifier.allow_unauthenticated(),
+                _ => verifier,
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(to_io_error)?)
+        }
+    };
+
+    let config = builder
+        .with_single_cert(load_certs(&settings.cert_file)?, load_key(&settings.key_file)?)
+        .map_err(|err| invalid_input(&settings.cert_file, err))?;
+    Ok(Arc::new(config))
+}
+
+/// TLS settings of a client
+pub fn client_config(settings: &ClientTls) -> io::Result<Arc<ClientConfig>> {
+    let identity = settings.cert_file.as_deref().zip(settings.key_file.as_deref());
+    build_client_config(settings.ca_file.as_deref(), identity)
+}
+
+/// TLS settings of the connections to the peer nodes,
+/// the node presents its server certificate as client certificate
+pub fn peer_config(settings: &ServerTls) -> io::Result<Arc<ClientConfig>> {
+    let identity = (settings.cert_file.as_path(), settings.key_file.as_path());
+    build_client_config(settings.ca_file.as_deref(), Some(identity))
+}
+
+/// Whether a certificate names the IP address, e.g. the address of a peer node.
+/// The certificate must come from a connection that verified it against the CA.
+pub fn certifies(cert: &CertificateDer, addr: IpAddr) -> bool {
+    webpki::EndEntityCert::try_from(cert)
+        .and_then(|cert| cert.verify_is_valid_for_subject_name(&ServerName::from(addr)))
+        .is_ok()
+}
+
+/// Name verified in the certificate of a server, its IP address if no name is configured
+pub fn server_name(name: Option<&str>, addr: IpAddr) -> io::Result<ServerName<'static>> {
+    match name {
+        Some(name) => ServerName::try_from(name.to_string()).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid server name {}: {}", name, err))
+        }),
+        None => Ok(ServerName::from(addr)),
+    }
+}
+
+fn build_client_config(
+    ca_file: Option<&Path>,
+    identity: Option<(&Path, &Path)>,
+) -> io::Result<Arc<ClientConfig>> {
+    let roots = match ca_file {
+        Some(ca_file) => load_roots(ca_file)?,
+        None => RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        },
+    };
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(to_io_error)?
+        .with_root_certificates(roots);
+
+    let config = match identity {
+        Some((cert_file, key_file)) => builder
+            .with_client_auth_cert(load_certs(cert_file)?, load_key(key_file)?)
+            .map_err(|err| invalid_input(cert_file, err))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| invalid_input(path, err))?;
+    if certs.is_empty() {
+        return Err(invalid_input(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|err| invalid_input(path, err))
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(|err| invalid_input(path, err))?;
+    }
+    Ok(roots)
+}
+
+fn invalid_input(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", path.display(), err))
+}
+
+fn to_io_error(err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::frame::{read_message, write_message};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::{ClientConnection, ServerConnection, StreamOwned};
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::thread;
+
+    /// Writes a CA, a certificate for 127.0.0.1 and a client certificate signed by it
+    fn test_certificates(dir: &Path) -> [PathBuf; 5] {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+
+        let mut files = Vec::new();
+        fs::create_dir_all(dir).unwrap();
+        let ca_file = dir.join("ca.pem");
+        fs::write(&ca_file, ca.pem()).unwrap();
+        files.push(ca_file);
+        for name in ["127.0.0.1", "client1"] {
+            let key = KeyPair::generate().unwrap();
+            let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            let cert_file = dir.join(format!("{}.pem", name));
+            let key_file = dir.join(format!("{}.key", name));
+            fs::write(&cert_file, cert.pem()).unwrap();
+            fs::write(&key_file, key.serialize_pem()).unwrap();
+            files.extend([cert_file, key_file]);
+        }
+        files.try_into().unwrap()
+    }
+
+    #[test]
+    fn client_certificates() {
+        let dir = std::env::temp_dir().join(format!("poncu-tls-{}", std::process::id()));
+        let [ca_file, cert_file, key_file, client_cert, client_key] = test_certificates(&dir);
+
+        let server = server_config(&ServerTls {
+            cert_file,
+            key_file,
+            ca_file: Some(ca_file.clone()),
+            client_certs: ClientCerts::Required,
+        })
+        .unwrap();
+        let with_cert = client_config(&ClientTls {
+            ca_file: Some(ca_file.clone()),
+            cert_file: Some(client_cert),
+            key_file: Some(client_key),
+            server_name: None,
+        })
+        .unwrap();
+        let without_cert = client_config(&ClientTls {
+            ca_file: Some(ca_file),
+            ..Default::default()
+        })
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let echo = thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                let mut stream = StreamOwned::new(ServerConnection::new(server.clone()).unwrap(), stream);
+                if let Ok(Some(message)) = read_message::<String>(&mut stream) {
+                    write_message(&mut stream, &message).unwrap();
+                }
+            }
+        });
+
+        let exchange = |config: Arc<ClientConfig>| {
+            let name = server_name(None, addr.ip()).unwrap();
+            let connection = ClientConnection::new(config, name).unwrap();
+            let mut stream = StreamOwned::new(connection, TcpStream::connect(addr).unwrap());
+            write_message(&mut stream, &"hello".to_string())?;
+            read_message::<String>(&mut stream)
+        };
+        assert_eq!(exchange(with_cert).unwrap(), Some("hello".to_string()));
+        assert!(exchange(without_cert).is_err());
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn certificates_name_addresses() {
+        let dir = std::env::temp_dir().join(format!("poncu-tls-names-{}", std::process::id()));
+        let [_, node_cert, _, client_cert, _] = test_certificates(&dir);
+        let node_cert = load_certs(&node_cert).unwrap().remove(0);
+        let client_cert = load_certs(&client_cert).unwrap().remove(0);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(certifies(&node_cert, "127.0.0.1".parse().unwrap()));
+        assert!(!certifies(&node_cert, "127.0.0.2".parse().unwrap()));
+        assert!(!certifies(&client_cert, "127.0.0.1".parse().unwrap()));
+    }
+}